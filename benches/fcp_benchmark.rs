@@ -52,6 +52,42 @@ fn fcp_benchmark(mut group: BenchmarkGroup<WallTime>, fixture_file: &str) {
     group.finish();
 }
 
+// Only copies made through userspace are affected by the buffer size, so on Linux this is only
+// meaningful when the destination is on a filesystem which `copy_file_range` can't copy to (e.g.
+// a network mount on an older kernel).
+fn adaptive_buffer_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Adaptive Buffer");
+    group.sampling_mode(SamplingMode::Flat);
+    group.warm_up_time(Duration::from_secs(60));
+    group.sample_size(100);
+    initialize();
+    let fixture_file = "large_files.json";
+    hydrate_fixture(fixture_file);
+    let source_path = HYDRATED_DIR.join(fixture_file.strip_suffix(".json").unwrap());
+    let dest_path = COPIES_DIR.join(fixture_file.strip_suffix(".json").unwrap());
+    let (source, dest) = (source_path.to_str().unwrap(), dest_path.to_str().unwrap());
+    let executable_path = fcp_executable_path();
+    let executable_path = executable_path.to_str().unwrap();
+    for flag in &["--no-adaptive-buffer", "--adaptive-buffer"] {
+        group.bench_with_input(
+            BenchmarkId::new(flag.trim_start_matches("--"), ""),
+            &(source, dest),
+            |b, (source, dest)| {
+                b.iter_with_setup(
+                    || {
+                        remove(&dest_path);
+                        let mut command = Command::new(executable_path);
+                        command.args(&[flag, source, dest]);
+                        command
+                    },
+                    run_command,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
 fn linux_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("Linux");
     group.sampling_mode(SamplingMode::Flat);
@@ -68,5 +104,10 @@ fn large_files_benchmark(c: &mut Criterion) {
     fcp_benchmark(group, "large_files.json");
 }
 
-criterion_group!(benches, linux_benchmark, large_files_benchmark);
+criterion_group!(
+    benches,
+    linux_benchmark,
+    large_files_benchmark,
+    adaptive_buffer_benchmark
+);
 criterion_main!(benches);
@@ -0,0 +1,148 @@
+//! Reading of default options from a config file, so that teams can share their usual settings
+//! rather than repeating them on every command line.
+//!
+//! The config file is the first of these which exists, unless another is given with `--config`
+//! or config files are disabled with `--no-config`:
+//! - `.fcp.toml` in the current directory.
+//! - `$XDG_CONFIG_HOME/fcp/config.toml` (or `~/.config/fcp/config.toml` if `XDG_CONFIG_HOME` is
+//!   unset).
+//!
+//! It's written in a subset of TOML: each line is blank, a `#` comment, or a `key = value` pair,
+//! where each key is the name of a command-line option without its leading dashes (e.g.
+//! `readdir-batch`, or equivalently `readdir_batch`), and each value is one of:
+//! - `true` or `false`, for options taking no value, which are given if `true`.
+//! - An integer or a double-quoted string, which is given as the option's value.
+//! - A single-line array of these, for options which may be given more than once.
+//!
+//! The options are turned back into command-line arguments and parsed along with the real ones,
+//! which come after them and so take precedence.
+
+use crate::error::{Error, Result};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The path of the config file to be read given the command-line arguments `args`, if any.
+pub fn find(args: &[String]) -> Result<Option<PathBuf>> {
+    let mut args = args.iter().take_while(|arg| *arg != "--");
+    while let Some(arg) = args.next() {
+        match arg.split_once('=') {
+            Some(("--config", path)) => return Ok(Some(PathBuf::from(path))),
+            _ if arg == "--config" => match args.next() {
+                Some(path) => return Ok(Some(PathBuf::from(path))),
+                None => return Err(Error::new("Option '--config' requires a value".to_string())),
+            },
+            _ if arg == "--no-config" => return Ok(None),
+            _ => {}
+        }
+    }
+    let user_config = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("fcp/config.toml"));
+    Ok([Some(PathBuf::from(".fcp.toml")), user_config]
+        .iter()
+        .flatten()
+        .find(|path| path.is_file())
+        .cloned())
+}
+
+/// The options in the config file at `path`, as command-line arguments.
+pub fn args(path: &Path) -> Result<Vec<String>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| Error::new(format!("{}: {}", path.display(), err)))?;
+    let mut args = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason: &str| {
+            Error::new(format!(
+                "{}:{}: {} (expected 'option = value')",
+                path.display(),
+                number + 1,
+                reason
+            ))
+        };
+        let (key, value) = line.split_once('=').ok_or_else(|| invalid("missing '='"))?;
+        let option = format!("--{}", key.trim().replace('_', "-"));
+        if option == "--" || option.contains(char::is_whitespace) {
+            return Err(invalid("invalid option name"));
+        }
+        let values = match value.trim() {
+            array if array.starts_with('[') => {
+                let items = array
+                    .strip_prefix('[')
+                    .and_then(|array| array.strip_suffix(']'))
+                    .ok_or_else(|| invalid("unterminated array"))?;
+                split_array(items)
+                    .iter()
+                    .map(|item| parse_value(item).ok_or_else(|| invalid("invalid value")))
+                    .collect::<Result<Vec<_>>>()?
+            }
+            value => vec![parse_value(value).ok_or_else(|| invalid("invalid value"))?],
+        };
+        for value in values {
+            match value {
+                Value::Bool(true) => args.push(option.clone()),
+                Value::Bool(false) => {}
+                Value::Text(value) => args.push(format!("{}={}", option, value)),
+            }
+        }
+    }
+    Ok(args)
+}
+
+enum Value {
+    Bool(bool),
+    Text(String),
+}
+
+fn parse_value(value: &str) -> Option<Value> {
+    let value = value.trim();
+    match value {
+        "true" => Some(Value::Bool(true)),
+        "false" => Some(Value::Bool(false)),
+        _ if value.parse::<u64>().is_ok() => Some(Value::Text(value.to_string())),
+        _ => {
+            let quoted = value.strip_prefix('"')?.strip_suffix('"')?;
+            let mut text = String::with_capacity(quoted.len());
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => match chars.next()? {
+                        c @ ('"' | '\\') => text.push(c),
+                        'n' => text.push('\n'),
+                        't' => text.push('\t'),
+                        _ => return None,
+                    },
+                    '"' => return None,
+                    c => text.push(c),
+                }
+            }
+            Some(Value::Text(text))
+        }
+    }
+}
+
+// Split the items of an array at the commas between them, ignoring those within strings.
+fn split_array(items: &str) -> Vec<&str> {
+    let (mut split, mut start, mut in_string, mut escaped) = (Vec::new(), 0, false, false);
+    for (index, c) in items.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ',' if !in_string => {
+                split.push(&items[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    split.push(&items[start..]);
+    // A trailing comma is permitted, as is an empty array.
+    split.retain(|item| !item.trim().is_empty());
+    split
+}
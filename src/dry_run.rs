@@ -0,0 +1,202 @@
+//! Dry runs with `--dry-run`, which list what would be copied (as `'SOURCE' -> 'DESTINATION'`
+//! lines on stdout) without copying anything.
+//!
+//! With `--dry-run=check`, the copy is also checked for problems which would make it fail, each
+//! of which is reported on stderr, so that they can be caught (e.g. in CI) before a long copy
+//! starts:
+//! - Destinations which already exist and couldn't be replaced (e.g. a directory in the way of a
+//!   file). Those which would be overwritten or skipped are noted in the listing instead.
+//! - Directories to be copied into which don't exist or aren't writable.
+//! - Destination filesystems without enough free space for all of the regular files copied to
+//!   them, not counting any space freed by overwriting.
+//!
+//! Nothing is written either way. Only `--no-recursive`, `--newer-than`, `--min-size`, and
+//! `--max-size` are taken into account in deciding what would be copied.
+
+use crate::error::Result;
+use crate::filesystem as fs;
+use crate::options::{CopyOptions, DryRun, Existing};
+use nix::sys::statvfs;
+use nix::unistd::{self, AccessFlags};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// The state of a dry run, accumulated while walking the sources.
+struct Plan<'a> {
+    options: &'a CopyOptions,
+    check: bool,
+    problems: Vec<String>,
+    /// The number of bytes of regular files which would be copied to each destination operand.
+    bytes: Vec<u64>,
+}
+
+/// List what copying `sources` to `dests` would do, returning whether any problems were found.
+/// With `--dry-run=check` this includes problems predicted for the copy itself, and otherwise
+/// only those in reading the sources.
+pub(crate) fn run(sources: &[PathBuf], dests: &[PathBuf], options: &CopyOptions) -> Result<bool> {
+    let mut plan = Plan {
+        options,
+        check: options.dry_run == Some(DryRun::Check),
+        problems: Vec::new(),
+        bytes: vec![0; dests.len()],
+    };
+    let targets = top_level_targets(sources, dests, options)?;
+    let mut parents = HashSet::new();
+    for (source, dest_index, target) in &targets {
+        if plan.check {
+            let parent = parent_dir(target);
+            if parents.insert(parent.to_path_buf()) {
+                plan.check_writable(parent);
+            }
+        }
+        plan.walk(source, target, *dest_index);
+    }
+    if plan.check {
+        for (dest, bytes) in dests.iter().zip(plan.bytes.clone()) {
+            plan.check_space(dest, bytes);
+        }
+    }
+    for problem in &plan.problems {
+        eprintln!("{}", problem);
+    }
+    Ok(!plan.problems.is_empty())
+}
+
+// The path each source would be copied to, along with the index of the destination operand
+// it's within, mirroring how `fcp_with_options` decides this.
+fn top_level_targets<'a>(
+    sources: &'a [PathBuf],
+    dests: &[PathBuf],
+    options: &CopyOptions,
+) -> Result<Vec<(&'a Path, usize, PathBuf)>> {
+    if let ([source], [dest]) = (sources, dests) {
+        let into_dir = fs::metadata(dest).is_ok_and(|metadata| metadata.is_dir());
+        if !options.parents && options.to.is_empty() && (options.swap || !into_dir) {
+            return Ok(vec![(source, 0, dest.clone())]);
+        }
+    }
+    let names = if options.parents {
+        crate::parent_paths(sources)?
+    } else {
+        crate::file_names(sources)?
+            .into_iter()
+            .map(PathBuf::from)
+            .collect()
+    };
+    let mut targets = Vec::with_capacity(sources.len() * dests.len());
+    for (dest_index, dest) in dests.iter().enumerate() {
+        for (source, name) in sources.iter().zip(&names) {
+            targets.push((source.as_path(), dest_index, dest.join(name)));
+        }
+    }
+    Ok(targets)
+}
+
+impl Plan<'_> {
+    fn walk(&mut self, source: &Path, target: &Path, dest_index: usize) {
+        let metadata = match fs::symlink_metadata(source) {
+            Ok(metadata) => metadata,
+            Err(err) => return self.problems.push(err.to_string()),
+        };
+        if metadata.is_dir() && self.options.no_recursive {
+            return self.problems.push(format!(
+                "-R not specified; omitting directory '{}'",
+                source.display()
+            ));
+        }
+        if !metadata.is_dir() && self.options.is_filtered(&metadata) {
+            return;
+        }
+        let existing = match self.check {
+            true => std::fs::symlink_metadata(target).ok(),
+            false => None,
+        };
+        let (note, skipped) = match (&existing, self.options.existing) {
+            (None, _) => ("", false),
+            (Some(existing), Some(Existing::NoClobber | Existing::Prompt))
+                if existing.is_dir() && metadata.is_dir() =>
+            {
+                (" (exists, so would be copied into)", false)
+            }
+            (Some(existing), Some(Existing::Prompt)) if !existing.is_dir() => {
+                (" (exists, so would be asked about)", false)
+            }
+            (Some(_), Some(_)) => (" (exists, so would be skipped)", true),
+            (Some(existing), None) if existing.is_dir() || metadata.is_dir() => {
+                self.problems.push(format!(
+                    "'{}': already exists, so '{}' couldn't be copied to it",
+                    target.display(),
+                    source.display()
+                ));
+                return;
+            }
+            (Some(_), None) => (" (exists, so would be overwritten)", false),
+        };
+        println!("'{}' -> '{}'{}", source.display(), target.display(), note);
+        if skipped {
+            return;
+        }
+        if metadata.is_file() {
+            self.bytes[dest_index] += metadata.len();
+        }
+        if !metadata.is_dir() {
+            return;
+        }
+        let entries = match fs::read_dir(source) {
+            Ok(entries) => entries,
+            Err(err) => return self.problems.push(err.to_string()),
+        };
+        for entry in entries {
+            match entry {
+                Ok(entry) => {
+                    let name = entry.file_name();
+                    self.walk(&source.join(&name), &target.join(&name), dest_index);
+                }
+                Err(err) => self.problems.push(err.to_string()),
+            }
+        }
+    }
+
+    fn check_writable(&mut self, dir: &Path) {
+        if std::fs::symlink_metadata(dir).is_err() {
+            self.problems
+                .push(format!("'{}': directory does not exist", dir.display()));
+        } else if let Err(err) = unistd::access(dir, AccessFlags::W_OK) {
+            self.problems.push(format!(
+                "'{}': directory is not writable ({})",
+                dir.display(),
+                err.desc()
+            ));
+        }
+    }
+
+    // The free space is that of the filesystem `dest` is on, or would be created on.
+    fn check_space(&mut self, dest: &Path, bytes: u64) {
+        let on = match std::fs::symlink_metadata(dest) {
+            Ok(_) => dest,
+            Err(_) => parent_dir(dest),
+        };
+        // The missing directory itself has already been reported.
+        if let Ok(stats) = statvfs::statvfs(on) {
+            // The types of these counts differ between systems.
+            #[allow(clippy::useless_conversion)]
+            let available = u64::from(stats.blocks_available()) * u64::from(stats.fragment_size());
+            if bytes > available {
+                self.problems.push(format!(
+                    "'{}': {} bytes would be copied, but only {} are available",
+                    dest.display(),
+                    bytes,
+                    available
+                ));
+            }
+        }
+    }
+}
+
+// The directory containing `path`, which is the current directory for a bare file name.
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    }
+}
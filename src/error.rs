@@ -1,15 +1,36 @@
 use std::error;
 use std::fmt;
+use std::io;
+use std::path::Path;
 use std::result;
 
 #[derive(Debug)]
-pub struct Error(String);
+pub struct Error {
+    message: String,
+    kind: Option<ErrorKind>,
+    operation: Option<&'static str>,
+    os_error: Option<i32>,
+}
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// Specific misuses of `fcp`, and other failures, which callers may want to recognize.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The destination is the source directory itself or lies somewhere inside of it, so copying
+    /// would never finish.
+    DestInsideSource,
+    /// No sources were given, whether as operands or with `--files-from`.
+    NoSources,
+    /// No destination was given, whether as the last operand or with `--to`.
+    NoDestination,
+    /// The copy was cancelled with `CopyOptions::cancel` before it finished.
+    Cancelled,
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+        write!(f, "{}", self.message)
     }
 }
 
@@ -19,12 +40,72 @@ impl fmt::Display for Error {
 // cannot implement `error::Error` (otherwise we'd run into the same issue).
 impl<T: error::Error> From<T> for Error {
     fn from(other: T) -> Self {
-        Error(other.to_string())
+        Error::new(other.to_string())
     }
 }
 
 impl Error {
     pub fn new(message: String) -> Self {
-        Error(message)
+        Error {
+            message,
+            kind: None,
+            operation: None,
+            os_error: None,
+        }
+    }
+
+    /// Like `new`, but retaining the name of the `operation` which failed (e.g. `open`) and the
+    /// OS error code of `err`, so that callers can react to specific failures.
+    pub fn with_os_error(message: String, operation: &'static str, err: &io::Error) -> Self {
+        Error {
+            message,
+            kind: None,
+            operation: Some(operation),
+            os_error: err.raw_os_error(),
+        }
+    }
+
+    pub fn dest_inside_source(source: &Path, dest: &Path) -> Self {
+        Error {
+            kind: Some(ErrorKind::DestInsideSource),
+            ..Error::new(format!(
+                "Cannot copy directory '{}' into itself '{}'",
+                source.display(),
+                dest.display()
+            ))
+        }
+    }
+
+    pub fn cancelled() -> Self {
+        Error {
+            kind: Some(ErrorKind::Cancelled),
+            ..Error::new("Copy cancelled before it finished".to_string())
+        }
+    }
+
+    /// An error of the given `kind` of misuse, described by `message`, to which a pointer to
+    /// `fcp --help` is added.
+    pub fn usage(kind: ErrorKind, message: &str) -> Self {
+        Error {
+            kind: Some(kind),
+            ..Error::new(format!("{} (run 'fcp --help' for details)", message))
+        }
+    }
+
+    /// This error, described by `message` instead.
+    pub fn reworded(self, message: String) -> Self {
+        Error { message, ..self }
+    }
+
+    pub fn kind(&self) -> Option<ErrorKind> {
+        self.kind
+    }
+
+    pub fn operation(&self) -> Option<&'static str> {
+        self.operation
+    }
+
+    pub fn raw_os_error(&self) -> Option<i32> {
+        self.os_error
     }
 }
@@ -3,18 +3,31 @@
 //! usefulness of error messages by providing additional context.
 
 use crate::error::{Error, Result};
+use nix::errno::Errno;
+use nix::fcntl::{self, OFlag};
 use nix::sys::stat::Mode;
 use nix::unistd;
 use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::fmt;
 use std::fs::{self, DirBuilder, DirEntry, File, Metadata, OpenOptions, Permissions, ReadDir};
+use std::io::{self, prelude::*, SeekFrom};
 use std::os::unix::fs::{self as unix, DirBuilderExt, FileTypeExt, OpenOptionsExt, PermissionsExt};
-use std::path::{Path, PathBuf};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 macro_rules! wrap {
     ($namespace:ident, $function:ident, $payload:ty) => {
         pub fn $function<P: AsRef<Path>>(path: P) -> Result<$payload> {
-            $namespace::$function(path.as_ref())
-                .map_err(|err| Error::new(format!("{}: {}", path.as_ref().display(), err)))
+            $namespace::$function(path.as_ref()).map_err(|err| {
+                let message = format!("{}: {}", path.as_ref().display(), err);
+                Error::with_os_error(message, stringify!($function), &err)
+            })
         }
     };
 }
@@ -24,7 +37,8 @@ macro_rules! wrap2 {
         pub fn $function<P: AsRef<Path>, Q: AsRef<Path>>(source: P, dest: Q) -> Result<$payload> {
             let (source, dest) = (source.as_ref(), dest.as_ref());
             $namespace::$function(source, dest).map_err(|err| {
-                Error::new(format!("{}, {}: {}", source.display(), dest.display(), err))
+                let message = format!("{}, {}: {}", source.display(), dest.display(), err);
+                Error::with_os_error(message, stringify!($function), &err)
             })
         }
     };
@@ -34,53 +48,1618 @@ wrap!(fs, symlink_metadata, Metadata);
 wrap!(fs, metadata, Metadata);
 wrap!(fs, read_link, PathBuf);
 wrap!(fs, read_dir, ReadDir);
+wrap!(fs, remove_dir, ());
 wrap!(fs, remove_dir_all, ());
 wrap!(fs, remove_file, ());
 wrap!(fs, canonicalize, PathBuf);
 wrap!(fs, create_dir_all, ());
 wrap!(File, open, File);
 wrap2!(symlink, unix, ());
-wrap2!(copy, fs, u64);
+wrap2!(rename, fs, ());
+wrap2!(hard_link, fs, ());
 
 macro_rules! make_error_message {
-    ($path:ident) => {
-        |err| Error::new(format!("{}: {}", $path.display(), err))
+    ($operation:expr, $path:ident) => {
+        |err| {
+            let err = io::Error::from(err);
+            Error::with_os_error(format!("{}: {}", $path.display(), err), $operation, &err)
+        }
     };
 }
 
 pub fn entry_file_type(entry: &DirEntry) -> Result<FileType> {
     match entry.file_type() {
-        Err(err) => Err(Error::new(format!("{}: {}", entry.path().display(), err))),
+        Err(err) => {
+            let message = format!("{}: {}", entry.path().display(), err);
+            Err(Error::with_os_error(message, "file_type", &err))
+        }
         Ok(file_type) => Ok(FileType::from(file_type)),
     }
 }
 
+/// If `err` is an `ENOTDIR` failure caused by a component of `path` before its last not being a
+/// directory, `err` reworded to name that component, as the OS's message only gives `path` as a
+/// whole. Otherwise `err` itself.
+pub fn name_non_directory(path: &Path, err: Error) -> Error {
+    if err.raw_os_error() != Some(nix::libc::ENOTDIR) {
+        return err;
+    }
+    let mut ancestors = path
+        .ancestors()
+        .skip(1)
+        .filter(|ancestor| *ancestor != Path::new(""))
+        .collect::<Vec<_>>();
+    ancestors.reverse();
+    match ancestors
+        .into_iter()
+        .find(|ancestor| fs::metadata(ancestor).is_ok_and(|metadata| !metadata.is_dir()))
+    {
+        Some(ancestor) => err.reworded(format!("'{}' is not a directory", ancestor.display())),
+        None => err,
+    }
+}
+
 pub fn create_dir<P: AsRef<Path>>(path: P, mode: u32) -> Result<()> {
     let path = path.as_ref();
     DirBuilder::new()
         .mode(mode)
         .create(path)
-        .map_err(make_error_message!(path))
+        .map_err(make_error_message!("create_dir", path))
+}
+
+/// Set the mode of `path` (following symlinks), which unlike that given when creating it isn't
+/// subject to the umask.
+pub fn set_mode<P: AsRef<Path>>(path: P, mode: u32) -> Result<()> {
+    let path = path.as_ref();
+    fs::set_permissions(path, Permissions::from_mode(mode)).map_err(|err| {
+        let message = format!("{}: {}", path.display(), err);
+        Error::with_os_error(message, "set_permissions", &err)
+    })
 }
 
 pub fn create<P: AsRef<Path>>(path: P, mode: u32) -> Result<File> {
+    create_truncate(path, mode, OFlag::empty())
+}
+
+/// Create the file at `path`, failing with `EEXIST` if anything already exists there. `flags`
+/// (e.g. `O_NOFOLLOW`) are additionally passed when opening the file.
+pub fn create_new<P: AsRef<Path>>(path: P, mode: u32, flags: OFlag) -> Result<File> {
+    create_truncate(path, mode, flags | OFlag::O_EXCL)
+}
+
+/// Create the file at `path`, truncating it if it already exists. `flags` (e.g. `O_NOFOLLOW`) are
+/// additionally passed when opening the file.
+pub fn create_truncate<P: AsRef<Path>>(path: P, mode: u32, flags: OFlag) -> Result<File> {
     let path = path.as_ref();
     OpenOptions::new()
         .mode(mode)
+        .custom_flags(flags.bits())
         .truncate(true)
         .write(true)
         .create(true)
         .open(path)
-        .map_err(make_error_message!(path))
+        .map_err(make_error_message!("open", path))
 }
 
 pub fn mkfifo<P: AsRef<Path>>(path: P, permissions: Permissions) -> Result<()> {
     let path = path.as_ref();
     let mode = Mode::from_bits_truncate(permissions.mode().try_into()?);
-    unistd::mkfifo(path, mode).map_err(make_error_message!(path))
+    unistd::mkfifo(path, mode).map_err(make_error_message!("mkfifo", path))
+}
+
+/// Create a socket node at `path`. This is only the filesystem's record of a socket, which nothing
+/// is bound to. Creating one generally requires privileges.
+pub fn mksock<P: AsRef<Path>>(path: P, permissions: Permissions) -> Result<()> {
+    use nix::sys::stat::{self, SFlag};
+
+    let path = path.as_ref();
+    // The type of the mode differs between systems.
+    #[allow(clippy::useless_conversion)]
+    let mode = Mode::from_bits_truncate(permissions.mode().try_into()?);
+    stat::mknod(path, SFlag::S_IFSOCK, mode, 0).map_err(make_error_message!("mknod", path))
+}
+
+/// Whether a file is an overlayfs whiteout, which marks a file in the layers beneath the one it's
+/// in as deleted: a character device with device number 0.
+pub fn is_whiteout(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.file_type().is_char_device() && metadata.rdev() == 0
+}
+
+/// Create an overlayfs whiteout (see `is_whiteout`) at `path`. Creating one generally requires
+/// privileges.
+pub fn mkwhiteout<P: AsRef<Path>>(path: P, permissions: Permissions) -> Result<()> {
+    use nix::sys::stat::{self, SFlag};
+
+    let path = path.as_ref();
+    // The type of the mode differs between systems.
+    #[allow(clippy::useless_conversion)]
+    let mode = Mode::from_bits_truncate(permissions.mode().try_into()?);
+    stat::mknod(path, SFlag::S_IFCHR, mode, 0).map_err(make_error_message!("mknod", path))
+}
+
+/// The size of the buffer used when copying a file's contents through userspace, unless
+/// `ContentOptions::buffer_size` is given.
+pub const BUFFER_SIZE: usize = 128 * 1024;
+
+/// The size beyond which an adaptive buffer (see `copy`) is not grown.
+const MAX_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+/// The number of reads which fill an adaptive buffer before its throughput is measured.
+const READS_PER_SAMPLE: usize = 16;
+
+/// The factor by which throughput must increase for the growth of an adaptive buffer to continue.
+const IMPROVEMENT_THRESHOLD: f64 = 1.05;
+
+/// The mechanism by which the contents of a regular file were copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyMechanism {
+    /// `copy_file_range(2)`, which copies the data without it ever leaving the kernel.
+    CopyFileRange,
+    /// `sendfile(2)`, which also copies within the kernel, and is used on Linux where
+    /// `copy_file_range` is unsupported (e.g. between filesystems on older kernels).
+    Sendfile,
+    /// `copyfile(3)`, which clones the file instead when the filesystem supports it.
+    Copyfile,
+    /// A loop of `read(2)` and `write(2)` through a buffer in userspace.
+    Buffered,
+    /// As `Buffered`, but with both files opened with `O_DIRECT`, bypassing the page cache.
+    Direct,
+    /// The `FICLONE` ioctl (or `clonefile(2)` on macOS), which makes the copy share the source's
+    /// storage (until either is written to) on filesystems supporting copy-on-write, such as
+    /// Btrfs, XFS, and APFS.
+    Clone,
+    /// As `Buffered`, but seeking over blocks which are entirely zero rather than writing them,
+    /// which left `holes` bytes of the copy unwritten.
+    Sparse { holes: u64 },
+    /// Copying only the extents of a source with holes which contain data, as found with
+    /// `lseek(2)`'s `SEEK_DATA` and `SEEK_HOLE`, so that the copy has holes wherever the source
+    /// does. This left `holes` bytes of the copy unwritten.
+    Extents { holes: u64 },
+    /// Mapping the source into memory with `mmap(2)`, and writing the mapping to the destination
+    /// with `write(2)`.
+    Mmap,
+    /// Reading and writing through io_uring, together with other small files (see the `uring`
+    /// module).
+    IoUring,
+}
+
+impl fmt::Display for CopyMechanism {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            CopyMechanism::CopyFileRange => "copy_file_range",
+            CopyMechanism::Sendfile => "sendfile",
+            CopyMechanism::Copyfile => "copyfile",
+            CopyMechanism::Buffered => "buffered read/write",
+            CopyMechanism::Direct => "direct read/write",
+            CopyMechanism::Clone if cfg!(target_os = "macos") => "clonefile",
+            CopyMechanism::Clone => "FICLONE",
+            CopyMechanism::Sparse { .. } => "sparse read/write",
+            CopyMechanism::Extents { .. } => "data extents",
+            CopyMechanism::Mmap => "mmap",
+            CopyMechanism::IoUring => "io_uring",
+        })
+    }
+}
+
+/// When to clone regular files (see `CopyMechanism::Clone`) rather than copying their contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reflink {
+    /// Clone where the filesystem supports it, and otherwise copy the contents as usual.
+    Auto,
+    /// Clone, failing rather than copying the contents if that isn't possible.
+    Always,
+}
+
+/// How to make holes in the copies of regular files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sparse {
+    /// Recreate the holes of sources which have them (see `CopyMechanism::Extents`), where the
+    /// operating system can find them.
+    Auto,
+    /// Copy through userspace, seeking over (rather than writing) blocks which are entirely zero
+    /// so that they become holes in the destination (see `CopyMechanism::Sparse`). This makes
+    /// the copy sparser than the source wherever it contains runs of literal zeroes.
+    Always,
+}
+
+/// The size of the blocks which are checked for being entirely zero when copying sparsely.
+const SPARSE_BLOCK_SIZE: usize = 4096;
+
+/// Settings controlling how `copy` copies the contents of a file.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ContentOptions<'a> {
+    /// If the contents are copied through userspace, start with a small buffer and double it for
+    /// as long as doing so improves throughput.
+    pub adaptive_buffer: bool,
+    /// The size of the buffer through which the contents are copied, if they're copied through
+    /// userspace, rather than `BUFFER_SIZE` (or, with `adaptive_buffer`, the size it starts at).
+    pub buffer_size: Option<usize>,
+    /// Make holes in the destination, either where the source has them (`Auto`) or wherever it
+    /// has blocks which are entirely zero (`Always`). `None` writes the holes out as zeroes.
+    pub sparse: Option<Sparse>,
+    /// Flush the copy to storage with `fsync(2)` before considering it complete.
+    pub fsync: bool,
+    /// Copy by this mechanism rather than whichever is fastest. A forced `CopyFileRange` fails
+    /// where it's unsupported, rather than falling back to copying through userspace.
+    pub mechanism: Option<CopyMechanism>,
+    /// Copy through userspace with `O_DIRECT`, bypassing the page cache, where the filesystems
+    /// support it (see `copy_direct`). This is ignored when copying sparsely or by a forced
+    /// `mechanism`.
+    pub direct: bool,
+    /// Don't preallocate the destination's storage before copying the contents of a source of at
+    /// least `PREALLOCATE_THRESHOLD` bytes (see `preallocate`). It's never preallocated when
+    /// copying sparsely.
+    pub no_preallocate: bool,
+    /// Copy sources of at least this many bytes in ranges, concurrently (see `copy_ranges`). This
+    /// is ignored when copying sparsely, with `direct`, or by a forced `mechanism` other than
+    /// `CopyFileRange` or `Buffered`.
+    pub split: Option<u64>,
+    /// Clone the file (see `CopyMechanism::Clone`), if at all. Where it is cloned, this takes
+    /// precedence over all of the other options but `fsync`.
+    pub reflink: Option<Reflink>,
+    /// Stop copying once this is set, failing with `ECANCELED`. It's checked after every buffer
+    /// copied through userspace, and every `CANCEL_CHECK_INTERVAL` bytes otherwise. Files copied
+    /// by `FICLONE` or `copyfile(3)` are copied all at once, so they can't be stopped partway.
+    pub cancel: Option<&'a AtomicBool>,
+    /// Limit the rate at which the contents are copied, together with those of every other file
+    /// sharing the throttle. It's recorded against after every buffer copied through userspace,
+    /// and every `THROTTLE_INTERVAL` bytes otherwise. Files which are cloned aren't throttled.
+    pub throttle: Option<&'a Throttle>,
 }
 
+/// Limits the rate at which the contents of files are copied in aggregate, across every thread
+/// copying them, by making each wait its turn once it's copied as much as the rate allows.
 #[derive(Debug)]
+pub struct Throttle {
+    /// The number of bytes which may be copied per second.
+    rate: u64,
+    /// The time by which everything recorded so far may have been copied, at `rate`.
+    next: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn new(rate: u64) -> Self {
+        Throttle {
+            rate: rate.max(1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Record that `bytes` were copied, sleeping until copying them keeps within the rate.
+    pub fn record(&self, bytes: u64) {
+        let until = {
+            let mut next = self.next.lock().unwrap();
+            // Time spent idle isn't saved up to be spent on a burst later.
+            let now = Instant::now();
+            if *next < now {
+                *next = now;
+            }
+            *next += Duration::from_secs_f64(bytes as f64 / self.rate as f64);
+            *next
+        };
+        if let Some(delay) = until.checked_duration_since(Instant::now()) {
+            thread::sleep(delay);
+        }
+    }
+}
+
+/// The size from which the storage for copies is preallocated (see `preallocate`).
+const PREALLOCATE_THRESHOLD: u64 = 1024 * 1024;
+
+/// The bounds on the size of the ranges into which `copy_ranges` splits a file, which is always a
+/// multiple of the lower one.
+const MIN_RANGE_SIZE: u64 = 1024 * 1024;
+const MAX_RANGE_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// The size of the windows in which `copy_mmap` maps a file, which bounds the address space used.
+const MMAP_WINDOW_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The number of bytes copied by `copy_file_range(2)` between checks of `ContentOptions::cancel`.
+const CANCEL_CHECK_INTERVAL: usize = 16 * 1024 * 1024;
+
+/// The number of bytes copied by `copy_file_range(2)` between records against
+/// `ContentOptions::throttle`, which is small enough to keep the rate smooth.
+const THROTTLE_INTERVAL: u64 = 1024 * 1024;
+
+/// The outcome of copying a regular file with `copy`.
+#[derive(Debug, Clone, Copy)]
+pub struct Copied {
+    /// The number of bytes copied.
+    pub bytes: u64,
+    /// The mechanism by which they were copied.
+    pub mechanism: CopyMechanism,
+    /// The size of the source when copying began and once it had finished, if these differ (e.g.
+    /// because another process was appending to it).
+    pub resized: Option<(u64, u64)>,
+}
+
+/// Copy the regular file `source` to `dest`. `dest_flags` are passed when opening `dest` (e.g.
+/// `O_NOFOLLOW`).
+///
+/// Only as much of `source` as it contained when copying began is copied, so that a file which
+/// is being appended to is copied as a consistent snapshot, rather than the copy chasing its
+/// moving end. Files reporting a size of zero (such as those in procfs, which nonetheless have
+/// contents) are copied to their end, however. Where `copyfile` is used to copy the entire file
+/// (on macOS), whatever it contains as of the copy is copied.
+pub fn copy<P: AsRef<Path>, Q: AsRef<Path>>(
+    source: P,
+    dest: Q,
+    dest_flags: OFlag,
+    content: ContentOptions,
+) -> Result<Copied> {
+    let (source, dest) = (source.as_ref(), dest.as_ref());
+    match content.reflink {
+        Some(Reflink::Always) => return clone(source, dest, dest_flags, content.fsync),
+        Some(Reflink::Auto) => match clone(source, dest, dest_flags, content.fsync) {
+            Err(err) if is_clone_unsupported(err.raw_os_error()) => {}
+            result => return result,
+        },
+        None => {}
+    }
+    copy_regular(source, dest, dest_flags, content).map_err(|err| {
+        let message = format!("{}, {}: {}", source.display(), dest.display(), err);
+        Error::with_os_error(message, "copy", &err)
+    })
+}
+
+fn copy_regular(
+    source: &Path,
+    dest: &Path,
+    dest_flags: OFlag,
+    content: ContentOptions,
+) -> io::Result<Copied> {
+    // `std::fs::copy` already uses `fcopyfile` on macOS (and will even clone the file when
+    // possible), which we couldn't improve upon here. However, it offers no way to control how
+    // the destination is opened.
+    #[cfg(target_os = "macos")]
+    if dest_flags.is_empty()
+        && content.sparse.is_none()
+        && content.split.is_none()
+        && content.throttle.is_none()
+        && !content.fsync
+        && matches!(content.mechanism, None | Some(CopyMechanism::Copyfile))
+    {
+        return fs::copy(source, dest).map(|bytes| Copied {
+            bytes,
+            mechanism: CopyMechanism::Copyfile,
+            resized: None,
+        });
+    }
+    let direct = content.direct
+        && content.sparse.is_none()
+        && content.mechanism.is_none()
+        && !direct_flag(true).is_empty();
+    let source = open_direct(source, direct)?;
+    let metadata = source.metadata()?;
+    let mut options = OpenOptions::new();
+    options
+        .mode(metadata.permissions().mode())
+        .truncate(true)
+        .write(true)
+        .create(true);
+    let dest = match options
+        .custom_flags((dest_flags | direct_flag(direct)).bits())
+        .open(dest)
+    {
+        // Not every filesystem supports `O_DIRECT`.
+        Err(err) if direct && err.raw_os_error() == Some(Errno::EINVAL as i32) => {
+            options.custom_flags(dest_flags.bits()).open(dest)?
+        }
+        result => result?,
+    };
+    // The mode given when opening is subject to the umask (and ignored entirely if the
+    // destination already existed), so we explicitly set the permissions afterwards.
+    dest.set_permissions(metadata.permissions())?;
+    let len = metadata.len();
+    if len >= PREALLOCATE_THRESHOLD && !content.no_preallocate && content.sparse.is_none() {
+        preallocate(&dest, len)?;
+    }
+    // Files reporting a size of zero may still have contents (see above), which only a copy
+    // reading until the end can find.
+    let split = content.split.is_some_and(|split| len >= split.max(1))
+        && content.sparse.is_none()
+        && matches!(
+            content.mechanism,
+            None | Some(CopyMechanism::CopyFileRange | CopyMechanism::Buffered)
+        );
+    let (bytes, mechanism) = if direct && len > 0 {
+        copy_direct(&source, &dest, len, content)?
+    } else if split {
+        copy_ranges(&source, &dest, len, content)?
+    } else {
+        copy_fds(&source, &dest, Some(len).filter(|&len| len > 0), content)?
+    };
+    // Only `copyfile` brings along the extended attributes in which macOS stores resource forks
+    // and Finder info, so they're copied separately when the contents were copied otherwise.
+    #[cfg(target_os = "macos")]
+    if mechanism != CopyMechanism::Copyfile {
+        copy_xattrs_fd(&source, &dest)?;
+    }
+    if content.fsync {
+        dest.sync_all()?;
+    }
+    let final_len = source.metadata()?.len();
+    Ok(Copied {
+        bytes,
+        mechanism,
+        resized: Some((len, final_len)).filter(|_| final_len != len),
+    })
+}
+
+// Clone `source` to `dest` with `FICLONE`, failing with an error naming the reason if that isn't
+// possible, so that it can't be mistaken for a failure to copy the file in some other way.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn clone(source: &Path, dest: &Path, dest_flags: OFlag, fsync: bool) -> Result<Copied> {
+    use nix::libc;
+
+    let error = |err: io::Error| {
+        let message = format!("{}, {}: {}", source.display(), dest.display(), err);
+        Error::with_os_error(message, "copy", &err)
+    };
+    let source_file = File::open(source).map_err(error)?;
+    let metadata = source_file.metadata().map_err(error)?;
+    let dest_file = OpenOptions::new()
+        .mode(metadata.permissions().mode())
+        .custom_flags(dest_flags.bits())
+        .truncate(true)
+        .write(true)
+        .create(true)
+        .open(dest)
+        .map_err(error)?;
+    // As in `copy_regular`, the mode given when opening is subject to the umask.
+    dest_file
+        .set_permissions(metadata.permissions())
+        .map_err(error)?;
+    let ficlone = nix::request_code_write!(0x94, 9, std::mem::size_of::<libc::c_int>());
+    // Safe because both file descriptors remain open for the duration of the call.
+    let result = unsafe { libc::ioctl(dest_file.as_raw_fd(), ficlone, source_file.as_raw_fd()) };
+    if let Err(err) = Errno::result(result) {
+        // Nothing was copied, so don't leave the empty file behind as though it had been.
+        let _ = fs::remove_file(dest);
+        let err = io::Error::from(err);
+        return Err(clone_error(source, dest, err, "ioctl"));
+    }
+    if fsync {
+        dest_file.sync_all().map_err(error)?;
+    }
+    Ok(Copied {
+        bytes: metadata.len(),
+        mechanism: CopyMechanism::Clone,
+        resized: None,
+    })
+}
+
+// Clone `source` to `dest` with `clonefile(2)`. This refuses to replace an existing file, so the
+// clone is made beside `dest` and then renamed over it.
+#[cfg(target_os = "macos")]
+fn clone(source: &Path, dest: &Path, _dest_flags: OFlag, fsync: bool) -> Result<Copied> {
+    use nix::libc;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let error = |err: io::Error| {
+        let message = format!("{}, {}: {}", source.display(), dest.display(), err);
+        Error::with_os_error(message, "copy", &err)
+    };
+    let dir = dest.parent().unwrap_or_else(|| Path::new("."));
+    let temporary = temporary_path(dir, dest.file_name().unwrap_or_default());
+    let c_source = CString::new(source.as_os_str().as_bytes())?;
+    let c_temporary = CString::new(temporary.as_os_str().as_bytes())?;
+    // Safe because both paths are null-terminated.
+    let result = unsafe {
+        libc::clonefile(
+            c_source.as_ptr(),
+            c_temporary.as_ptr(),
+            libc::CLONE_NOFOLLOW,
+        )
+    };
+    if let Err(err) = Errno::result(result) {
+        return Err(clone_error(source, dest, io::Error::from(err), "clonefile"));
+    }
+    let renamed = fs::rename(&temporary, dest).and_then(|_| match fsync {
+        true => File::open(dest)?.sync_all(),
+        false => Ok(()),
+    });
+    if let Err(err) = renamed {
+        let _ = fs::remove_file(&temporary);
+        return Err(error(err));
+    }
+    Ok(Copied {
+        bytes: fs::metadata(dest).map_err(error)?.len(),
+        mechanism: CopyMechanism::Clone,
+        resized: None,
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+fn clone(source: &Path, dest: &Path, _dest_flags: OFlag, _fsync: bool) -> Result<Copied> {
+    Err(Error::new(format!(
+        "{}: couldn't be cloned to '{}', as cloning is only supported on Linux and macOS",
+        source.display(),
+        dest.display()
+    )))
+}
+
+// Whether cloning failed with `errno` because the file can't be cloned (as opposed to, say, the
+// destination not being writable), in which case `Reflink::Auto` copies it instead.
+fn is_clone_unsupported(errno: Option<i32>) -> bool {
+    use nix::libc;
+
+    // Where cloning isn't supported at all, `clone` fails without an errno.
+    let errno = match errno {
+        Some(errno) => errno,
+        None => return true,
+    };
+    // `ENOTSUP` is distinct from `EOPNOTSUPP` on macOS, though not on Linux.
+    matches!(
+        errno,
+        libc::EXDEV | libc::EOPNOTSUPP | libc::ENOTTY | libc::EINVAL
+    ) || errno == libc::ENOTSUP
+}
+
+// The error with which cloning `source` to `dest` failed in `operation`, naming the reason.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+fn clone_error(source: &Path, dest: &Path, err: io::Error, operation: &'static str) -> Error {
+    use nix::libc;
+
+    let reason = match err.raw_os_error() {
+        Some(libc::EXDEV) => "they're on different filesystems",
+        errno if is_clone_unsupported(errno) => "the filesystem doesn't support cloning",
+        _ => "cloning failed",
+    };
+    let message = format!(
+        "{}: couldn't be cloned to '{}', as {} ({})",
+        source.display(),
+        dest.display(),
+        reason,
+        err
+    );
+    Error::with_os_error(message, operation, &err)
+}
+
+// Reserving all of the storage for a large copy up front lets the filesystem allocate it
+// contiguously, and makes a copy which won't fit fail with `ENOSPC` before anything is written
+// rather than partway through. `FALLOC_FL_KEEP_SIZE` leaves the length of `dest` alone, so it
+// isn't padded with zeroes if the source shrinks while being copied.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn preallocate(dest: &File, len: u64) -> io::Result<()> {
+    use fcntl::FallocateFlags;
+
+    let len = len.try_into().unwrap_or(i64::MAX);
+    match fcntl::fallocate(
+        dest.as_raw_fd(),
+        FallocateFlags::FALLOC_FL_KEEP_SIZE,
+        0,
+        len,
+    ) {
+        // Not every filesystem supports it, and the copy can go ahead without it.
+        Ok(()) | Err(Errno::EOPNOTSUPP | Errno::ENOSYS | Errno::EINVAL) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn preallocate(_dest: &File, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+/// Copy the contents of the already open file `source` to `dest`, starting from their current
+/// offsets, using the fastest mechanism available (as `copy` does). At most `len` bytes are
+/// copied if given, and otherwise everything up to the end of `source`. Returns the number of
+/// bytes copied and the mechanism which was used to do so.
+pub fn copy_fds(
+    source: &File,
+    dest: &File,
+    len: Option<u64>,
+    content: ContentOptions,
+) -> io::Result<(u64, CopyMechanism)> {
+    match content.mechanism {
+        _ if content.sparse == Some(Sparse::Always) => copy_buffered(source, dest, len, content),
+        _ if content.sparse == Some(Sparse::Auto) => copy_extents(source, dest, len, content),
+        Some(CopyMechanism::Buffered) => copy_buffered(source, dest, len, content),
+        Some(CopyMechanism::Mmap) => copy_mmap(source, dest, len, content),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        Some(CopyMechanism::Sendfile) => copy_sendfile(source, dest, len, content),
+        _ => copy_contents(source, dest, len, content),
+    }
+}
+
+/// Give the existing file `dest` the ownership, permissions, and access and modification times
+/// of `source`, without touching its contents. Symlinks themselves are updated rather than the
+/// files they point to (although their permissions can't be changed). Ownership is only changed
+/// if it differs, so that this succeeds without privileges when it needn't change.
+pub fn copy_attributes<P: AsRef<Path>, Q: AsRef<Path>>(source: P, dest: Q) -> Result<()> {
+    let (source, dest) = (source.as_ref(), dest.as_ref());
+    let metadata = symlink_metadata(source)?;
+    if let Err(err) = fs::symlink_metadata(dest) {
+        let message = format!(
+            "{}: no destination corresponding to '{}' ({})",
+            dest.display(),
+            source.display(),
+            err
+        );
+        return Err(Error::with_os_error(message, "stat", &err));
+    }
+    // Changing the ownership of a file clears its setuid and setgid bits, so it must come before
+    // changing its permissions.
+    copy_ownership(&metadata, dest)?;
+    if !metadata.file_type().is_symlink() {
+        fs::set_permissions(dest, metadata.permissions())
+            .map_err(make_error_message!("chmod", dest))?;
+    }
+    copy_times(&metadata, dest)
+}
+
+/// Give the existing file `dest` the owner and group in `metadata` (those of its source), if they
+/// differ from its own. Symlinks themselves are updated rather than the files they point to.
+pub fn copy_ownership(metadata: &Metadata, dest: &Path) -> Result<()> {
+    use nix::unistd::{FchownatFlags, Gid, Uid};
+    use std::os::unix::fs::MetadataExt;
+
+    let dest_metadata = symlink_metadata(dest)?;
+    if (metadata.uid(), metadata.gid()) == (dest_metadata.uid(), dest_metadata.gid()) {
+        return Ok(());
+    }
+    unistd::fchownat(
+        None,
+        dest,
+        Some(Uid::from_raw(metadata.uid())),
+        Some(Gid::from_raw(metadata.gid())),
+        FchownatFlags::NoFollowSymlink,
+    )
+    .map_err(make_error_message!("chown", dest))
+}
+
+/// Give the existing file `dest` the access and modification times in `metadata` (those of its
+/// source), to the nanosecond. Symlinks themselves are updated rather than the files they point
+/// to.
+pub fn copy_times(metadata: &Metadata, dest: &Path) -> Result<()> {
+    use nix::libc;
+    use nix::sys::stat::{self, UtimensatFlags};
+    use nix::sys::time::TimeSpec;
+    use std::os::unix::fs::MetadataExt;
+
+    let time = |seconds, nanoseconds| {
+        TimeSpec::from(libc::timespec {
+            tv_sec: seconds,
+            tv_nsec: nanoseconds,
+        })
+    };
+    stat::utimensat(
+        None,
+        dest,
+        &time(metadata.atime(), metadata.atime_nsec()),
+        &time(metadata.mtime(), metadata.mtime_nsec()),
+        UtimensatFlags::NoFollowSymlink,
+    )
+    .map_err(make_error_message!("utimensat", dest))
+}
+
+/// The BSD file flags (see `chflags(2)`) in `metadata`, such as `uchg` or `nodump`. Systems
+/// without them report none.
+#[cfg(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+pub fn flags(metadata: &Metadata) -> u32 {
+    #[cfg(target_os = "dragonfly")]
+    use std::os::dragonfly::fs::MetadataExt;
+    #[cfg(target_os = "freebsd")]
+    use std::os::freebsd::fs::MetadataExt;
+    #[cfg(target_os = "macos")]
+    use std::os::macos::fs::MetadataExt;
+    #[cfg(target_os = "netbsd")]
+    use std::os::netbsd::fs::MetadataExt;
+    #[cfg(target_os = "openbsd")]
+    use std::os::openbsd::fs::MetadataExt;
+
+    metadata.st_flags()
+}
+
+#[cfg(not(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+)))]
+pub fn flags(_metadata: &Metadata) -> u32 {
+    0
+}
+
+/// Set the BSD file flags of `path` (or of the symlink itself, if it's one) to `flags`. Once a
+/// file is immutable (e.g. with `uchg`), nothing else about it can be changed, so this should come
+/// last. This does nothing on systems without file flags.
+#[cfg(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+pub fn set_flags<P: AsRef<Path>>(path: P, flags: u32) -> Result<()> {
+    use nix::libc;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = path.as_ref();
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // Safe because the path is null-terminated. The type of the flags differs between systems.
+    #[allow(clippy::useless_conversion)]
+    let result = unsafe { libc::lchflags(c_path.as_ptr(), flags.into()) };
+    Errno::result(result)
+        .map(drop)
+        .map_err(make_error_message!("lchflags", path))
+}
+
+#[cfg(not(any(
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+)))]
+pub fn set_flags<P: AsRef<Path>>(_path: P, _flags: u32) -> Result<()> {
+    Ok(())
+}
+
+/// Set the modification time of `path` (following symlinks) to `time`, leaving its access time
+/// as it is.
+pub fn set_mtime<P: AsRef<Path>>(path: P, time: SystemTime) -> Result<()> {
+    use nix::libc;
+    use nix::sys::stat::{self, UtimensatFlags};
+    use nix::sys::time::TimeSpec;
+
+    let path = path.as_ref();
+    let (seconds, nanoseconds) = match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => (since.as_secs() as i64, since.subsec_nanos()),
+        Err(err) => {
+            let before = err.duration();
+            match before.subsec_nanos() {
+                0 => (-(before.as_secs() as i64), 0),
+                nanoseconds => (-(before.as_secs() as i64) - 1, 1_000_000_000 - nanoseconds),
+            }
+        }
+    };
+    let time = |seconds, nanoseconds| {
+        TimeSpec::from(libc::timespec {
+            tv_sec: seconds,
+            tv_nsec: nanoseconds,
+        })
+    };
+    stat::utimensat(
+        None,
+        path,
+        &time(0, libc::UTIME_OMIT),
+        &time(seconds as libc::time_t, nanoseconds.into()),
+        UtimensatFlags::FollowSymlink,
+    )
+    .map_err(make_error_message!("utimensat", path))
+}
+
+/// Generate a path in `dir` at which to temporarily store the file named `file_name`. The path is
+/// unique to this process, and is never returned more than once.
+pub fn temporary_path(dir: &Path, file_name: &OsStr) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut name = OsStr::new(".").to_os_string();
+    name.push(file_name);
+    name.push(format!(
+        ".fcp-{}-{}",
+        process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    dir.join(name)
+}
+
+/// Move the regular file `source` to `dest`. If they're on different filesystems, `source` is
+/// instead copied to a temporary path beside `dest` which is then renamed over it (so that a
+/// partially-written file never appears at `dest`), and `source` is then removed. With `fsync`,
+/// the directory containing `dest` is synced afterwards, as that's what makes the rename itself
+/// durable (and any copy is synced before being renamed).
+pub fn move_file<P: AsRef<Path>, Q: AsRef<Path>>(source: P, dest: Q, fsync: bool) -> Result<()> {
+    let (source, dest) = (source.as_ref(), dest.as_ref());
+    let dir = dest.parent().unwrap_or_else(|| Path::new("."));
+    match fs::rename(source, dest) {
+        Err(err) if err.raw_os_error() == Some(Errno::EXDEV as i32) => {
+            let temporary = temporary_path(dir, dest.file_name().unwrap_or_default());
+            let content = ContentOptions {
+                fsync,
+                ..ContentOptions::default()
+            };
+            if let Err(err) = copy(source, &temporary, OFlag::O_EXCL, content)
+                .and_then(|_| rename(&temporary, dest))
+            {
+                let _ = fs::remove_file(&temporary);
+                return Err(err);
+            }
+            remove_file(source)?;
+        }
+        result => result.map_err(|err| {
+            let message = format!("{}, {}: {}", source.display(), dest.display(), err);
+            Error::with_os_error(message, "rename", &err)
+        })?,
+    }
+    if fsync {
+        sync_dir(dir)?;
+    }
+    Ok(())
+}
+
+/// Publish `staged` (a complete copy, typically of a directory) at `dest`, replacing whatever is
+/// there. POSIX can't rename a directory over a non-empty one, so any existing `dest` is first
+/// renamed aside, then `staged` is renamed to `dest`, and only then is the old `dest` removed.
+/// Readers thus see either all of the old `dest` or all of `staged`, though for the instant
+/// between the two renames they see nothing at all. If the second rename fails, the old `dest` is
+/// put back. With `fsync`, the directory containing `dest` is synced afterwards.
+pub fn swap_into_place(staged: &Path, dest: &Path, fsync: bool) -> Result<()> {
+    let dir = dest.parent().unwrap_or_else(|| Path::new("."));
+    let old = match fs::symlink_metadata(dest) {
+        Ok(metadata) => {
+            let old = temporary_path(dir, dest.file_name().unwrap_or_default());
+            rename(dest, &old)?;
+            Some((old, metadata.is_dir()))
+        }
+        Err(_) => None,
+    };
+    if let Err(err) = rename(staged, dest) {
+        if let Some((old, _)) = &old {
+            let _ = fs::rename(old, dest);
+        }
+        return Err(err);
+    }
+    match old {
+        Some((old, true)) => remove_dir_all(old)?,
+        Some((old, false)) => remove_file(old)?,
+        None => {}
+    }
+    if fsync {
+        sync_dir(dir)?;
+    }
+    Ok(())
+}
+
+/// Flush the directory `path` to storage, which makes the creation, removal, and renaming of its
+/// entries durable. Syncing a file alone only persists its contents and metadata, not its name.
+pub fn sync_dir<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    File::open(path)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|err| {
+            let message = format!("{}: {}", path.display(), err);
+            Error::with_os_error(message, "fsync", &err)
+        })
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn copy_contents(
+    source: &File,
+    dest: &File,
+    len: Option<u64>,
+    content: ContentOptions,
+) -> io::Result<(u64, CopyMechanism)> {
+    let (mut copied, limit) = (0, len.unwrap_or(u64::MAX));
+    let chunk = chunk_size(&content);
+    while copied < limit {
+        check_cancelled(content.cancel)?;
+        let count = (limit - copied).min(chunk) as usize;
+        match fcntl::copy_file_range(source.as_raw_fd(), None, dest.as_raw_fd(), None, count) {
+            Ok(0) => break,
+            Ok(count) => {
+                copied += count as u64;
+                throttle(&content, count as u64);
+            }
+            Err(Errno::EINTR) => {}
+            // These indicate that `copy_file_range` is unsupported for this pair of files (e.g.
+            // because they're on different filesystems on an older kernel), in which case we
+            // can fall back to `sendfile`, having not yet written anything.
+            Err(
+                Errno::EXDEV | Errno::ENOSYS | Errno::EINVAL | Errno::EOPNOTSUPP | Errno::EPERM,
+            ) if copied == 0 && content.mechanism.is_none() => {
+                return copy_sendfile(source, dest, len, content)
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    // `copy_file_range` copies nothing from the files of some pseudo-filesystems (e.g. procfs)
+    // despite them having contents, so if nothing was copied we make sure by reading directly.
+    if copied == 0 && limit > 0 {
+        return copy_buffered(source, dest, len, content);
+    }
+    Ok((copied, CopyMechanism::CopyFileRange))
+}
+
+// `sendfile` keeps the data within the kernel like `copy_file_range`, but works between any
+// regular files, whatever their filesystems. Where it's unsupported too, the copy falls back to
+// going through userspace.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn copy_sendfile(
+    source: &File,
+    dest: &File,
+    len: Option<u64>,
+    content: ContentOptions,
+) -> io::Result<(u64, CopyMechanism)> {
+    use nix::sys::sendfile;
+
+    let (mut copied, limit) = (0, len.unwrap_or(u64::MAX));
+    // `sendfile` transfers at most 0x7ffff000 bytes per call in any case.
+    let chunk = chunk_size(&content);
+    while copied < limit {
+        check_cancelled(content.cancel)?;
+        let count = (limit - copied).min(chunk) as usize;
+        match sendfile::sendfile(dest.as_raw_fd(), source.as_raw_fd(), None, count) {
+            Ok(0) => break,
+            Ok(count) => {
+                copied += count as u64;
+                throttle(&content, count as u64);
+            }
+            Err(Errno::EINTR) => {}
+            Err(Errno::ENOSYS | Errno::EINVAL) if copied == 0 && content.mechanism.is_none() => {
+                return copy_buffered(source, dest, len, content)
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    // As with `copy_file_range`, nothing may be copied from files which nonetheless have
+    // contents.
+    if copied == 0 && limit > 0 && content.mechanism.is_none() {
+        return copy_buffered(source, dest, len, content);
+    }
+    Ok((copied, CopyMechanism::Sendfile))
+}
+
+// Copying with `COPYFILE_ALL` brings along the extended attributes in which macOS stores resource
+// forks and Finder info, which would otherwise be silently lost. However, it always copies the
+// file in its entirety, so copying only part of one (or from partway through it) has to be done
+// through userspace.
+#[cfg(target_os = "macos")]
+fn copy_contents(
+    source: &File,
+    dest: &File,
+    len: Option<u64>,
+    content: ContentOptions,
+) -> io::Result<(u64, CopyMechanism)> {
+    use nix::libc;
+    use std::ptr;
+
+    let mut position = source;
+    let partial = match len {
+        Some(len) => len < source.metadata()?.len(),
+        None => false,
+    };
+    // `fcopyfile` also copies the whole file at once, which a throttle couldn't pace.
+    if partial || position.stream_position()? != 0 || content.throttle.is_some() {
+        return copy_buffered(source, dest, len, content);
+    }
+
+    // Safe because both file descriptors are valid for the duration of the call, and a null
+    // state is explicitly permitted.
+    let bytes = source.metadata()?.len();
+    let result = unsafe {
+        libc::fcopyfile(
+            source.as_raw_fd(),
+            dest.as_raw_fd(),
+            ptr::null_mut(),
+            libc::COPYFILE_ALL,
+        )
+    };
+    if result == 0 {
+        Ok((bytes, CopyMechanism::Copyfile))
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn copy_xattrs_fd(source: &File, dest: &File) -> io::Result<()> {
+    use nix::libc;
+    use std::ptr;
+
+    // Safe because both file descriptors are valid for the duration of the call, and a null
+    // state is explicitly permitted.
+    let result = unsafe {
+        libc::fcopyfile(
+            source.as_raw_fd(),
+            dest.as_raw_fd(),
+            ptr::null_mut(),
+            libc::COPYFILE_XATTR,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+fn copy_contents(
+    source: &File,
+    dest: &File,
+    len: Option<u64>,
+    content: ContentOptions,
+) -> io::Result<(u64, CopyMechanism)> {
+    copy_buffered(source, dest, len, content)
+}
+
+// A file occupying fewer blocks than its size would need has holes, and only its data extents
+// are copied, each by whichever mechanism would have copied the whole file. Seeking the
+// destination past its end before writing each extent leaves the holes in between. As with
+// `Sparse::Always`, the bytes returned are the size of the copy, holes included.
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+fn copy_extents(
+    mut source: &File,
+    mut dest: &File,
+    len: Option<u64>,
+    content: ContentOptions,
+) -> io::Result<(u64, CopyMechanism)> {
+    use std::os::unix::fs::MetadataExt;
+    use unistd::Whence;
+
+    let content = ContentOptions {
+        sparse: None,
+        ..content
+    };
+    let metadata = source.metadata()?;
+    let size = metadata.len();
+    // Only whole files are copied by extent, as the offsets `SEEK_DATA` finds are absolute.
+    let whole = len.is_some_and(|len| len == size)
+        && source.stream_position()? == 0
+        && dest.stream_position()? == 0;
+    if !whole || metadata.blocks() * 512 >= size {
+        return copy_fds(source, dest, len, content);
+    }
+    let (mut offset, mut copied) = (0, 0);
+    while offset < size {
+        let fd = source.as_raw_fd();
+        let data = match unistd::lseek(fd, offset as i64, Whence::SeekData) {
+            Ok(data) => data as u64,
+            // There's no more data, only (possibly) a trailing hole.
+            Err(Errno::ENXIO) => break,
+            // The filesystem can't find holes, so the rest is copied as though it had none.
+            Err(Errno::EINVAL) if offset == 0 => return copy_fds(source, dest, len, content),
+            Err(err) => return Err(err.into()),
+        };
+        if data >= size {
+            break;
+        }
+        let hole = (unistd::lseek(fd, data as i64, Whence::SeekHole)? as u64).min(size);
+        source.seek(SeekFrom::Start(data))?;
+        dest.seek(SeekFrom::Start(data))?;
+        let (count, _) = copy_fds(source, dest, Some(hole - data), content)?;
+        copied += count;
+        offset = data + count;
+        // The source was truncated while being copied.
+        if count < hole - data {
+            dest.set_len(offset)?;
+            return Ok((
+                offset,
+                CopyMechanism::Extents {
+                    holes: offset - copied,
+                },
+            ));
+        }
+    }
+    // Seeking past the end of a file doesn't extend it, so a trailing hole has to be made by
+    // setting its length.
+    dest.set_len(size)?;
+    Ok((
+        size,
+        CopyMechanism::Extents {
+            holes: size - copied,
+        },
+    ))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+fn copy_extents(
+    source: &File,
+    dest: &File,
+    len: Option<u64>,
+    content: ContentOptions,
+) -> io::Result<(u64, CopyMechanism)> {
+    let content = ContentOptions {
+        sparse: None,
+        ..content
+    };
+    copy_fds(source, dest, len, content)
+}
+
+fn copy_buffered(
+    mut source: &File,
+    mut dest: &File,
+    len: Option<u64>,
+    content: ContentOptions,
+) -> io::Result<(u64, CopyMechanism)> {
+    let size = content.buffer_size.unwrap_or(BUFFER_SIZE);
+    let (mut buffer, mut copied, limit) = (vec![0; size], 0, len.unwrap_or(u64::MAX));
+    let mut holes = 0;
+    let mut sampler = content
+        .adaptive_buffer
+        .then(|| ThroughputSampler::new(size));
+    // The source is read once, from start to end, which lets the kernel read further ahead than
+    // usual (and drop what's been read, where it heeds `NoReuse`). Each buffer's worth is also
+    // requested before it's read, so that slow storage (e.g. a spinning disk or a network
+    // filesystem) is kept busy while the previous one is written.
+    let start = source.stream_position().ok();
+    if let Some(start) = start {
+        advise(source, start, len.unwrap_or(0), Advice::Sequential);
+        advise(source, start, len.unwrap_or(0), Advice::NoReuse);
+    }
+    while copied < limit {
+        check_cancelled(content.cancel)?;
+        let count = buffer
+            .len()
+            .min((limit - copied).try_into().unwrap_or(usize::MAX));
+        match source.read(&mut buffer[..count]) {
+            Ok(0) => break,
+            Ok(count) => {
+                if content.sparse == Some(Sparse::Always) {
+                    holes += write_sparse(dest, &buffer[..count])?;
+                } else {
+                    dest.write_all(&buffer[..count])?;
+                }
+                copied += count as u64;
+                throttle(&content, count as u64);
+                if let Some(size) = sampler.as_mut().and_then(|s| s.record(count, buffer.len())) {
+                    buffer.resize(size, 0);
+                }
+                let ahead = (buffer.len() as u64).min(limit - copied);
+                if let (Some(start), true) = (start, ahead > 0) {
+                    advise(source, start + copied, ahead, Advice::WillNeed);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    // Seeking past the end of a file doesn't extend it, so a trailing hole has to be made by
+    // setting the length explicitly.
+    if content.sparse == Some(Sparse::Always) {
+        let end = dest.stream_position()?;
+        if dest.metadata()?.len() < end {
+            dest.set_len(end)?;
+        }
+        return Ok((copied, CopyMechanism::Sparse { holes }));
+    }
+    Ok((copied, CopyMechanism::Buffered))
+}
+
+// Writing straight from a mapping of the source saves copying its contents into a buffer first,
+// which pays off for large files already in the page cache. The source is mapped a window at a
+// time, and each window unmapped once written. Should the source be truncated while mapped,
+// reading beyond its new end raises `SIGBUS`, which is why this is only used when forced.
+fn copy_mmap(
+    mut source: &File,
+    mut dest: &File,
+    len: Option<u64>,
+    content: ContentOptions,
+) -> io::Result<(u64, CopyMechanism)> {
+    use nix::libc;
+    use std::{ptr, slice};
+
+    let start = source.stream_position()?;
+    let size = source.metadata()?.len();
+    // Files reporting a size of zero may still have contents, which only reading can find.
+    if size == 0 {
+        return copy_buffered(source, dest, len, content);
+    }
+    let limit = size.saturating_sub(start).min(len.unwrap_or(u64::MAX));
+    // Safe because `sysconf` has no preconditions.
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    let page: u64 = page.try_into().unwrap_or(4096);
+    let window = chunk_size(&content).min(MMAP_WINDOW_SIZE);
+    let mut copied = 0;
+    while copied < limit {
+        check_cancelled(content.cancel)?;
+        // Mappings must start at a multiple of the page size.
+        let offset = start + copied;
+        let aligned = offset - offset % page;
+        let count = (limit - copied).min(window);
+        let mapped = (offset - aligned + count) as usize;
+        // Safe because the mapping is only read within its bounds (those of the file, unless it
+        // shrinks), and is unmapped before anything else could refer to it.
+        let address = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                mapped,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                source.as_raw_fd(),
+                aligned as libc::off_t,
+            )
+        };
+        if address == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because `offset - aligned + count` bytes were mapped, and the slice is dropped
+        // before the mapping is unmapped.
+        let written = unsafe {
+            let data = (address as *const u8).add((offset - aligned) as usize);
+            dest.write_all(slice::from_raw_parts(data, count as usize))
+        };
+        // Safe because the mapping was made above with this length, and nothing refers to it any
+        // longer.
+        unsafe { libc::munmap(address, mapped) };
+        written?;
+        copied += count;
+        throttle(&content, count);
+    }
+    // As with the other mechanisms, `source` is left at the end of what was copied.
+    source.seek(SeekFrom::Start(start + copied))?;
+    Ok((copied, CopyMechanism::Mmap))
+}
+
+/// Copy the first `len` bytes of `source` to `dest` in ranges, each copied by a separate task on
+/// the current rayon thread pool, so that a single large file is copied by as many threads as
+/// there are, rather than just one. There are as many ranges as threads (within the bounds of
+/// `MIN_RANGE_SIZE` and `MAX_RANGE_SIZE`), and each is copied with `copy_range`, neither using nor
+/// changing the offsets of the files.
+fn copy_ranges(
+    source: &File,
+    dest: &File,
+    len: u64,
+    content: ContentOptions,
+) -> io::Result<(u64, CopyMechanism)> {
+    use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+
+    let threads = rayon::current_num_threads().max(1) as u64;
+    let size = (len / threads).clamp(MIN_RANGE_SIZE, MAX_RANGE_SIZE);
+    let size = size + (MIN_RANGE_SIZE - size % MIN_RANGE_SIZE) % MIN_RANGE_SIZE;
+    let offsets = (0..len).step_by(size as usize).collect::<Vec<_>>();
+    let ranges = offsets
+        .into_par_iter()
+        .map(|offset| copy_range(source, dest, offset, size.min(len - offset), content))
+        .collect::<io::Result<Vec<_>>>()?;
+    // Should the source shrink while being copied, the ranges beyond its new end copy nothing,
+    // so what was copied is still contiguous.
+    let copied = ranges.iter().map(|&(count, _)| count).sum();
+    Ok((copied, ranges[0].1))
+}
+
+/// Copy `len` bytes of `source` from `offset` to the same offset in `dest`, by
+/// `copy_file_range(2)` where it's supported (as `copy_contents` does) and otherwise through
+/// userspace, with positional reads and writes.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn copy_range(
+    source: &File,
+    dest: &File,
+    offset: u64,
+    len: u64,
+    content: ContentOptions,
+) -> io::Result<(u64, CopyMechanism)> {
+    use nix::libc;
+
+    if content.mechanism == Some(CopyMechanism::Buffered) {
+        return copy_range_buffered(source, dest, offset, len, content);
+    }
+    let chunk = chunk_size(&content);
+    let mut copied = 0;
+    while copied < len {
+        check_cancelled(content.cancel)?;
+        let count = (len - copied).min(chunk) as usize;
+        let mut source_offset = (offset + copied) as libc::loff_t;
+        let mut dest_offset = source_offset;
+        match fcntl::copy_file_range(
+            source.as_raw_fd(),
+            Some(&mut source_offset),
+            dest.as_raw_fd(),
+            Some(&mut dest_offset),
+            count,
+        ) {
+            Ok(0) => break,
+            Ok(count) => {
+                copied += count as u64;
+                throttle(&content, count as u64);
+            }
+            Err(Errno::EINTR) => {}
+            Err(
+                Errno::EXDEV | Errno::ENOSYS | Errno::EINVAL | Errno::EOPNOTSUPP | Errno::EPERM,
+            ) if copied == 0 && content.mechanism.is_none() => {
+                return copy_range_buffered(source, dest, offset, len, content)
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok((copied, CopyMechanism::CopyFileRange))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn copy_range(
+    source: &File,
+    dest: &File,
+    offset: u64,
+    len: u64,
+    content: ContentOptions,
+) -> io::Result<(u64, CopyMechanism)> {
+    copy_range_buffered(source, dest, offset, len, content)
+}
+
+fn copy_range_buffered(
+    source: &File,
+    dest: &File,
+    offset: u64,
+    len: u64,
+    content: ContentOptions,
+) -> io::Result<(u64, CopyMechanism)> {
+    use std::os::unix::fs::FileExt;
+
+    let size = content.buffer_size.unwrap_or(BUFFER_SIZE);
+    let (mut buffer, mut copied) = (vec![0; size], 0);
+    while copied < len {
+        check_cancelled(content.cancel)?;
+        let count = buffer
+            .len()
+            .min((len - copied).try_into().unwrap_or(usize::MAX));
+        match source.read_at(&mut buffer[..count], offset + copied) {
+            Ok(0) => break,
+            Ok(count) => {
+                dest.write_all_at(&buffer[..count], offset + copied)?;
+                copied += count as u64;
+                throttle(&content, count as u64);
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok((copied, CopyMechanism::Buffered))
+}
+
+/// How a file is about to be read, as hinted to the kernel with `posix_fadvise(2)`.
+#[derive(Debug, Clone, Copy)]
+enum Advice {
+    /// From start to end.
+    Sequential,
+    /// Only once.
+    NoReuse,
+    /// Soon, so reading it can begin now.
+    WillNeed,
+}
+
+// These are only hints, so it's of no consequence whether they're heeded, or even supported
+// (which they aren't for pipes, for one). A `len` of zero extends to the end of the file.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn advise(file: &File, offset: u64, len: u64, advice: Advice) {
+    use fcntl::PosixFadviseAdvice;
+
+    let advice = match advice {
+        Advice::Sequential => PosixFadviseAdvice::POSIX_FADV_SEQUENTIAL,
+        Advice::NoReuse => PosixFadviseAdvice::POSIX_FADV_NOREUSE,
+        Advice::WillNeed => PosixFadviseAdvice::POSIX_FADV_WILLNEED,
+    };
+    if let (Ok(offset), Ok(len)) = (offset.try_into(), len.try_into()) {
+        let _ = fcntl::posix_fadvise(file.as_raw_fd(), offset, len, advice);
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn advise(_file: &File, _offset: u64, _len: u64, _advice: Advice) {}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn direct_flag(direct: bool) -> OFlag {
+    match direct {
+        true => OFlag::O_DIRECT,
+        false => OFlag::empty(),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn direct_flag(_direct: bool) -> OFlag {
+    OFlag::empty()
+}
+
+// The most copied by each call to the kernel, which is less with `cancel` or `throttle`, so that
+// they're acted upon promptly.
+fn chunk_size(content: &ContentOptions) -> u64 {
+    match (content.throttle, content.cancel) {
+        (Some(_), _) => THROTTLE_INTERVAL,
+        (None, Some(_)) => CANCEL_CHECK_INTERVAL as u64,
+        (None, None) => 1 << 30,
+    }
+}
+
+fn throttle(content: &ContentOptions, bytes: u64) {
+    if let Some(throttle) = content.throttle {
+        throttle.record(bytes);
+    }
+}
+
+// Fail with `ECANCELED` if `cancel` has been set.
+fn check_cancelled(cancel: Option<&AtomicBool>) -> io::Result<()> {
+    match cancel {
+        Some(cancel) if cancel.load(Ordering::Relaxed) => {
+            Err(io::Error::from_raw_os_error(nix::libc::ECANCELED))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Whether `file` is open with `O_DIRECT`.
+fn is_direct(file: &File) -> bool {
+    fcntl::fcntl(file.as_raw_fd(), fcntl::FcntlArg::F_GETFL)
+        .is_ok_and(|flags| OFlag::from_bits_truncate(flags).contains(direct_flag(true)))
+}
+
+// Take `file` off of `O_DIRECT`, so that transfers to and from it go through the page cache.
+fn clear_direct(file: &File) -> io::Result<()> {
+    let flags = fcntl::fcntl(file.as_raw_fd(), fcntl::FcntlArg::F_GETFL)?;
+    let mut flags = OFlag::from_bits_truncate(flags);
+    flags.remove(direct_flag(true));
+    fcntl::fcntl(file.as_raw_fd(), fcntl::FcntlArg::F_SETFL(flags))?;
+    Ok(())
+}
+
+// The alignment `O_DIRECT` requires for transfers to and from `file`: the logical block size of
+// the device it's on, or where that isn't known (e.g. for network filesystems), the preferred
+// block size it reports. Files which aren't open with `O_DIRECT` require none.
+fn direct_alignment(file: &File) -> io::Result<usize> {
+    use std::os::unix::fs::MetadataExt;
+
+    if !is_direct(file) {
+        return Ok(1);
+    }
+    let metadata = file.metadata()?;
+    let block_size = queue_attribute(metadata.dev(), "logical_block_size")
+        .and_then(|size| size.trim().parse().ok())
+        .unwrap_or_else(|| metadata.blksize());
+    Ok((block_size as usize).max(512))
+}
+
+fn lcm(a: usize, b: usize) -> usize {
+    let (mut x, mut y) = (a, b);
+    while y != 0 {
+        let remainder = x % y;
+        x = y;
+        y = remainder;
+    }
+    a / x * b
+}
+
+// Open `path` for reading, with `O_DIRECT` if `direct` is set and the filesystem supports it.
+fn open_direct(path: &Path, direct: bool) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true);
+    match options.custom_flags(direct_flag(direct).bits()).open(path) {
+        Err(err) if direct && err.raw_os_error() == Some(Errno::EINVAL as i32) => {
+            options.custom_flags(0).open(path)
+        }
+        result => result,
+    }
+}
+
+/// Copy the first `len` bytes of `source` to `dest`, which were opened with `O_DIRECT` where their
+/// filesystems support it. `O_DIRECT` requires every transfer's size and offset to be multiples of
+/// the block size of the device a file is on, and its buffer to be aligned in memory likewise, so
+/// the buffer is sized and aligned to the least common multiple of those of `source` and `dest`
+/// (see `direct_alignment`), which suits both even if their devices differ. The final block of a
+/// file usually isn't such a multiple, so `dest` is taken off of `O_DIRECT` to write it. Should
+/// either file nonetheless reject a transfer as misaligned (with `EINVAL`), it's taken off of
+/// `O_DIRECT` too, so that the copy carries on through the page cache on that side alone.
+fn copy_direct(
+    mut source: &File,
+    mut dest: &File,
+    len: u64,
+    content: ContentOptions,
+) -> io::Result<(u64, CopyMechanism)> {
+    let cancel = content.cancel;
+    if !is_direct(source) && !is_direct(dest) {
+        // Neither filesystem supports `O_DIRECT`, so there's nothing to align for.
+        let content = ContentOptions {
+            cancel,
+            buffer_size: content.buffer_size,
+            throttle: content.throttle,
+            ..ContentOptions::default()
+        };
+        return copy_buffered(source, dest, Some(len), content);
+    }
+    let alignment = lcm(direct_alignment(source)?, direct_alignment(dest)?);
+    let size = content.buffer_size.unwrap_or(BUFFER_SIZE);
+    let size = alignment * (size / alignment).max(1);
+    let memory_alignment = alignment.next_power_of_two();
+    let mut allocation = vec![0; size + memory_alignment];
+    let offset = allocation.as_ptr().align_offset(memory_alignment);
+    let buffer = &mut allocation[offset..offset + size];
+    let misaligned = |err: &io::Error| err.raw_os_error() == Some(Errno::EINVAL as i32);
+    let mut copied = 0;
+    while copied < len {
+        check_cancelled(cancel)?;
+        let count = match source.read(buffer) {
+            Ok(0) => break,
+            Ok(count) => count.min((len - copied).try_into().unwrap_or(usize::MAX)),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) if misaligned(&err) && is_direct(source) => {
+                clear_direct(source)?;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        if count % alignment != 0 && is_direct(dest) {
+            clear_direct(dest)?;
+        }
+        let mut written = 0;
+        while written < count {
+            match dest.write(&buffer[written..count]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(count) => written += count,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) if misaligned(&err) && is_direct(dest) => clear_direct(dest)?,
+                Err(err) => return Err(err),
+            }
+        }
+        copied += count as u64;
+        throttle(&content, count as u64);
+    }
+    Ok((copied, CopyMechanism::Direct))
+}
+
+// Returns the number of bytes which were seeked over rather than written.
+fn write_sparse(mut dest: &File, data: &[u8]) -> io::Result<u64> {
+    let mut holes = 0;
+    for block in data.chunks(SPARSE_BLOCK_SIZE) {
+        if block.iter().all(|&byte| byte == 0) {
+            dest.seek(SeekFrom::Current(block.len() as i64))?;
+            holes += block.len() as u64;
+        } else {
+            dest.write_all(block)?;
+        }
+    }
+    Ok(holes)
+}
+
+/// Measures the throughput of a buffered copy in order to size its buffer. The buffer is doubled
+/// after each sample for as long as throughput keeps improving, and once it plateaus the buffer
+/// is returned to the last size which helped and left there for the rest of the copy.
+struct ThroughputSampler {
+    /// The size the buffer started at, below which it's never shrunk.
+    initial: usize,
+    start: Instant,
+    bytes: u64,
+    best: f64,
+    settled: bool,
+}
+
+impl ThroughputSampler {
+    fn new(initial: usize) -> Self {
+        ThroughputSampler {
+            initial,
+            start: Instant::now(),
+            bytes: 0,
+            best: 0.0,
+            settled: false,
+        }
+    }
+
+    /// Record that `bytes` were copied through a buffer of `size` bytes, returning the size to
+    /// which the buffer should be changed, if any.
+    fn record(&mut self, bytes: usize, size: usize) -> Option<usize> {
+        if self.settled {
+            return None;
+        }
+        self.bytes += bytes as u64;
+        if self.bytes < (size * READS_PER_SAMPLE) as u64 {
+            return None;
+        }
+        let throughput = self.bytes as f64 / self.start.elapsed().as_secs_f64();
+        self.start = Instant::now();
+        self.bytes = 0;
+        if throughput > self.best * IMPROVEMENT_THRESHOLD && size < MAX_BUFFER_SIZE {
+            self.best = throughput;
+            Some(size * 2)
+        } else if throughput > self.best * IMPROVEMENT_THRESHOLD || size == self.initial {
+            self.settled = true;
+            None
+        } else {
+            // The last doubling didn't pay off, so go back to the size before it.
+            self.settled = true;
+            Some(size / 2)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum FileType {
     Regular,
     Directory,
@@ -91,6 +1670,21 @@ pub enum FileType {
     BlockDevice,
 }
 
+impl FileType {
+    /// A short lowercase name for the type (e.g. `regular` or `symlink`).
+    pub fn name(&self) -> &'static str {
+        match self {
+            FileType::Regular => "regular",
+            FileType::Directory => "directory",
+            FileType::Symlink => "symlink",
+            FileType::Fifo => "fifo",
+            FileType::Socket => "socket",
+            FileType::CharacterDevice => "character_device",
+            FileType::BlockDevice => "block_device",
+        }
+    }
+}
+
 impl From<std::fs::FileType> for FileType {
     fn from(file_type: std::fs::FileType) -> Self {
         if file_type.is_file() {
@@ -113,6 +1707,569 @@ impl From<std::fs::FileType> for FileType {
     }
 }
 
+/// Take ownership of the file descriptor `fd`, inherited already open (e.g. as one end of a pipe
+/// set up by a parent process), after checking that it's open for writing.
+pub fn writable_fd(fd: RawFd) -> Result<File> {
+    let flags = fcntl::fcntl(fd, fcntl::FcntlArg::F_GETFL).map_err(|err| {
+        let err = io::Error::from(err);
+        Error::with_os_error(format!("File descriptor {}: {}", fd, err), "fcntl", &err)
+    })?;
+    if OFlag::from_bits_truncate(flags) & OFlag::O_ACCMODE == OFlag::O_RDONLY {
+        return Err(Error::new(format!(
+            "File descriptor {}: not open for writing",
+            fd
+        )));
+    }
+    // Safe because nothing else in the process uses the file descriptor, which was inherited for
+    // this purpose.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Fail if traversing `path` would require following a symlink in any of its components, including
+/// the last. Each component is opened relative to its parent with `O_NOFOLLOW`, so the check
+/// can't be fooled by symlinks elsewhere along the path. It's fine for the last component not to
+/// exist yet, as it will then be created by the copy.
+pub fn reject_symlinks(path: &Path) -> Result<()> {
+    let open = |dir: Option<&File>, component: &Path| {
+        let flags = OFlag::O_RDONLY | OFlag::O_CLOEXEC | OFlag::O_NOFOLLOW;
+        match dir {
+            Some(dir) => fcntl::openat(dir.as_raw_fd(), component, flags, Mode::empty()),
+            None => fcntl::open(component, flags, Mode::empty()),
+        }
+        // Safe because the file descriptor was just opened and is owned by nothing else.
+        .map(|fd| unsafe { File::from_raw_fd(fd) })
+    };
+    let mut traversed = PathBuf::new();
+    let mut dir = open(None, Path::new(if path.is_absolute() { "/" } else { "." }))
+        .map_err(make_error_message!("open", path))?;
+    let mut components = path.components().peekable();
+    while let Some(component) = components.next() {
+        traversed.push(component);
+        let component = match component {
+            Component::Normal(component) => Path::new(component),
+            Component::ParentDir => Path::new(".."),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => continue,
+        };
+        match open(Some(&dir), component) {
+            Ok(file) => dir = file,
+            Err(Errno::ENOENT) if components.peek().is_none() => {}
+            Err(_) if is_symlink(&traversed) => {
+                return Err(Error::new(format!(
+                    "{}: refusing to follow symlink in destination path",
+                    traversed.display()
+                )));
+            }
+            Err(err) => return Err(make_error_message!("open", traversed)(err)),
+        }
+    }
+    Ok(())
+}
+
+/// Whether `path` (or its parent, if `path` doesn't exist yet) resides on a rotational storage
+/// device such as a hard disk drive. Returns `None` when this can't be determined, which is
+/// always the case on platforms other than Linux.
+#[cfg(target_os = "linux")]
+pub fn is_rotational(path: &Path) -> Option<bool> {
+    Some(queue_attribute(device(path)?, "rotational")?.trim() == "1")
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_rotational(_path: &Path) -> Option<bool> {
+    None
+}
+
+/// Whether `path` (or its parent, if `path` doesn't exist yet) resides on an NVMe device, which
+/// can serve many more requests at once than other solid-state storage. Returns `None` when this
+/// can't be determined, which is always the case on platforms other than Linux.
+#[cfg(target_os = "linux")]
+pub fn is_nvme(path: &Path) -> Option<bool> {
+    use nix::sys::stat::{major, minor};
+
+    let device = device(path)?;
+    // The device's entry in sysfs links to its directory, which is named after the device (e.g.
+    // `nvme0n1`, or `nvme0n1p1` for a partition).
+    let link = format!("/sys/dev/block/{}:{}", major(device), minor(device));
+    let target = fs::read_link(link).ok()?;
+    Some(target.file_name()?.to_string_lossy().starts_with("nvme"))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_nvme(_path: &Path) -> Option<bool> {
+    None
+}
+
+// The device on which `path` resides, or its parent if `path` doesn't exist yet.
+#[cfg(target_os = "linux")]
+fn device(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    match fs::metadata(path) {
+        Ok(metadata) => Some(metadata.dev()),
+        Err(_) => match path.parent() {
+            Some(parent) if parent != Path::new("") => Some(fs::metadata(parent).ok()?.dev()),
+            _ => Some(fs::metadata(".").ok()?.dev()),
+        },
+    }
+}
+
+// The attribute `name` of the request queue of the block device `device`, as read from sysfs.
+#[cfg(target_os = "linux")]
+fn queue_attribute(device: u64, name: &str) -> Option<String> {
+    use nix::sys::stat::{major, minor};
+
+    let device = PathBuf::from(format!(
+        "/sys/dev/block/{}:{}",
+        major(device),
+        minor(device)
+    ));
+    // Partitions don't have a queue of their own, but share that of their parent device.
+    [device.join("queue"), device.join("../queue")]
+        .iter()
+        .find_map(|queue| fs::read_to_string(queue.join(name)).ok())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn queue_attribute(_device: u64, _name: &str) -> Option<String> {
+    None
+}
+
+/// Whether names in the directory `dir` are matched case-insensitively (as on APFS and exFAT by
+/// default), determined by creating a temporary file in `dir` and looking it up by its name in
+/// upper case. Returns `None` when this can't be determined (e.g. because `dir` isn't writable).
+pub fn is_case_insensitive(dir: &Path) -> Option<bool> {
+    let probe = temporary_path(dir, OsStr::new("case-probe"));
+    let upper = probe.with_file_name(probe.file_name()?.to_string_lossy().to_uppercase());
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .ok()?;
+    let insensitive = fs::symlink_metadata(upper).is_ok();
+    let _ = fs::remove_file(&probe);
+    Some(insensitive)
+}
+
+/// The extended attribute in which Linux stores a file's capabilities (see `capabilities(7)`).
+#[cfg(target_os = "linux")]
+const CAPABILITY_XATTR: &[u8] = b"security.capability\0";
+
+/// The extended attribute marking a directory in an overlayfs layer as opaque, hiding the
+/// contents of the same directory in the layers beneath it.
+#[cfg(target_os = "linux")]
+const OVERLAY_OPAQUE_XATTR: &[u8] = b"trusted.overlay.opaque\0";
+
+/// The value of the extended attribute `name` (which must be null-terminated) of the file at
+/// `path` (or the symlink itself, if it's one), or `None` if it has no such attribute.
+#[cfg(target_os = "linux")]
+fn xattr(path: &Path, name: &[u8]) -> Result<Option<Vec<u8>>> {
+    use nix::libc;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // Safe because both strings are null-terminated, and the buffer is valid for its whole length.
+    let value = read_sized(|buffer| unsafe {
+        libc::lgetxattr(
+            c_path.as_ptr(),
+            name.as_ptr().cast(),
+            buffer.as_mut_ptr().cast(),
+            buffer.len(),
+        )
+    });
+    match value {
+        Err(Errno::ENODATA) | Err(Errno::EOPNOTSUPP) => Ok(None),
+        value => Ok(Some(value.map_err(make_error_message!("getxattr", path))?)),
+    }
+}
+
+/// Set the extended attribute `name` (which must be null-terminated) of the file at `path` (or the
+/// symlink itself, if it's one).
+#[cfg(target_os = "linux")]
+fn set_xattr(path: &Path, name: &[u8], value: &[u8]) -> Result<()> {
+    use nix::libc;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // Safe because both strings are null-terminated, and `value` is valid for its whole length.
+    let result = unsafe {
+        libc::lsetxattr(
+            c_path.as_ptr(),
+            name.as_ptr().cast(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    Errno::result(result)
+        .map(drop)
+        .map_err(make_error_message!("setxattr", path))
+}
+
+/// The extended attributes in which Linux stores POSIX ACLs: the access ACL of any file, and the
+/// default ACL of a directory, which the files created in it inherit.
+#[cfg(target_os = "linux")]
+const ACL_XATTRS: [&[u8]; 2] = [b"system.posix_acl_access\0", b"system.posix_acl_default\0"];
+
+/// The POSIX ACLs of the file at `path` in the raw form of their extended
+/// attributes, as `(name, value)` pairs. Files whose permissions are just those of their mode
+/// have none, as do those on filesystems which don't support ACLs.
+#[cfg(target_os = "linux")]
+pub fn acls<P: AsRef<Path>>(path: P) -> Result<Vec<(&'static [u8], Vec<u8>)>> {
+    let mut acls = Vec::new();
+    for name in ACL_XATTRS {
+        if let Some(value) = xattr(path.as_ref(), name)? {
+            acls.push((name, value));
+        }
+    }
+    Ok(acls)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn acls<P: AsRef<Path>>(_path: P) -> Result<Vec<(&'static [u8], Vec<u8>)>> {
+    Ok(Vec::new())
+}
+
+/// Give the file at `path` the `acls` previously returned by `acls`. This fails with `EOPNOTSUPP`
+/// on filesystems which don't support ACLs.
+#[cfg(target_os = "linux")]
+pub fn set_acls<P: AsRef<Path>>(path: P, acls: &[(&[u8], Vec<u8>)]) -> Result<()> {
+    for (name, value) in acls {
+        set_xattr(path.as_ref(), name, value)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_acls<P: AsRef<Path>>(_path: P, _acls: &[(&[u8], Vec<u8>)]) -> Result<()> {
+    Ok(())
+}
+
+/// Copy the extended attributes of `source` to `dest` (without following symlinks), in which macOS
+/// stores resource forks, Finder info, and quarantine flags. Regular files copied with `copy`
+/// already bring these along, but directories (e.g. app bundles) and symlinks need them copied
+/// separately. This does nothing on other systems.
+#[cfg(target_os = "macos")]
+pub fn copy_finder_metadata<P: AsRef<Path>, Q: AsRef<Path>>(source: P, dest: Q) -> Result<()> {
+    use nix::libc;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::ptr;
+
+    let (source, dest) = (source.as_ref(), dest.as_ref());
+    let c_source = CString::new(source.as_os_str().as_bytes())?;
+    let c_dest = CString::new(dest.as_os_str().as_bytes())?;
+    // Safe because both paths are valid null-terminated strings, and a null state is explicitly
+    // permitted.
+    let result = unsafe {
+        libc::copyfile(
+            c_source.as_ptr(),
+            c_dest.as_ptr(),
+            ptr::null_mut(),
+            libc::COPYFILE_XATTR | libc::COPYFILE_NOFOLLOW,
+        )
+    };
+    Errno::result(result).map(drop).map_err(|err| {
+        let err = io::Error::from(err);
+        let message = format!("{}, {}: {}", source.display(), dest.display(), err);
+        Error::with_os_error(message, "copyfile", &err)
+    })
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn copy_finder_metadata<P: AsRef<Path>, Q: AsRef<Path>>(_source: P, _dest: Q) -> Result<()> {
+    Ok(())
+}
+
+/// The namespaces of the extended attributes copied by `xattrs` and `set_xattrs`. The others are
+/// either copied separately (`security`, with `--preserve=caps` and `--preserve=context`) or
+/// managed by the kernel (`system`, which holds ACLs).
+#[cfg(target_os = "linux")]
+const COPIED_XATTR_NAMESPACES: [&[u8]; 2] = [b"user.", b"trusted."];
+
+/// The extended attributes of the file at `path` (or of the symlink itself) in the `user` and
+/// `trusted` namespaces, as `(name, value)` pairs. Those in `trusted` can only be read with
+/// `CAP_SYS_ADMIN`, and are otherwise omitted. Filesystems which don't support extended
+/// attributes have none.
+#[cfg(target_os = "linux")]
+pub fn xattrs<P: AsRef<Path>>(path: P) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    use nix::libc;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = path.as_ref();
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // Safe because `c_path` is null-terminated, and the buffer is valid for its whole length.
+    let names = read_sized(|buffer| unsafe {
+        libc::llistxattr(c_path.as_ptr(), buffer.as_mut_ptr().cast(), buffer.len())
+    });
+    let names = match names {
+        Err(Errno::EOPNOTSUPP) => return Ok(Vec::new()),
+        names => names.map_err(make_error_message!("listxattr", path))?,
+    };
+    let mut xattrs = Vec::new();
+    for name in names.split(|&byte| byte == 0) {
+        if !COPIED_XATTR_NAMESPACES
+            .iter()
+            .any(|namespace| name.starts_with(namespace))
+        {
+            continue;
+        }
+        let c_name = CString::new(name)?;
+        // Safe because both strings are null-terminated, and the buffer is valid for its whole
+        // length.
+        let value = read_sized(|buffer| unsafe {
+            libc::lgetxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                buffer.as_mut_ptr().cast(),
+                buffer.len(),
+            )
+        });
+        match value {
+            // The attribute was removed since the names were listed.
+            Err(Errno::ENODATA) => {}
+            value => xattrs.push((
+                name.to_vec(),
+                value.map_err(make_error_message!("getxattr", path))?,
+            )),
+        }
+    }
+    Ok(xattrs)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn xattrs<P: AsRef<Path>>(_path: P) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    Ok(Vec::new())
+}
+
+/// Give the file at `path` (or the symlink itself) the extended attributes `xattrs`, as
+/// previously returned by `xattrs`.
+#[cfg(target_os = "linux")]
+pub fn set_xattrs<P: AsRef<Path>>(path: P, xattrs: &[(Vec<u8>, Vec<u8>)]) -> Result<()> {
+    use nix::libc;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = path.as_ref();
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    for (name, value) in xattrs {
+        let c_name = CString::new(name.as_slice())?;
+        // Safe because both strings are null-terminated, and `value` is valid for its whole
+        // length.
+        let result = unsafe {
+            libc::lsetxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        Errno::result(result).map_err(|err| {
+            let err = io::Error::from(err);
+            let message = format!(
+                "{}: couldn't set extended attribute '{}': {}",
+                path.display(),
+                String::from_utf8_lossy(name),
+                err
+            );
+            Error::with_os_error(message, "setxattr", &err)
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_xattrs<P: AsRef<Path>>(_path: P, _xattrs: &[(Vec<u8>, Vec<u8>)]) -> Result<()> {
+    Ok(())
+}
+
+// Read a value of unknown length with `read`, which fills the buffer it's given and returns the
+// length of the value, or given an empty buffer, just returns the length. Should the value grow
+// between the two calls (failing with `ERANGE`), it's read again.
+#[cfg(target_os = "linux")]
+fn read_sized(mut read: impl FnMut(&mut [u8]) -> isize) -> nix::Result<Vec<u8>> {
+    loop {
+        let len = Errno::result(read(&mut []))?;
+        let mut buffer = vec![0; len as usize];
+        match Errno::result(read(&mut buffer)) {
+            Err(Errno::ERANGE) => continue,
+            len => {
+                buffer.truncate(len? as usize);
+                return Ok(buffer);
+            }
+        }
+    }
+}
+
+/// The capabilities (see `capabilities(7)`) of the file at `path` in their raw extended attribute
+/// form, or `None` if it has none. Files never have capabilities on platforms other than Linux.
+#[cfg(target_os = "linux")]
+pub fn capabilities<P: AsRef<Path>>(path: P) -> Result<Option<Vec<u8>>> {
+    xattr(path.as_ref(), CAPABILITY_XATTR)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn capabilities<P: AsRef<Path>>(_path: P) -> Result<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+/// Give the file at `path` the `capabilities` previously returned by `capabilities`. This
+/// requires `CAP_SETFCAP`, which in practice means running as root.
+#[cfg(target_os = "linux")]
+pub fn set_capabilities<P: AsRef<Path>>(path: P, capabilities: &[u8]) -> Result<()> {
+    set_xattr(path.as_ref(), CAPABILITY_XATTR, capabilities)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_capabilities<P: AsRef<Path>>(_path: P, _capabilities: &[u8]) -> Result<()> {
+    Ok(())
+}
+
+/// The value of the attribute marking the directory at `path` as opaque in an overlayfs layer,
+/// or `None` if it isn't marked. Reading it requires `CAP_SYS_ADMIN`, without which directories
+/// appear unmarked. There's no overlayfs on platforms other than Linux.
+#[cfg(target_os = "linux")]
+pub fn overlay_opaque<P: AsRef<Path>>(path: P) -> Result<Option<Vec<u8>>> {
+    xattr(path.as_ref(), OVERLAY_OPAQUE_XATTR)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn overlay_opaque<P: AsRef<Path>>(_path: P) -> Result<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+/// Mark the directory at `path` as opaque, with the `value` previously returned by
+/// `overlay_opaque`. This requires `CAP_SYS_ADMIN`.
+#[cfg(target_os = "linux")]
+pub fn set_overlay_opaque<P: AsRef<Path>>(path: P, value: &[u8]) -> Result<()> {
+    set_xattr(path.as_ref(), OVERLAY_OPAQUE_XATTR, value)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_overlay_opaque<P: AsRef<Path>>(_path: P, _value: &[u8]) -> Result<()> {
+    Ok(())
+}
+
+/// The extended attribute in which SELinux stores a file's security context, as a null-terminated
+/// string. Without the `selinux` feature, contexts are copied through it directly.
+#[cfg(all(target_os = "linux", not(feature = "selinux")))]
+const SECURITY_CONTEXT_XATTR: &[u8] = b"security.selinux\0";
+
+/// Whether SELinux is enabled, without libselinux: it's only enabled when its filesystem is
+/// mounted, which libselinux checks for too.
+#[cfg(all(target_os = "linux", not(feature = "selinux")))]
+fn is_selinux_enabled() -> bool {
+    Path::new("/sys/fs/selinux/enforce").exists()
+}
+
+/// The parts of libselinux used to copy SELinux security contexts, which go through the policy
+/// layer to validate them, unlike writing the `security.selinux` extended attribute directly.
+#[cfg(feature = "selinux")]
+mod selinux {
+    use nix::libc::{c_char, c_int};
+
+    #[link(name = "selinux")]
+    extern "C" {
+        pub fn is_selinux_enabled() -> c_int;
+        pub fn lgetfilecon(path: *const c_char, context: *mut *mut c_char) -> c_int;
+        pub fn lsetfilecon(path: *const c_char, context: *const c_char) -> c_int;
+        pub fn freecon(context: *mut c_char);
+    }
+}
+
+/// The SELinux security context of the file at `path` (without following it if it's a symlink),
+/// or `None` if SELinux is disabled or the file's filesystem doesn't support labels.
+#[cfg(feature = "selinux")]
+pub fn security_context<P: AsRef<Path>>(path: P) -> Result<Option<String>> {
+    use std::ffi::{CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+    use std::ptr;
+
+    // Safe because it takes no arguments and merely reads the state of the system.
+    if unsafe { selinux::is_selinux_enabled() } <= 0 {
+        return Ok(None);
+    }
+    let path = path.as_ref();
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut context = ptr::null_mut();
+    // Safe because `c_path` is null-terminated, and `context` is only read if the call succeeds.
+    match Errno::result(unsafe { selinux::lgetfilecon(c_path.as_ptr(), &mut context) }) {
+        Err(Errno::ENODATA) | Err(Errno::EOPNOTSUPP) => Ok(None),
+        result => {
+            result.map_err(make_error_message!("lgetfilecon", path))?;
+            // Safe because on success `context` points to a null-terminated string allocated by
+            // libselinux, which must be freed with `freecon` once it's been copied.
+            let copied = unsafe { CStr::from_ptr(context) }
+                .to_string_lossy()
+                .into_owned();
+            unsafe { selinux::freecon(context) };
+            Ok(Some(copied))
+        }
+    }
+}
+
+/// Without libselinux, the context is read from its extended attribute directly, though this
+/// can't tell whether the policy considers it valid.
+#[cfg(all(target_os = "linux", not(feature = "selinux")))]
+pub fn security_context<P: AsRef<Path>>(path: P) -> Result<Option<String>> {
+    if !is_selinux_enabled() {
+        return Ok(None);
+    }
+    let context = xattr(path.as_ref(), SECURITY_CONTEXT_XATTR)?;
+    Ok(context.map(|context| {
+        let context = context.strip_suffix(b"\0").unwrap_or(&context);
+        String::from_utf8_lossy(context).into_owned()
+    }))
+}
+
+#[cfg(not(any(target_os = "linux", feature = "selinux")))]
+pub fn security_context<P: AsRef<Path>>(_path: P) -> Result<Option<String>> {
+    Ok(None)
+}
+
+/// Give the file at `path` (or the symlink itself, if it's one) the SELinux security `context`,
+/// which the policy may reject. This does nothing if SELinux is disabled.
+#[cfg(feature = "selinux")]
+pub fn set_security_context<P: AsRef<Path>>(path: P, context: &str) -> Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    // Safe because it takes no arguments and merely reads the state of the system.
+    if unsafe { selinux::is_selinux_enabled() } <= 0 {
+        return Ok(());
+    }
+    let path = path.as_ref();
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let c_context = CString::new(context)?;
+    // Safe because both strings are null-terminated.
+    Errno::result(unsafe { selinux::lsetfilecon(c_path.as_ptr(), c_context.as_ptr()) })
+        .map(drop)
+        .map_err(make_error_message!("lsetfilecon", path))
+}
+
+/// Without libselinux, the context is written to its extended attribute directly, which the
+/// kernel still checks against the policy.
+#[cfg(all(target_os = "linux", not(feature = "selinux")))]
+pub fn set_security_context<P: AsRef<Path>>(path: P, context: &str) -> Result<()> {
+    if !is_selinux_enabled() {
+        return Ok(());
+    }
+    let mut value = context.as_bytes().to_vec();
+    value.push(0);
+    set_xattr(path.as_ref(), SECURITY_CONTEXT_XATTR, &value)
+}
+
+#[cfg(not(any(target_os = "linux", feature = "selinux")))]
+pub fn set_security_context<P: AsRef<Path>>(_path: P, _context: &str) -> Result<()> {
+    Ok(())
+}
+
+fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_symlink())
+}
+
 pub fn file_type(path: &Path) -> Result<FileType> {
     Ok(FileType::from(symlink_metadata(path)?.file_type()))
 }
@@ -0,0 +1,157 @@
+//! Flattening with `--flatten`, which copies every regular file found anywhere under the sources
+//! directly into the destination directory (e.g. `logs/a/x.log` and `logs/b/y.log` to
+//! `dest/x.log` and `dest/y.log`), as when gathering files from a nested tree into one place.
+//!
+//! Directories are traversed but not recreated, and anything other than regular files and
+//! directories is skipped. The files are found before any of them is copied, in order of their
+//! paths, so that which of those with the same name is copied under it (and with
+//! `--on-collision=rename`, which suffix each of the others gets) is the same on every run.
+
+use crate::error::{Error, Result};
+use crate::filesystem::{self as fs, FileType};
+use crate::options::Collision;
+use crate::{copy_file, log_skipped, omit_directory, report_error, Context};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::ops::BitOr;
+use std::path::{Path, PathBuf};
+
+/// Copy the regular files under `sources` into the directory `dest`, creating it if it doesn't
+/// exist, and returning whether any error occurred.
+pub(crate) fn copy_flattened(sources: &[PathBuf], dest: &Path, context: &Context) -> Result<bool> {
+    if !dest.exists() {
+        fs::create_dir(dest, 0o777)?;
+    }
+    if !fs::metadata(dest)?.is_dir() {
+        return Err(Error::new(format!("{} is not a directory", dest.display())));
+    }
+    let mut files = Vec::new();
+    let mut has_err = false;
+    for (index, source) in sources.iter().enumerate() {
+        has_err |= find_files(source, &mut files, &context.for_source(index));
+    }
+    // The source first copied under each name.
+    let mut copied = HashMap::<OsString, &Path>::new();
+    let mut copies = Vec::with_capacity(files.len());
+    for (source, index) in &files {
+        let context = &context.for_source(*index);
+        let name = source.file_name().unwrap_or_default();
+        let first = match copied.get(name) {
+            None => {
+                copied.insert(name.to_os_string(), source);
+                copies.push((source, *index, dest.join(name)));
+                continue;
+            }
+            Some(first) => *first,
+        };
+        match context.options.on_collision {
+            None => {
+                let err = Error::new(format!(
+                    "{}: not copied, as '{}' was already copied to '{}'",
+                    source.display(),
+                    first.display(),
+                    dest.join(name).display()
+                ));
+                report_error(source, Some(FileType::Regular), &err, context);
+                has_err = true;
+            }
+            Some(Collision::Skip) => {
+                eprintln!(
+                    "Warning: skipping '{}', as '{}' was already copied to '{}'",
+                    source.display(),
+                    first.display(),
+                    dest.join(name).display()
+                );
+                log_skipped(
+                    source,
+                    "name already copied, with --on-collision=skip",
+                    context,
+                );
+            }
+            Some(Collision::Rename) => {
+                let renamed = (1..)
+                    .map(|suffix| suffixed(Path::new(name), suffix))
+                    .find(|renamed| !copied.contains_key(renamed))
+                    .unwrap();
+                copies.push((source, *index, dest.join(&renamed)));
+                copied.insert(renamed, source);
+            }
+        }
+    }
+    Ok(copies
+        .into_par_iter()
+        .map(|(source, index, target)| {
+            copy_file(
+                source,
+                Ok(FileType::Regular),
+                &target,
+                &context.for_source(index),
+            )
+        })
+        .reduce(|| false, BitOr::bitor)
+        | has_err)
+}
+
+// Add the regular files at or beneath `path` to `files`, along with the index of the source
+// they're within, returning whether any error occurred.
+fn find_files(path: &Path, files: &mut Vec<(PathBuf, usize)>, context: &Context) -> bool {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            report_error(path, None, &err, context);
+            return true;
+        }
+    };
+    if metadata.is_file() {
+        files.push((path.to_path_buf(), context.source.unwrap_or_default()));
+        return false;
+    }
+    if !metadata.is_dir() {
+        log_skipped(path, "not a regular file, with --flatten", context);
+        return false;
+    }
+    if context.options.no_recursive {
+        report_error(
+            path,
+            Some(FileType::Directory),
+            &omit_directory(path),
+            context,
+        );
+        return true;
+    }
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(err) => {
+            report_error(path, Some(FileType::Directory), &err, context);
+            return true;
+        }
+    };
+    let mut has_err = false;
+    let mut names = Vec::new();
+    for entry in entries {
+        match entry {
+            Ok(entry) => names.push(entry.file_name()),
+            Err(err) => {
+                eprintln!("{}", err);
+                has_err = true;
+            }
+        }
+    }
+    names.sort_unstable();
+    for name in names {
+        has_err |= find_files(&path.join(name), files, context);
+    }
+    has_err
+}
+
+// The file name `name` with `suffix` added before its extension, if any (e.g. `a-1.txt`).
+fn suffixed(name: &Path, suffix: u64) -> OsString {
+    let mut suffixed = name.file_stem().unwrap_or_default().to_os_string();
+    suffixed.push(format!("-{}", suffix));
+    if let Some(extension) = name.extension() {
+        suffixed.push(".");
+        suffixed.push(extension);
+    }
+    suffixed
+}
@@ -1,24 +1,414 @@
-use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use nix::fcntl::OFlag;
+use nix::libc;
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd;
+use rayon::prelude::{
+    IntoParallelIterator, IntoParallelRefIterator, IntoParallelRefMutIterator, ParallelBridge,
+    ParallelIterator,
+};
 use std::array;
+use std::cell::RefCell;
+use std::cmp::Reverse;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::env;
-use std::ffi::OsStr;
-use std::fmt::Display;
-use std::io;
-use std::ops::BitOr;
-use std::os::unix::fs::{MetadataExt, PermissionsExt};
-use std::path::{Path, PathBuf};
-use std::process;
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, Metadata, ReadDir};
+use std::io::{self, prelude::*};
+use std::mem;
+use std::ops::{Add, BitOr};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
 
+pub mod config;
+mod dry_run;
 pub mod error;
 pub mod filesystem;
+mod flatten;
+pub mod log;
+pub mod options;
+pub mod progress;
+mod prompt;
+mod record;
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+mod uring;
 
-use crate::error::{Error, Result};
-use crate::filesystem::{self as fs, FileType};
+use crate::error::{Error, ErrorKind, Result};
+use crate::filesystem::{self as fs, CopyMechanism, FileType};
+use crate::log::Log;
+use crate::options::{
+    CopyError, CopyEstimate, CopyOptions, ErrorAction, Existing, Order, Skeleton, Sockets, Storage,
+};
+use crate::progress::{Progress, Reporter};
+use crate::prompt::Prompter;
 
-pub fn fatal(message: impl Display) -> ! {
-    eprintln!("{}", message);
-    process::exit(1);
+/// The number of threads used when copying between hard disk drives, which is kept low to avoid
+/// having the disks thrash between many files at once.
+const HDD_THREADS: usize = 2;
+
+/// The number of threads used per CPU when copying between NVMe devices, since each thread spends
+/// much of its time waiting on the device, which can serve many requests at once.
+const NVME_THREADS_PER_CPU: usize = 2;
+
+/// The number of entries of a directory beyond which the rest of it is copied in batches of this
+/// size as they're read (as with `--readdir-batch`), rather than reading it all before copying any
+/// of it. Smaller directories are faster to read in full, but listing ones with millions of entries
+/// would take a great deal of memory, and delay copying until it finished. With `--stable-order`,
+/// directories are always read in full.
+const STREAMING_THRESHOLD: usize = 65_536;
+
+/// The number of file descriptors left out of those `Descriptors` hands out, for the standard
+/// streams, log files, and anything else held open besides the files being copied.
+const RESERVED_DESCRIPTORS: usize = 64;
+
+/// The number of times an operation failing for want of file descriptors is retried, each once
+/// some have been released (or `DESCRIPTOR_RETRY_INTERVAL` has passed).
+const DESCRIPTOR_RETRIES: usize = 10;
+
+const DESCRIPTOR_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The most bytes of small files (and other entries besides directories) of a directory which are
+/// copied together by a single task, unless `--batch-bytes` is given.
+const BATCH_BYTES: u64 = 1024 * 1024;
+
+/// The most entries copied together by a single task, however small they are.
+const MAX_BATCH_ENTRIES: usize = 128;
+
+/// The stack size of the threads copying, which recurse once for every level of directories being
+/// copied. It's that of the main thread by default, rather than the smaller one of those spawned.
+const THREAD_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// The number of CPUs online, for each of which rayon starts a thread by default.
+fn cpus() -> Option<usize> {
+    // Safe because `sysconf` has no preconditions.
+    let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    usize::try_from(cpus).ok().filter(|&cpus| cpus > 0)
+}
+
+// The number of file descriptors the process may have open at once (`RLIMIT_NOFILE`).
+fn descriptor_limit() -> Option<usize> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // Safe because `limit` is valid for writes for the duration of the call.
+    match unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } {
+        0 if limit.rlim_cur == libc::RLIM_INFINITY => Some(usize::MAX),
+        0 => usize::try_from(limit.rlim_cur).ok(),
+        _ => None,
+    }
+}
+
+extern "C" fn record_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+/// Handle `SIGINT` by letting the copies already in progress finish but starting no new ones, so
+/// that `fcp` returns promptly without abandoning any files mid-copy. A second `SIGINT` terminates
+/// the process immediately, as usual.
+pub fn handle_interrupts() -> Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(record_interrupt),
+        SaFlags::SA_RESETHAND,
+        SigSet::empty(),
+    );
+    // Safe because the handler does nothing but store to an atomic, which is async-signal-safe.
+    unsafe { signal::sigaction(Signal::SIGINT, &action) }?;
+    Ok(())
+}
+
+/// Whether a `SIGINT` has been received since `handle_interrupts` was called.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Tracks the limits set by `--max-entries` and `--timeout`, beyond which no new copies are
+/// started.
+#[derive(Default)]
+struct Limits {
+    /// The number of entries whose copy has been started.
+    entries: AtomicUsize,
+    /// Set once either of the limits has been exceeded.
+    reached: Arc<AtomicBool>,
+    /// Set once an `ErrorHandler` has aborted the copy, which then likewise starts no new copies.
+    aborted: AtomicBool,
+}
+
+impl Limits {
+    /// Count another entry about to be copied, returning whether it's beyond either limit.
+    fn exceeded(&self, options: &CopyOptions) -> bool {
+        if self.reached.load(Ordering::Relaxed) || self.aborted.load(Ordering::Relaxed) {
+            return true;
+        }
+        let entries = self.entries.fetch_add(1, Ordering::Relaxed);
+        if options.max_entries.is_some_and(|max| entries >= max) {
+            self.reached.store(true, Ordering::Relaxed);
+            return true;
+        }
+        false
+    }
+
+    fn abort(&self) {
+        self.aborted.store(true, Ordering::Relaxed);
+    }
+
+    /// Have `reached` set once `timeout` elapses, unless the returned sender is dropped first.
+    fn start_timeout(&self, timeout: Duration) -> mpsc::Sender<()> {
+        let (sender, receiver) = mpsc::channel::<()>();
+        let reached = Arc::clone(&self.reached);
+        thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(timeout) {
+                reached.store(true, Ordering::Relaxed);
+            }
+        });
+        sender
+    }
+}
+
+/// The copies of regular files with more than one hard link, by the device and inode of their
+/// source, so that the rest of their links are recreated as links to the same copies, rather than
+/// each being copied anew. Nothing waits for a copy to be made: links found while it's being made
+/// are deferred to whoever is making it, so that no thread is kept from copying meanwhile.
+#[derive(Default)]
+struct Links {
+    copies: Mutex<HashMap<(u64, u64), Linked>>,
+}
+
+enum Linked {
+    /// Being copied, with the other links found meanwhile.
+    Copying(Vec<DeferredLink>),
+    /// Copied, to one path for each destination.
+    Copied(Vec<PathBuf>),
+}
+
+/// Another link to a file which was being copied when it was found, to be recreated once that
+/// copy is done.
+struct DeferredLink {
+    source: PathBuf,
+    dests: Vec<PathBuf>,
+    /// The index of the source given on the command line from within which it was found.
+    index: Option<usize>,
+}
+
+enum Claim {
+    /// The file is to be copied by the caller, who must call `finish` once done.
+    Copy,
+    /// The file has already been copied to these paths, which the caller is to link to.
+    Link(Vec<PathBuf>),
+    /// The file is being copied, and the caller's link will be recreated once it has been.
+    Deferred,
+}
+
+impl Links {
+    /// Claim the file identified by `inode`, found at `source` and to be copied to `dests` from
+    /// within the source at `index`.
+    fn claim(
+        &self,
+        inode: (u64, u64),
+        source: &Path,
+        dests: &[PathBuf],
+        index: Option<usize>,
+    ) -> Claim {
+        let mut copies = self.copies.lock().unwrap();
+        match copies.get_mut(&inode) {
+            Some(Linked::Copied(copies)) => Claim::Link(copies.clone()),
+            Some(Linked::Copying(deferred)) => {
+                deferred.push(DeferredLink {
+                    source: source.to_path_buf(),
+                    dests: dests.to_vec(),
+                    index,
+                });
+                Claim::Deferred
+            }
+            None => {
+                copies.insert(inode, Linked::Copying(Vec::new()));
+                Claim::Copy
+            }
+        }
+    }
+
+    /// Record the copies of the file identified by `inode` that was claimed, or, if it couldn't
+    /// be copied, leave the next to claim it to try, returning the links deferred meanwhile.
+    fn finish(&self, inode: (u64, u64), copies: Option<Vec<PathBuf>>) -> Vec<DeferredLink> {
+        let mut linked = self.copies.lock().unwrap();
+        let claimed = match copies {
+            Some(copies) => linked.insert(inode, Linked::Copied(copies)),
+            None => linked.remove(&inode),
+        };
+        match claimed {
+            Some(Linked::Copying(deferred)) => deferred,
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Bounds the number of file descriptors held open at once by the copies in progress to what
+/// `RLIMIT_NOFILE` allows, so that copying a deep or wide tree waits for descriptors to be released
+/// rather than failing with `EMFILE`.
+struct Descriptors {
+    /// The number of descriptors which may be held at once.
+    capacity: usize,
+    available: Mutex<usize>,
+    /// Notified whenever descriptors are released.
+    released: Condvar,
+}
+
+impl Descriptors {
+    fn new() -> Self {
+        let capacity = descriptor_limit()
+            .unwrap_or(1024)
+            .saturating_sub(RESERVED_DESCRIPTORS)
+            .max(2);
+        Descriptors {
+            capacity,
+            available: Mutex::new(capacity),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Take `count` descriptors (or all of them, if that's fewer), waiting for other copies to
+    /// release them if need be. They're released when the returned guard is dropped.
+    fn acquire(&self, count: usize) -> HeldDescriptors<'_> {
+        let count = count.min(self.capacity);
+        let mut available = self.available.lock().unwrap();
+        while *available < count {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= count;
+        HeldDescriptors {
+            descriptors: self,
+            count,
+        }
+    }
+
+    /// Retry `operation` while it fails with `EMFILE` or `ENFILE`, as it may when descriptors are
+    /// held by other processes or outside of this accounting, each time once some have been
+    /// released.
+    fn retry<T>(&self, mut operation: impl FnMut() -> Result<T>) -> Result<T> {
+        for _ in 0..DESCRIPTOR_RETRIES {
+            match operation() {
+                Err(err) if matches!(err.raw_os_error(), Some(libc::EMFILE | libc::ENFILE)) => {
+                    let available = self.available.lock().unwrap();
+                    let _ = self
+                        .released
+                        .wait_timeout(available, DESCRIPTOR_RETRY_INTERVAL)
+                        .unwrap();
+                }
+                result => return result,
+            }
+        }
+        operation()
+    }
+}
+
+/// File descriptors taken from `Descriptors`, which are released when this is dropped.
+struct HeldDescriptors<'a> {
+    descriptors: &'a Descriptors,
+    count: usize,
+}
+
+impl Drop for HeldDescriptors<'_> {
+    fn drop(&mut self) {
+        *self.descriptors.available.lock().unwrap() += self.count;
+        self.descriptors.released.notify_all();
+    }
+}
+
+/// The results of copying the contents of regular files together (with `--io-uring`), by the path
+/// of their source, each of which is taken when that file is copied, in place of copying its
+/// contents then.
+type Batched = Mutex<HashMap<PathBuf, io::Result<u64>>>;
+
+/// The state shared by all of the copies performed by a single invocation of `fcp`.
+struct Context<'a> {
+    options: &'a CopyOptions,
+    progress: Option<&'a Progress>,
+    log: Option<&'a Log>,
+    limits: &'a Limits,
+    links: &'a Links,
+    descriptors: &'a Descriptors,
+    /// Asks whether to overwrite each existing destination, with `--interactive`.
+    prompter: Option<&'a Prompter>,
+    /// The files of the directory being copied whose contents have already been copied.
+    batched: Option<&'a Batched>,
+    /// Whether to skip (with a warning) symlinks, FIFOs, and device files which the destination
+    /// doesn't permit creating. This is disabled when copying a single such file, in which case
+    /// skipping it would leave nothing copied at all.
+    skip_special_on_error: bool,
+    /// Whether any destination matches names case-insensitively, in which case entries whose
+    /// names differ only in case would be copied over one another.
+    case_insensitive: bool,
+    /// The index of the source given on the command line from within which files are being
+    /// copied, to which their progress is attributed.
+    source: Option<usize>,
+    /// The device of the directory whose entries are being copied, if any, against which those
+    /// entries are compared to find mount points with `--one-file-system`.
+    device: Option<u64>,
+}
+
+impl Context<'_> {
+    /// This context, for copying the source given on the command line at index `source`.
+    fn for_source(&self, source: usize) -> Self {
+        Context {
+            source: Some(source),
+            ..*self
+        }
+    }
+
+    /// This context, for copying the entries of a directory on the device `device`.
+    fn within(&self, device: u64) -> Self {
+        Context {
+            device: Some(device),
+            ..*self
+        }
+    }
+
+    /// Whether a directory on the device `device` is a mount point which `--one-file-system`
+    /// stops at. Sources given on the command line are always descended into.
+    fn is_mount_point(&self, device: u64) -> bool {
+        self.options.one_file_system && self.device.is_some_and(|within| within != device)
+    }
+}
+
+/// The metadata of a source, fetched when first needed and then reused, so that however many
+/// features need it, each source is statted at most once. It isn't fetched up front, as the type
+/// of each entry of a directory can usually be told from its listing, and copying a file often
+/// needs nothing more.
+struct SourceMetadata<'a> {
+    path: &'a Path,
+    metadata: RefCell<Option<Metadata>>,
+}
+
+impl<'a> SourceMetadata<'a> {
+    fn new(path: &'a Path) -> Self {
+        SourceMetadata::with(path, None)
+    }
+
+    /// Reuse `metadata` should it have been fetched already (e.g. to filter the source).
+    fn with(path: &'a Path, metadata: Option<Metadata>) -> Self {
+        SourceMetadata {
+            path,
+            metadata: RefCell::new(metadata),
+        }
+    }
+
+    fn get(&self) -> Result<Metadata> {
+        let mut metadata = self.metadata.borrow_mut();
+        if let Some(metadata) = &*metadata {
+            return Ok(metadata.clone());
+        }
+        let fetched = fs::symlink_metadata(self.path)?;
+        *metadata = Some(fetched.clone());
+        Ok(fetched)
+    }
 }
 
 // The boolean returned signifies whether an error occurred (`true`) or not (`false`). The purpose
@@ -27,56 +417,1094 @@ pub fn fatal(message: impl Display) -> ! {
 // long-running jobs) as opposed to propagating it upwards and printing all errors at the end.
 // However, at the end of the process we still need to know whether or not an error occurred at any
 // point in order to set the exit code appropriately.
-fn copy_file(source: &Path, source_type: Result<FileType>, dest: &Path) -> bool {
-    fn __copy_file(source: &Path, source_type: Result<FileType>, dest: &Path) -> Result<bool> {
-        match source_type? {
+fn copy_file(source: &Path, source_type: Result<FileType>, dest: &Path, context: &Context) -> bool {
+    copy_file_with_metadata(source, source_type, None, dest, context)
+}
+
+// Like `copy_file`, but reusing the metadata of `source` should it already have been fetched.
+fn copy_file_with_metadata(
+    source: &Path,
+    source_type: Result<FileType>,
+    metadata: Option<Metadata>,
+    dest: &Path,
+    context: &Context,
+) -> bool {
+    // When interrupted, the files not yet copied are silently skipped, as the user already knows
+    // why they're missing.
+    if interrupted() {
+        log_skipped(source, "interrupted", context);
+        return true;
+    }
+    // Likewise once the copy is cancelled or a limit is reached, which is reported once the copy
+    // finishes.
+    if context.options.is_cancelled() {
+        log_skipped(source, "cancelled", context);
+        return true;
+    }
+    if context.limits.exceeded(context.options) {
+        log_skipped(source, "limit reached", context);
+        return true;
+    }
+    copy_file_unchecked(source, source_type, metadata, dest, context)
+}
+
+// Copy `source` once it's been checked that copying is to go on, and `source` counted against the
+// limits on it.
+fn copy_file_unchecked(
+    source: &Path,
+    source_type: Result<FileType>,
+    metadata: Option<Metadata>,
+    dest: &Path,
+    context: &Context,
+) -> bool {
+    fn __copy_file(
+        source: &Path,
+        source_type: Result<FileType>,
+        metadata: Option<Metadata>,
+        dest: &Path,
+        context: &Context,
+    ) -> Result<bool> {
+        let source_type = source_type?;
+        if context.options.no_recursive && matches!(source_type, FileType::Directory) {
+            return Err(omit_directory(source));
+        }
+        let metadata = SourceMetadata::with(source, metadata);
+        if let Some(threshold) = context.options.newer_than {
+            let is_directory = matches!(source_type, FileType::Directory);
+            if !is_directory && metadata.get()?.modified()? <= threshold {
+                log_skipped(source, "not newer than --newer-than", context);
+                return Ok(false);
+            }
+        }
+        let (min_size, max_size) = (context.options.min_size, context.options.max_size);
+        if matches!(source_type, FileType::Regular) && (min_size.is_some() || max_size.is_some()) {
+            let size = metadata.get()?.len();
+            if min_size.is_some_and(|min| size < min) || max_size.is_some_and(|max| size > max) {
+                if let Some(progress) = context.progress {
+                    progress.record_skipped_for_size();
+                }
+                log_skipped(source, "outside --min-size and --max-size", context);
+                return Ok(false);
+            }
+        }
+        if context.options.attrs_only {
+            let has_err = match source_type {
+                FileType::Directory => copy_directory(source, &metadata, dest, context)?,
+                _ => false,
+            };
+            // A directory's attributes are applied after its contents, as otherwise its times
+            // wouldn't reflect the source's if those contents had changed it.
+            fs::copy_attributes(source, dest)?;
+            if context.options.preserve_capabilities && matches!(source_type, FileType::Regular) {
+                copy_capabilities(source, dest)?;
+            }
+            if matches!(source_type, FileType::Directory) {
+                copy_overlay_opaque(source, dest, context)?;
+            }
+            copy_security_context(source, dest, context)?;
+            copy_flags(&metadata, dest, context)?;
+            log_copied(source, context);
+            return Ok(has_err);
+        }
+        match (context.options.skeleton, source_type) {
+            (None, _) | (Some(_), FileType::Directory) => {}
+            (Some(Skeleton::DirsOnly), _) => {
+                log_skipped(source, "not a directory, with --dirs-only", context);
+                return Ok(false);
+            }
+            (
+                Some(Skeleton::EmptyFiles),
+                FileType::Regular | FileType::CharacterDevice | FileType::BlockDevice,
+            ) => {
+                let permissions = metadata.get()?.permissions();
+                let file = create_dest(dest, permissions.mode(), context)?;
+                // As in `fs::copy`, the mode given when creating the file is subject to the umask.
+                file.set_permissions(permissions).map_err(|err| {
+                    let message = format!("{}: {}", dest.display(), err);
+                    Error::with_os_error(message, "set_permissions", &err)
+                })?;
+                copy_security_context(source, dest, context)?;
+                if let Some(progress) = context.progress {
+                    progress.record(context.source, 0);
+                }
+                log_copied(source, context);
+                return Ok(false);
+            }
+            (Some(Skeleton::EmptyFiles), _) => {}
+        }
+        let mut linked_with_err = false;
+        let bytes = match source_type {
             FileType::Regular => {
-                fs::copy(source, dest)?;
-            }
-            FileType::Directory => return copy_directory(source, dest),
-            FileType::Symlink => fs::symlink(fs::read_link(source)?, dest)?,
-            FileType::Fifo => fs::mkfifo(dest, fs::symlink_metadata(source)?.permissions())?,
-            FileType::Socket => {
-                return Err(Error::new(format!(
-                    "{}: sockets cannot be copied",
-                    source.display(),
-                )));
+                let inode = {
+                    let metadata = metadata.get()?;
+                    Some((metadata.dev(), metadata.ino())).filter(|_| metadata.nlink() > 1)
+                };
+                // Any other link to a file already copied is recreated, rather than its contents
+                // being copied again. Should that fail (e.g. because the copy has since been
+                // removed), it's copied after all.
+                let dests = [dest.to_path_buf()];
+                let mut claimed = None;
+                if let Some(inode) = inode {
+                    match context.links.claim(inode, source, &dests, context.source) {
+                        Claim::Link(copies) if link_to_copies(source, &copies, &dests, context) => {
+                            return Ok(false);
+                        }
+                        Claim::Link(_) => {}
+                        Claim::Deferred => return Ok(false),
+                        Claim::Copy => claimed = Some(inode),
+                    }
+                }
+                let batched = context
+                    .batched
+                    .and_then(|batched| batched.lock().unwrap().remove(source));
+                let copied = match (batched, &context.options.staging_dir) {
+                    (Some(batched), _) => batched
+                        .map(|bytes| fs::Copied {
+                            bytes,
+                            mechanism: CopyMechanism::IoUring,
+                            resized: None,
+                        })
+                        .map_err(|err| {
+                            let message =
+                                format!("{}, {}: {}", source.display(), dest.display(), err);
+                            Error::with_os_error(message, "copy", &err)
+                        }),
+                    (None, staging_dir) => {
+                        let _held = context.descriptors.acquire(2);
+                        context.descriptors.retry(|| match staging_dir {
+                            Some(staging_dir) => copy_staged(source, dest, staging_dir, context),
+                            None => fs::copy(
+                                source,
+                                dest,
+                                dest_flags(context),
+                                context.options.content(source),
+                            ),
+                        })
+                    }
+                };
+                if let Some(inode) = claimed {
+                    let copies = copied.as_ref().ok().map(|_| dests.to_vec());
+                    linked_with_err = finish_links(inode, copies, context);
+                }
+                let copied = copied?;
+                if context.options.explain && !context.options.summary_only {
+                    println!("copied '{}' via {}", source.display(), copied.mechanism);
+                }
+                if let Some((len, final_len)) = copied.resized {
+                    eprintln!(
+                        "Warning: '{}' changed size from {} to {} bytes while being copied, so \
+                         {} bytes of it were copied",
+                        source.display(),
+                        len,
+                        final_len,
+                        copied.bytes
+                    );
+                }
+                if let (
+                    Some(progress),
+                    CopyMechanism::Sparse { holes } | CopyMechanism::Extents { holes },
+                ) = (context.progress, copied.mechanism)
+                {
+                    progress.record_holes(holes);
+                }
+                // Changing the ownership of a file also clears its capabilities, and the time
+                // given by `--mtime-from` takes precedence over the source's.
+                preserve_attributes(&metadata, dest, context)?;
+                copy_acls(source, dest, context)?;
+                if context.options.preserve_capabilities {
+                    copy_capabilities(source, dest)?;
+                }
+                set_mtime_from_name(source, dest, context)?;
+                copied.bytes
+            }
+            FileType::Directory => {
+                let has_err = copy_directory(source, &metadata, dest, context)?;
+                // As with `--attrs-only`, this comes after the directory's contents are copied.
+                preserve_attributes(&metadata, dest, context)?;
+                copy_acls(source, dest, context)?;
+                fs::copy_finder_metadata(source, dest)?;
+                copy_overlay_opaque(source, dest, context)?;
+                copy_security_context(source, dest, context)?;
+                copy_flags(&metadata, dest, context)?;
+                log_copied(source, context);
+                return Ok(has_err);
+            }
+            FileType::Symlink => {
+                let created = fs::symlink(fs::read_link(source)?, dest);
+                match skip_unsupported(source, created, context)? {
+                    Some(()) => 0,
+                    None => return Ok(false),
+                }
+            }
+            FileType::Fifo => {
+                let created = fs::mkfifo(dest, metadata.get()?.permissions());
+                match skip_unsupported(source, created, context)? {
+                    Some(()) => 0,
+                    None => return Ok(false),
+                }
             }
+            FileType::Socket => match context.options.sockets {
+                None => {
+                    return Err(Error::new(format!(
+                        "{}: sockets cannot be copied",
+                        source.display(),
+                    )));
+                }
+                Some(Sockets::Skip) => {
+                    eprintln!("Warning: skipping socket '{}'", source.display());
+                    log_skipped(source, "socket, with --sockets=skip", context);
+                    return Ok(false);
+                }
+                Some(Sockets::Recreate) => {
+                    let created = fs::mksock(dest, metadata.get()?.permissions());
+                    match skip_unsupported(source, created, context)? {
+                        Some(()) => 0,
+                        None => return Ok(false),
+                    }
+                }
+            },
             FileType::CharacterDevice | FileType::BlockDevice => {
-                let metadata = fs::symlink_metadata(source)?;
-                let mut source = fs::open(source)?;
-                let mut dest = fs::create(dest, metadata.permissions().mode())?;
-                io::copy(&mut source, &mut dest)?;
+                let metadata = metadata.get()?;
+                // A whiteout can't be opened, let alone read, so with `--preserve=overlay` it's
+                // recreated instead.
+                if context.options.preserve_overlay && fs::is_whiteout(&metadata) {
+                    let created = fs::mkwhiteout(dest, metadata.permissions());
+                    match skip_unsupported(source, created, context)? {
+                        Some(()) => 0,
+                        None => return Ok(false),
+                    }
+                } else {
+                    // The contents are copied byte for byte. Any translation (e.g. of carriage
+                    // returns read from a terminal) is done by the device's driver beforehand.
+                    let mut source_file = fs::open(source)?;
+                    let created = create_dest(dest, metadata.permissions().mode(), context);
+                    match skip_unsupported(source, created, context)? {
+                        Some(mut dest_file) => io::copy(&mut source_file, &mut dest_file)?,
+                        None => return Ok(false),
+                    }
+                }
             }
+        };
+        if !matches!(source_type, FileType::Regular) {
+            preserve_attributes(&metadata, dest, context)?;
         }
-        Ok(false)
+        // Symlinks have no ACLs of their own, but would have those of their targets read.
+        if !matches!(source_type, FileType::Regular | FileType::Symlink) {
+            copy_acls(source, dest, context)?;
+        }
+        if !matches!(source_type, FileType::Regular) {
+            fs::copy_finder_metadata(source, dest)?;
+        }
+        copy_security_context(source, dest, context)?;
+        copy_flags(&metadata, dest, context)?;
+        if let Some(progress) = context.progress {
+            progress.record(context.source, bytes);
+        }
+        log_copied(source, context);
+        Ok(linked_with_err)
     }
 
-    __copy_file(source, source_type, dest).unwrap_or_else(|err| {
-        eprintln!("{}", err);
-        true
+    let (mut source_type, mut metadata, mut attempt) = (source_type, metadata, 1);
+    loop {
+        let file_type = source_type.as_ref().ok().copied();
+        // Should the copy be retried, the source is statted afresh, as it may have changed.
+        let err = match __copy_file(source, source_type, metadata.take(), dest, context) {
+            Ok(has_err) => return has_err,
+            Err(err) => fs::name_non_directory(dest, err),
+        };
+        // A regular file whose copy was cancelled partway is removed rather than left incomplete.
+        // One being staged was copied to a temporary file, which has already been removed.
+        if context.options.is_cancelled() && err.raw_os_error() == Some(libc::ECANCELED) {
+            if context.options.staging_dir.is_none() {
+                let _ = std::fs::remove_file(dest);
+            }
+            log_skipped(source, "cancelled", context);
+            return true;
+        }
+        // Destinations are created exclusively with `--no-clobber`, `--ignore-existing`, and
+        // `--interactive` (see `dest_flags`), so that rather than checking whether each one exists
+        // beforehand (leaving a window in which it could be created and then overwritten), failing
+        // to create it is what tells us to skip it.
+        if context.options.existing.is_some() && err.raw_os_error() == Some(libc::EEXIST) {
+            // With `--interactive`, an existing file the user agrees to overwrite is removed, and
+            // the copy tried again. Directories are copied into rather than overwritten.
+            let overwrite = context
+                .prompter
+                .is_some_and(|prompter| !dest.is_dir() && prompter.overwrite(dest));
+            if overwrite {
+                if let Err(err) = std::fs::remove_file(dest) {
+                    let message = format!("{}: {}", dest.display(), err);
+                    let err = Error::with_os_error(message, "remove_file", &err);
+                    report_error(source, file_type, &err, context);
+                    return true;
+                }
+                source_type = file_type.map_or_else(|| fs::file_type(source), Ok);
+                continue;
+            }
+            if context.options.explain && !context.options.summary_only {
+                println!(
+                    "skipped '{}', as '{}' already exists",
+                    source.display(),
+                    dest.display()
+                );
+            }
+            if let Some(progress) = context.progress {
+                progress.record_skipped_existing();
+            }
+            log_skipped(source, "already exists", context);
+            return false;
+        }
+        // Errors matching `--ignore-errors-matching` are expected, so they're only warned about.
+        if context.options.is_ignored(source, &err) {
+            eprintln!("Warning: ignoring error: {}", err);
+            log_skipped(source, &format!("ignored error: {}", err), context);
+            return false;
+        }
+        let action = context.options.on_error.as_ref().map(|handler| {
+            handler.decide(&CopyError {
+                source,
+                error: &err,
+                attempt,
+            })
+        });
+        match action {
+            Some(ErrorAction::Retry) => {
+                attempt += 1;
+                source_type = file_type.map_or_else(|| fs::file_type(source), Ok);
+                continue;
+            }
+            Some(ErrorAction::Abort) => context.limits.abort(),
+            Some(ErrorAction::Continue) | None => {}
+        }
+        report_error(source, file_type, &err, context);
+        return true;
+    }
+}
+
+// Recreate `source`, another link to a file already copied to `copies`, as links to those copies
+// at `dests`, returning whether that succeeded. Should any of them fail (e.g. because its copy has
+// since been removed), those already made are removed again, so that `source` can be copied after
+// all.
+fn link_to_copies(source: &Path, copies: &[PathBuf], dests: &[PathBuf], context: &Context) -> bool {
+    for (linked, (copy, dest)) in copies.iter().zip(dests).enumerate() {
+        if fs::hard_link(copy, dest).is_err() {
+            for dest in &dests[..linked] {
+                let _ = std::fs::remove_file(dest);
+            }
+            return false;
+        }
+    }
+    if context.options.explain && !context.options.summary_only {
+        for copy in copies {
+            println!("linked '{}' to '{}'", source.display(), copy.display());
+        }
+    }
+    if let Some(progress) = context.progress {
+        progress.record(context.source, 0);
+    }
+    log_copied(source, context);
+    true
+}
+
+// Finish the claim on the file identified by `inode`, which has been copied to `copies` (or
+// couldn't be, if `None`), then recreate the links to it deferred meanwhile, returning whether an
+// error occurred. Those which can't be linked are copied after all, as each would have been had
+// it been claimed first.
+fn finish_links(inode: (u64, u64), copies: Option<Vec<PathBuf>>, context: &Context) -> bool {
+    context
+        .links
+        .finish(inode, copies)
+        .into_iter()
+        .map(|deferred| {
+            let context = &Context {
+                source: deferred.index,
+                batched: None,
+                ..*context
+            };
+            // Each was counted against the limits when it was found, so isn't counted again.
+            match deferred.dests.as_slice() {
+                [dest] => copy_file_unchecked(
+                    &deferred.source,
+                    Ok(FileType::Regular),
+                    None,
+                    dest,
+                    context,
+                ),
+                dests => tee_regular(&deferred.source, dests, context),
+            }
+        })
+        .fold(false, BitOr::bitor)
+}
+
+/// The flags with which to open destination files, which include `O_EXCL` when existing
+/// destinations are to be skipped rather than overwritten.
+fn dest_flags(context: &Context) -> OFlag {
+    match context.options.existing {
+        Some(_) => context.options.dest_flags() | OFlag::O_EXCL,
+        None => context.options.dest_flags(),
+    }
+}
+
+fn create_dest(dest: &Path, mode: u32, context: &Context) -> Result<File> {
+    match context.options.existing {
+        Some(_) => fs::create_new(dest, mode, context.options.dest_flags()),
+        None => fs::create_truncate(dest, mode, context.options.dest_flags()),
+    }
+}
+
+// With `--mtime-from`, a regular file whose name encodes a time is given that time as its
+// modification time, while others keep the one they were copied with.
+fn set_mtime_from_name(source: &Path, dest: &Path, context: &Context) -> Result<()> {
+    let format = match &context.options.mtime_from {
+        Some(format) => format,
+        None => return Ok(()),
+    };
+    let name = source.file_name().unwrap_or_default().to_string_lossy();
+    match options::time_from_name(format, &name) {
+        Some(time) => fs::set_mtime(dest, time),
+        None => Ok(()),
+    }
+}
+
+// With `--no-recursive`, directories are refused with the same message as `cp` gives for them.
+fn omit_directory(source: &Path) -> Error {
+    Error::new(format!(
+        "-R not specified; omitting directory '{}'",
+        source.display()
+    ))
+}
+
+fn report_error(source: &Path, file_type: Option<FileType>, err: &Error, context: &Context) {
+    eprintln!("{}", err);
+    if let Some(progress) = context.progress {
+        progress.record_error(source, file_type, err);
+    }
+    if let Some(log) = context.log {
+        log.failed(source, err);
+    }
+}
+
+fn log_copied(source: &Path, context: &Context) {
+    if let Some(log) = context.log {
+        log.copied(source);
+    }
+}
+
+fn log_skipped(source: &Path, reason: &str, context: &Context) {
+    if let Some(log) = context.log {
+        log.skipped(source, reason);
+    }
+}
+
+// Some filesystems (e.g. FAT32) and containers don't permit creating symlinks, FIFOs, or device
+// files. With `--skip-special-on-error`, failing to create one of these for `source` is reported
+// as a warning and the file is skipped (`None`), rather than failing the copy.
+fn skip_unsupported<T>(source: &Path, created: Result<T>, context: &Context) -> Result<Option<T>> {
+    match created {
+        Err(err) if context.skip_special_on_error && is_unsupported(&err) => {
+            eprintln!("Warning: skipping '{}': {}", source.display(), err);
+            log_skipped(source, "not supported by the destination", context);
+            Ok(None)
+        }
+        created => created.map(Some),
+    }
+}
+
+fn is_unsupported(err: &Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EPERM) | Some(libc::EACCES) | Some(libc::EOPNOTSUPP)
+    )
+}
+
+// With `--context`, every copy is given the same SELinux security context, and with
+// `--preserve=context` each is given its source's. Otherwise copies keep whatever the policy gave
+// them when they were created.
+fn copy_security_context(source: &Path, dest: &Path, context: &Context) -> Result<()> {
+    let security_context = match &context.options.security_context {
+        Some(security_context) => Some(security_context.clone()),
+        None if context.options.preserve_security_context => fs::security_context(source)?,
+        None => None,
+    };
+    match security_context {
+        Some(security_context) => fs::set_security_context(dest, &security_context),
+        None => Ok(()),
+    }
+}
+
+// With `--preserve` (or `-p`), give `dest` the permissions, times, ownership, and extended
+// attributes of the source whose `metadata` is given, as requested. Ownership comes first, as
+// changing it clears the setuid and setgid bits, and the times last, so that nothing else changes
+// them afterwards.
+fn preserve_attributes(metadata: &SourceMetadata, dest: &Path, context: &Context) -> Result<()> {
+    let options = context.options;
+    if !(options.preserve_mode
+        || options.preserve_timestamps
+        || options.preserve_ownership
+        || options.preserve_xattr)
+    {
+        return Ok(());
+    }
+    let source = metadata.path;
+    let metadata = metadata.get()?;
+    if options.preserve_ownership {
+        let privileged = unistd::geteuid().is_root();
+        match fs::copy_ownership(&metadata, dest) {
+            Err(err) if err.raw_os_error() == Some(libc::EPERM) && !privileged => {}
+            result => result?,
+        }
+    }
+    if options.preserve_mode && !metadata.file_type().is_symlink() {
+        fs::set_mode(dest, metadata.mode() & 0o7777)?;
+    }
+    if options.preserve_xattr {
+        fs::set_xattrs(dest, &fs::xattrs(source)?)?;
+    }
+    if options.preserve_timestamps {
+        fs::copy_times(&metadata, dest)?;
+    }
+    Ok(())
+}
+
+// With `--preserve=flags`, BSD file flags are copied last of all, as once a copy is immutable,
+// nothing else about it can be changed.
+fn copy_flags(metadata: &SourceMetadata, dest: &Path, context: &Context) -> Result<()> {
+    if !context.options.preserve_flags {
+        return Ok(());
+    }
+    match fs::flags(&metadata.get()?) {
+        0 => Ok(()),
+        flags => fs::set_flags(dest, flags),
+    }
+}
+
+// POSIX ACLs are copied unless `--no-acls` is given, since permissions granted or denied by them
+// would otherwise silently change.
+fn copy_acls(source: &Path, dest: &Path, context: &Context) -> Result<()> {
+    if context.options.no_acls {
+        return Ok(());
+    }
+    let acls = fs::acls(source)?;
+    if acls.is_empty() {
+        return Ok(());
+    }
+    fs::set_acls(dest, &acls).map_err(|err| match err.raw_os_error() {
+        Some(libc::EOPNOTSUPP) => {
+            let message = format!(
+                "{}: ACLs of '{}' couldn't be copied, as the filesystem doesn't support them \
+                 (use --no-acls to copy without them)",
+                dest.display(),
+                source.display()
+            );
+            err.reworded(message)
+        }
+        _ => err,
     })
 }
 
-fn copy_directory(source: &Path, dest: &Path) -> Result<bool> {
-    fs::create_dir(dest, fs::symlink_metadata(source)?.permissions().mode())?;
-    let (mut entries, mut has_err) = (Vec::new(), false);
-    for entry in fs::read_dir(source)? {
-        match entry {
-            Ok(entry) => entries.push((entry.file_name(), fs::entry_file_type(&entry))),
-            Err(err) => {
-                eprintln!("{}", err);
-                has_err = true;
+// File capabilities are stored in an extended attribute which only root can set, and which is
+// cleared whenever the file is written to, so this must happen after its contents are copied.
+fn copy_capabilities(source: &Path, dest: &Path) -> Result<()> {
+    if let Some(capabilities) = fs::capabilities(source)? {
+        if unistd::geteuid().is_root() {
+            fs::set_capabilities(dest, &capabilities)?;
+        } else {
+            eprintln!(
+                "Warning: '{}': file capabilities were not copied, as this requires running as root",
+                source.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+// With `--preserve=overlay`, a directory marked as opaque in the overlayfs layer being copied is
+// marked as such in the copy too.
+fn copy_overlay_opaque(source: &Path, dest: &Path, context: &Context) -> Result<()> {
+    if !context.options.preserve_overlay {
+        return Ok(());
+    }
+    match fs::overlay_opaque(source)? {
+        Some(value) => fs::set_overlay_opaque(dest, &value),
+        None => Ok(()),
+    }
+}
+
+/// Copy the regular file `source` into `staging_dir`, and only once that's complete move it to
+/// `dest`. The staged copy is removed if anything goes wrong.
+fn copy_staged(
+    source: &Path,
+    dest: &Path,
+    staging_dir: &Path,
+    context: &Context,
+) -> Result<fs::Copied> {
+    // The staged copy is renamed over `dest`, which can't be done exclusively, so this is the one
+    // place where an existing destination has to be checked for up front.
+    if context.options.existing.is_some() && fs::symlink_metadata(dest).is_ok() {
+        let err = io::Error::from_raw_os_error(libc::EEXIST);
+        let message = format!("{}: {}", dest.display(), err);
+        return Err(Error::with_os_error(message, "rename", &err));
+    }
+    let staged = fs::temporary_path(staging_dir, dest.file_name().unwrap_or_default());
+    let flags = context.options.dest_flags() | OFlag::O_EXCL;
+    let result = fs::copy(source, &staged, flags, context.options.content(source))
+        .and_then(|copied| fs::move_file(&staged, dest, context.options.fsync).map(|_| copied));
+    if result.is_err() {
+        // The staged copy may never have been created, or may already have been moved, so
+        // failing to remove it is of no concern.
+        let _ = fs::remove_file(&staged);
+    }
+    result
+}
+
+/// An entry of a directory being copied: its name, its type, and its metadata should that already
+/// have been fetched (e.g. to filter it), so that it isn't fetched again to copy it.
+type Entry = (OsString, Result<FileType>, Option<Metadata>);
+
+fn copy_directory(
+    source: &Path,
+    metadata: &SourceMetadata,
+    dest: &Path,
+    context: &Context,
+) -> Result<bool> {
+    // With `--no-clobber` or `--interactive`, copying into a directory which already exists fills
+    // in whatever it's missing. Such a directory is never pruned, as it isn't ours to remove.
+    let (mode, device) = {
+        let metadata = metadata.get()?;
+        (metadata.permissions().mode(), metadata.dev())
+    };
+    let created = if context.options.attrs_only {
+        false
+    } else {
+        match fs::create_dir(dest, mode) {
+            Err(_)
+                if matches!(
+                    context.options.existing,
+                    Some(Existing::NoClobber | Existing::Prompt)
+                ) && dest.is_dir() =>
+            {
+                false
             }
+            result => result.map(|_| true)?,
         }
+    };
+    // A mount point is copied as an empty directory with its attributes (those of the root of the
+    // filesystem mounted there, as the directory beneath can't be seen while it's mounted over),
+    // so that the destination still has somewhere to mount it. With `--attrs-only`, the caller
+    // copies them anyway.
+    if context.is_mount_point(device) {
+        if !context.options.attrs_only {
+            fs::copy_attributes(source, dest)?;
+        }
+        return Ok(false);
+    }
+    let context = &context.within(device);
+    let held = context.descriptors.acquire(1);
+    let mut entries = context.descriptors.retry(|| fs::read_dir(source))?;
+    let (mut batch, mut has_err) = (Vec::new(), false);
+    let batch_size = match context.options.readdir_batch {
+        Some(batch_size) => Some(batch_size),
+        None => {
+            for entry in entries.by_ref() {
+                match entry {
+                    Ok(entry) => batch.push((entry.file_name(), fs::entry_file_type(&entry), None)),
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        has_err = true;
+                    }
+                }
+                // A stable order requires the whole listing, to be sorted.
+                if batch.len() == STREAMING_THRESHOLD && !context.options.stable_order {
+                    break;
+                }
+            }
+            // Only if the threshold was reached can there be more entries left to read.
+            if batch.len() == STREAMING_THRESHOLD && !context.options.stable_order {
+                Some(STREAMING_THRESHOLD)
+            } else {
+                None
+            }
+        }
+    };
+    if let Some(batch_size) = batch_size {
+        let has_err =
+            copy_entries_batched(source, dest, batch, entries, batch_size, context) | has_err;
+        sync_directory(dest, context)?;
+        if created {
+            prune_if_empty(dest, false, context)?;
+        }
+        return Ok(has_err);
     }
+    // The listing is complete, so its descriptor is released before the entries are copied, as
+    // otherwise one would be held for every level of the tree being copied.
+    drop(entries);
+    drop(held);
+    let batch = filter_entries(source, batch, context);
+    let empty = batch.is_empty();
+    let can_be_empty = batch
+        .iter()
+        .all(|(_, file_type, _)| matches!(file_type, Ok(FileType::Directory)));
+    let has_err = copy_entries(source, dest, batch, context) | has_err;
+    sync_directory(dest, context)?;
+    // A directory to which any file was to be copied is only empty if that copy failed, in which
+    // case it's left be.
+    if created && can_be_empty {
+        prune_if_empty(dest, empty, context)?;
+    }
+    Ok(has_err)
+}
+
+// With `--fsync`, a directory is synced once all of its entries have been copied, which makes
+// their names durable (syncing each file only persists its contents). Any which couldn't be copied
+// have already been reported, so the directory is synced regardless.
+fn sync_directory(dest: &Path, context: &Context) -> Result<()> {
+    if context.options.fsync && !context.options.attrs_only {
+        fs::sync_dir(dest)?;
+    }
+    Ok(())
+}
+
+// Drop the entries of a directory which won't be copied at all, so that whether the directory will
+// be left empty can be told from its listing, without having to read the destination afterwards.
+// Failures to determine an entry's type are kept so that copying reports them.
+fn filter_entries(source: &Path, entries: Vec<Entry>, context: &Context) -> Vec<Entry> {
+    let threshold = match context.options.newer_than {
+        Some(threshold) => threshold,
+        None => return entries,
+    };
+    // The metadata fetched here is kept with each entry, so that copying it needn't stat it again.
+    let mut entries = entries
+        .into_iter()
+        .filter_map(|(file_name, file_type, metadata)| match file_type {
+            Ok(FileType::Directory) | Err(_) => Some((file_name, file_type, metadata)),
+            Ok(_) => {
+                let path = source.join(&file_name);
+                let metadata = fs::symlink_metadata(&path).ok();
+                let newer = match metadata.as_ref().map(Metadata::modified) {
+                    Some(Ok(modified)) => modified > threshold,
+                    _ => true,
+                };
+                if !newer {
+                    log_skipped(&path, "not newer than --newer-than", context);
+                    return None;
+                }
+                Some((file_name, file_type, metadata))
+            }
+        })
+        .collect::<Vec<_>>();
     entries.shrink_to_fit();
-    Ok(entries
+    entries
+}
+
+// When copying only a subset of files, directories which end up containing none of them are
+// removed rather than being left empty. The destination is only read to check this if it may have
+// been left non-empty (i.e. it's not known to be `empty` already).
+fn prune_if_empty(dest: &Path, empty: bool, context: &Context) -> Result<()> {
+    if context.options.newer_than.is_some() && (empty || fs::read_dir(dest)?.next().is_none()) {
+        fs::remove_dir(dest)?;
+    }
+    Ok(())
+}
+
+// Rather than buffering the entire listing of `source` before copying any of it, dispatch its
+// entries in batches of `batch_size` as they're read. This bounds the memory used for
+// directories with enormous numbers of entries, and lets copying start before the listing is
+// complete. `first` is a batch of entries which have already been read.
+fn copy_entries_batched(
+    source: &Path,
+    dest: &Path,
+    first: Vec<Entry>,
+    entries: ReadDir,
+    batch_size: usize,
+    context: &Context,
+) -> bool {
+    let has_err = AtomicBool::new(false);
+    rayon::scope(|scope| {
+        let has_err = &has_err;
+        if !first.is_empty() {
+            scope.spawn(move |_| {
+                if copy_entries(source, dest, first, context) {
+                    has_err.store(true, Ordering::Relaxed);
+                }
+            });
+        }
+        let mut batch = Vec::with_capacity(batch_size);
+        for entry in entries {
+            match entry {
+                Ok(entry) => batch.push((entry.file_name(), fs::entry_file_type(&entry), None)),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    has_err.store(true, Ordering::Relaxed);
+                }
+            }
+            if batch.len() == batch_size {
+                let batch = mem::replace(&mut batch, Vec::with_capacity(batch_size));
+                scope.spawn(move |_| {
+                    if copy_entries(source, dest, batch, context) {
+                        has_err.store(true, Ordering::Relaxed);
+                    }
+                });
+            }
+        }
+        if copy_entries(source, dest, batch, context) {
+            has_err.store(true, Ordering::Relaxed);
+        }
+    });
+    has_err.into_inner()
+}
+
+fn copy_entries(source: &Path, dest: &Path, entries: Vec<Entry>, context: &Context) -> bool {
+    let (entries, has_err) = check_case_collisions(source, entries, context);
+    let batched = copy_small_files(source, dest, &entries, context);
+    let context = &Context {
+        batched: batched.as_ref(),
+        ..*context
+    };
+    let copy = |(file_name, file_type, metadata): Entry| {
+        copy_file_with_metadata(
+            &source.join(&file_name),
+            file_type,
+            metadata,
+            &dest_path(&source.join(&file_name), &file_name, dest, context),
+            context,
+        )
+    };
+    let order = match context.options.order {
+        None if context.options.stable_order => Some(Order::Name),
+        order => order,
+    };
+    let copied_with_err = match order {
+        None => {
+            let (batches, entries) = batch_entries(source, entries, context);
+            copy_batches(batches, &copy)
+                | entries
+                    .into_par_iter()
+                    .map(copy)
+                    .reduce(|| false, BitOr::bitor)
+        }
+        // Rather than splitting the entries between threads up front, bridging hands them out one
+        // at a time as threads become free, and so dispatches them in order.
+        Some(order) => sort_entries(source, entries, order)
+            .into_iter()
+            .par_bridge()
+            .map(copy)
+            .reduce(|| false, BitOr::bitor),
+    };
+    copied_with_err | has_err
+}
+
+// Copying a small file takes little enough time that dispatching it as a task of its own would take
+// a good share of it, so runs of small files (and other entries but directories) are grouped into
+// batches each copied by a single task, of up to `--batch-bytes` (or `BATCH_BYTES`) and
+// `MAX_BATCH_ENTRIES` between them. Everything else is returned apart, to be copied on its own.
+// Only unordered copies are batched, as a batch's entries aren't started until those before them in
+// it are finished.
+fn batch_entries(
+    source: &Path,
+    entries: Vec<Entry>,
+    context: &Context,
+) -> (Vec<Vec<Entry>>, Vec<Entry>) {
+    let limit = context.options.batch_bytes.unwrap_or(BATCH_BYTES);
+    if limit == 0 {
+        return (Vec::new(), entries);
+    }
+    let (mut batches, mut batch, mut bytes, mut rest) = (Vec::new(), Vec::new(), 0, Vec::new());
+    for mut entry in entries {
+        let size = match &entry.1 {
+            // The metadata is kept with the entry, so that copying it doesn't stat it again.
+            Ok(FileType::Regular) => {
+                if entry.2.is_none() {
+                    entry.2 = fs::symlink_metadata(source.join(&entry.0)).ok();
+                }
+                entry.2.as_ref().map(Metadata::len)
+            }
+            Ok(FileType::Directory) | Err(_) => None,
+            Ok(_) => Some(0),
+        };
+        match size.filter(|&size| size < limit) {
+            Some(size) => {
+                if bytes + size > limit || batch.len() == MAX_BATCH_ENTRIES {
+                    batches.push(mem::take(&mut batch));
+                    bytes = 0;
+                }
+                bytes += size;
+                batch.push(entry);
+            }
+            None => rest.push(entry),
+        }
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    (batches, rest)
+}
+
+// The batches are copied in a function of their own, before the rest of the entries rather than
+// alongside them, so that copying the directories among the rest doesn't add the frames of copying
+// a batch at every level of nesting.
+fn copy_batches(batches: Vec<Vec<Entry>>, copy: &(impl Fn(Entry) -> bool + Sync)) -> bool {
+    batches
+        .into_par_iter()
+        .map(|batch| batch.into_iter().map(copy).fold(false, BitOr::bitor))
+        .reduce(|| false, BitOr::bitor)
+}
+
+/// The largest regular files whose contents are copied together with `--io-uring`.
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+const SMALL_FILE_SIZE: u64 = 64 * 1024;
+
+// With `--io-uring`, the contents of the small regular files among `entries` are copied together up
+// front, after which the rest of copying each of them (e.g. preserving its attributes) proceeds as
+// usual. Only files which would be copied as usual are included, so none are copied while any
+// option might have them skipped, or copied some other way. Should io_uring be unavailable, this
+// copies nothing.
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+fn copy_small_files(
+    source: &Path,
+    dest: &Path,
+    entries: &[Entry],
+    context: &Context,
+) -> Option<Batched> {
+    let options = context.options;
+    if !options.io_uring
+        || options.attrs_only
+        || options.skeleton.is_some()
+        || options.staging_dir.is_some()
+        || options.reflink.is_some()
+        || options.sparse.is_some()
+        || options.direct
+        || options.fsync
+        || options.max_entries.is_some()
+        || options.timeout.is_some()
+        || options.cancel.is_some()
+        || options.throttle.is_some()
+    {
+        return None;
+    }
+    let mut paths = Vec::new();
+    for (file_name, file_type, _) in entries {
+        if !matches!(file_type, Ok(FileType::Regular)) {
+            continue;
+        }
+        let path = source.join(file_name);
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        // Files reporting a size of zero may still have contents (see `fs::copy`), and those with
+        // other links are linked to rather than copied again once one of them has been.
+        let content = options.content(&path);
+        if metadata.len() == 0
+            || metadata.len() > SMALL_FILE_SIZE
+            || metadata.nlink() > 1
+            || options.is_filtered(&metadata)
+            || content.mechanism.is_some()
+            || content.reflink.is_some()
+        {
+            continue;
+        }
+        let dest_path = dest_path(&path, file_name, dest, context);
+        paths.push((path, dest_path, metadata));
+    }
+    if paths.is_empty() {
+        return None;
+    }
+    let files = paths
+        .iter()
+        .map(|(source, dest, metadata)| uring::SmallFile {
+            source,
+            dest,
+            len: metadata.len(),
+            mode: metadata.permissions().mode(),
+        })
+        .collect::<Vec<_>>();
+    let results = {
+        let _held = context.descriptors.acquire(uring::DESCRIPTORS);
+        uring::copy_small_files(&files, dest_flags(context)).ok()?
+    };
+    let batched = paths
+        .into_iter()
+        .map(|(source, _, _)| source)
+        .zip(results)
+        .collect();
+    Some(Mutex::new(batched))
+}
+
+#[cfg(not(all(feature = "io_uring", target_os = "linux")))]
+fn copy_small_files(_: &Path, _: &Path, _: &[Entry], _: &Context) -> Option<Batched> {
+    None
+}
+
+// On a case-insensitive destination, entries of `source` whose names differ only in case would be
+// copied over one another. Each such collision is warned about, or with
+// `--error-on-case-collision` reported as an error and all but the first of the colliding entries
+// skipped. Only the entries given are compared, so when copying in batches (with `--readdir-batch`
+// or beyond `STREAMING_THRESHOLD`) collisions between entries in different batches go undetected.
+fn check_case_collisions(
+    source: &Path,
+    entries: Vec<Entry>,
+    context: &Context,
+) -> (Vec<Entry>, bool) {
+    if !context.case_insensitive {
+        return (entries, false);
+    }
+    let (mut first_names, mut has_err) = (HashMap::new(), false);
+    let entries = entries
+        .into_iter()
+        .filter(|(file_name, file_type, _)| {
+            let folded = file_name.to_string_lossy().to_lowercase();
+            let first = match first_names.get(&folded) {
+                Some(first) => source.join(first),
+                None => {
+                    first_names.insert(folded, file_name.clone());
+                    return true;
+                }
+            };
+            let path = source.join(file_name);
+            if !context.options.error_on_case_collision {
+                eprintln!(
+                    "Warning: '{}' and '{}' differ only in case, so one will overwrite the other \
+                     in the case-insensitive destination",
+                    first.display(),
+                    path.display()
+                );
+                return true;
+            }
+            let err = Error::new(format!(
+                "{}: not copied, as its name differs only in case from '{}' and the destination \
+                 is case-insensitive",
+                path.display(),
+                first.display()
+            ));
+            report_error(&path, file_type.as_ref().ok().copied(), &err, context);
+            has_err = true;
+            false
+        })
+        .collect();
+    (entries, has_err)
+}
+
+// Sorting by size requires statting every entry up front, as the type of an entry can usually be
+// determined from its directory's listing alone, so there's no earlier stat to reuse. Entries of
+// the same size are sorted by name, so that every order is the same from one run to the next.
+fn sort_entries(source: &Path, entries: Vec<Entry>, order: Order) -> Vec<Entry> {
+    if order == Order::Name {
+        let mut entries = entries;
+        entries.sort_unstable_by(|(a, ..), (b, ..)| a.cmp(b));
+        return entries;
+    }
+    let mut sized = entries
         .into_par_iter()
-        .map(|(file_name, file_type)| {
-            copy_file(&source.join(&file_name), file_type, &dest.join(&file_name))
+        .map(|entry| {
+            let size = fs::symlink_metadata(source.join(&entry.0)).map_or(0, |meta| meta.len());
+            (size, entry)
         })
-        .reduce(|| has_err, BitOr::bitor))
+        .collect::<Vec<_>>();
+    sized.sort_unstable_by(|(a_size, (a, ..)), (b_size, (b, ..))| match order {
+        Order::LargestFirst => b_size.cmp(a_size).then_with(|| a.cmp(b)),
+        _ => a_size.cmp(b_size).then_with(|| a.cmp(b)),
+    });
+    sized.into_iter().map(|(_, entry)| entry).collect()
 }
 
 fn reject_self_copies(sources: &[PathBuf], dest: &Path) -> Result<()> {
@@ -102,10 +1530,12 @@ fn reject_self_copies(sources: &[PathBuf], dest: &Path) -> Result<()> {
         .map(|ancestor| fs::metadata(ancestor).map(|meta| meta.ino()));
 
     // In contrast, we use `fs::symlink_metadata` for `source_inos` because we copy the symlinks
-    // themselves, not the underlying files that they point to.
+    // themselves, not the underlying files that they point to. A source which can't be statted
+    // (e.g. because it doesn't exist) can't be one of `dest`'s ancestors, and is left to fail when
+    // it's copied, so that its error is reported, logged, and handled like any other.
     let source_inos = sources
         .iter()
-        .map(|source| fs::symlink_metadata(source).map(|meta| meta.ino()))
+        .map(|source| fs::symlink_metadata(source).map(|meta| meta.ino()).ok())
         .collect::<Box<_>>();
 
     let mut errors = Vec::new();
@@ -113,14 +1543,12 @@ fn reject_self_copies(sources: &[PathBuf], dest: &Path) -> Result<()> {
     for (ancestor, ino) in dest.ancestors().zip(ancestor_inos) {
         let ino = ino?;
         for (source, source_ino) in sources.iter().zip(source_inos.as_ref()) {
-            match source_ino {
-                Ok(source_ino) if *source_ino == ino => errors.push(format!(
+            if *source_ino == Some(ino) {
+                errors.push(format!(
                     "Cannot copy directory '{}' into itself '{}'",
                     source.display(),
                     ancestor.strip_prefix(prefix).unwrap_or(ancestor).display()
-                )),
-                Err(err) => errors.push(err.to_string()),
-                _ => {}
+                ));
             }
         }
     }
@@ -132,6 +1560,45 @@ fn reject_self_copies(sources: &[PathBuf], dest: &Path) -> Result<()> {
     }
 }
 
+// Unlike `reject_self_copies`, this handles destinations which don't exist yet (e.g. `fcp dir
+// dir/sub`), and is done up front so that nothing at all is copied.
+fn reject_dest_inside_source(sources: &[PathBuf], dest: &Path) -> Result<()> {
+    let dest_path = canonicalize_partially(dest)?;
+    for source in sources {
+        if !fs::symlink_metadata(source).is_ok_and(|metadata| metadata.is_dir()) {
+            continue;
+        }
+        if dest_path.starts_with(fs::canonicalize(source)?) {
+            return Err(Error::dest_inside_source(source, dest));
+        }
+    }
+    Ok(())
+}
+
+// Canonicalize `path`, which needn't exist: its deepest existing ancestor is canonicalized, and
+// the remaining components appended to that.
+fn canonicalize_partially(path: &Path) -> Result<PathBuf> {
+    let path = env::current_dir()?.join(path);
+    let mut missing = Vec::new();
+    let mut ancestor = path.as_path();
+    loop {
+        if let Ok(canonical) = ancestor.canonicalize() {
+            return Ok(missing
+                .iter()
+                .rev()
+                .fold(canonical, |path, name| path.join(name)));
+        }
+        match (ancestor.file_name(), ancestor.parent()) {
+            (Some(name), Some(parent)) => {
+                missing.push(name);
+                ancestor = parent;
+            }
+            // The path contains a `..` which can't be resolved, so give up on canonicalizing it.
+            _ => return Ok(path.clone()),
+        }
+    }
+}
+
 fn file_names(sources: &[PathBuf]) -> Result<Vec<&OsStr>> {
     let source_file_names = sources
         .iter()
@@ -170,48 +1637,913 @@ fn file_names(sources: &[PathBuf]) -> Result<Vec<&OsStr>> {
     }
 }
 
-/// Copy each file in `sources` into the directory `dest`.
-fn copy_into(sources: &[PathBuf], dest: &Path) -> bool {
-    if let Some(err) = match fs::metadata(dest) {
-        Err(err) => Some(err),
-        Ok(metadata) if !metadata.is_dir() => {
-            Some(Error::new(format!("{} is not a directory", dest.display())))
+// With `--parents`, each source is copied to its own path beneath the destination rather than
+// just its file name. Absolute paths are treated as relative to the root directory (e.g. both
+// `/a/b` and `a/b` are copied to `dest/a/b`), and `.` components are ignored. Paths containing
+// `..` are rejected, as they could escape the destination.
+fn parent_paths(sources: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut errors = Vec::new();
+    let mut paths = Vec::with_capacity(sources.len());
+    for source in sources {
+        let mut path = PathBuf::new();
+        for component in source.components() {
+            match component {
+                Component::Normal(name) => path.push(name),
+                Component::ParentDir => {
+                    errors.push(format!(
+                        "{}: paths containing '..' cannot be copied with --parents",
+                        source.display()
+                    ));
+                    break;
+                }
+                Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+            }
+        }
+        if path.as_os_str().is_empty() {
+            errors.push(format!(
+                "{}: path does not end with a file name",
+                source.display()
+            ));
+        }
+        paths.push(path);
+    }
+    if !errors.is_empty() {
+        Err(Error::new(errors.join("\n")))
+    } else {
+        Ok(paths)
+    }
+}
+
+/// Copy `source` to each of `dests`, reading the contents of regular files only once. When
+/// copying to one of the destinations fails the error is reported, and copying continues to the
+/// others. The return value is the same as that of `copy_file`.
+fn tee_file(
+    source: &Path,
+    source_type: Result<FileType>,
+    dests: &[PathBuf],
+    context: &Context,
+) -> bool {
+    if interrupted() {
+        log_skipped(source, "interrupted", context);
+        return true;
+    }
+    if context.options.is_cancelled() {
+        log_skipped(source, "cancelled", context);
+        return true;
+    }
+    if context.limits.exceeded(context.options) {
+        log_skipped(source, "limit reached", context);
+        return true;
+    }
+    match source_type {
+        Ok(FileType::Regular) => tee_regular(source, dests, context),
+        Ok(FileType::Directory) if context.options.no_recursive => {
+            report_error(
+                source,
+                Some(FileType::Directory),
+                &omit_directory(source),
+                context,
+            );
+            true
+        }
+        Ok(FileType::Directory) => tee_directory(source, dests, context),
+        // Other types of file have no contents to speak of (devices aside, which are rare enough
+        // that reading them repeatedly doesn't matter), so are simply copied to each destination.
+        Ok(file_type) => dests
+            .iter()
+            .map(|dest| copy_file(source, Ok(file_type), dest, context))
+            .fold(false, BitOr::bitor),
+        Err(err) => {
+            report_error(source, None, &err, context);
+            true
         }
-        _ => reject_self_copies(sources, dest).err(),
-    } {
-        fatal(err)
     }
+}
 
-    sources
+fn tee_regular(source: &Path, dests: &[PathBuf], context: &Context) -> bool {
+    let metadata = SourceMetadata::new(source);
+    let inode = match metadata.get() {
+        Ok(metadata) => Some((metadata.dev(), metadata.ino())).filter(|_| metadata.nlink() > 1),
+        Err(err) => {
+            report_error(source, Some(FileType::Regular), &err, context);
+            return true;
+        }
+    };
+    // As in `copy_file`, any other link to a file already copied is recreated as links to each of
+    // its copies.
+    let mut claimed = None;
+    if let Some(inode) = inode {
+        match context.links.claim(inode, source, dests, context.source) {
+            Claim::Link(copies) if link_to_copies(source, &copies, dests, context) => return false,
+            Claim::Link(_) => {}
+            Claim::Deferred => return false,
+            Claim::Copy => claimed = Some(inode),
+        }
+    }
+    let has_err = tee_contents(source, &metadata, dests, context);
+    // Only copies which are complete are linked to.
+    match claimed {
+        Some(inode) => {
+            let copies = Some(dests.to_vec()).filter(|_| !has_err);
+            finish_links(inode, copies, context) | has_err
+        }
+        None => has_err,
+    }
+}
+
+fn tee_contents(
+    source: &Path,
+    metadata: &SourceMetadata,
+    dests: &[PathBuf],
+    context: &Context,
+) -> bool {
+    let report = |err: Error| {
+        report_error(source, Some(FileType::Regular), &err, context);
+        true
+    };
+    let _held = context.descriptors.acquire(1 + dests.len());
+    let (mut source_file, permissions) = match metadata
+        .get()
+        .and_then(|metadata| Ok((fs::open(source)?, metadata.permissions())))
+    {
+        Ok(opened) => opened,
+        Err(err) => return report(err),
+    };
+    let mut has_err = false;
+    let mut outputs = Vec::with_capacity(dests.len());
+    for dest in dests {
+        let created = fs::create_truncate(dest, permissions.mode(), context.options.dest_flags())
+            .and_then(|file| {
+                // As in `fs::copy`, the mode given when creating the file is subject to the
+                // umask, so the permissions are set explicitly.
+                file.set_permissions(permissions.clone()).map_err(|err| {
+                    let message = format!("{}: {}", dest.display(), err);
+                    Error::with_os_error(message, "set_permissions", &err)
+                })?;
+                Ok(file)
+            });
+        match created {
+            Ok(file) => outputs.push((dest, file)),
+            Err(err) => has_err = report(err),
+        }
+    }
+    let size = context.options.buffer_size.unwrap_or(fs::BUFFER_SIZE);
+    let (mut buffer, mut copied) = (vec![0; size], 0);
+    while !outputs.is_empty() {
+        // As in `fs::copy`, copies cancelled partway are removed rather than left incomplete.
+        if context.options.is_cancelled() {
+            for (dest, _) in &outputs {
+                let _ = std::fs::remove_file(dest);
+            }
+            log_skipped(source, "cancelled", context);
+            return true;
+        }
+        let count = match source_file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                let message = format!("{}: {}", source.display(), err);
+                return report(Error::with_os_error(message, "read", &err));
+            }
+        };
+        let chunk = &buffer[..count];
+        let results = outputs
+            .par_iter_mut()
+            .map(|(dest, file)| {
+                file.write_all(chunk).map_err(|err| {
+                    let message = format!("{}: {}", dest.display(), err);
+                    Error::with_os_error(message, "write", &err)
+                })
+            })
+            .collect::<Vec<_>>();
+        // Iterating in reverse keeps the indices of the remaining failures valid while removing.
+        for (index, result) in results.into_iter().enumerate().rev() {
+            if let Err(err) = result {
+                has_err = report(err);
+                outputs.remove(index);
+            }
+        }
+        copied += count as u64;
+        if let Some(throttle) = &context.options.throttle {
+            throttle.record(count as u64);
+        }
+    }
+    for (dest, file) in &outputs {
+        if context.options.fsync {
+            if let Err(err) = file.sync_all() {
+                let message = format!("{}: {}", dest.display(), err);
+                has_err = report(Error::with_os_error(message, "fsync", &err));
+            }
+        }
+        // In the same order as in `copy_file`.
+        if let Err(err) = preserve_attributes(metadata, dest, context)
+            .and_then(|_| copy_acls(source, dest, context))
+            .and_then(|_| fs::copy_finder_metadata(source, dest))
+        {
+            has_err = report(err);
+        }
+        if context.options.preserve_capabilities {
+            if let Err(err) = copy_capabilities(source, dest) {
+                has_err = report(err);
+            }
+        }
+        if let Err(err) = set_mtime_from_name(source, dest, context) {
+            has_err = report(err);
+        }
+        if let Err(err) = copy_security_context(source, dest, context)
+            .and_then(|_| copy_flags(metadata, dest, context))
+        {
+            has_err = report(err);
+        }
+    }
+    if !outputs.is_empty() {
+        if let Some(progress) = context.progress {
+            progress.record(context.source, copied);
+        }
+        log_copied(source, context);
+    }
+    has_err
+}
+
+fn tee_directory(source: &Path, dests: &[PathBuf], context: &Context) -> bool {
+    let report = |err: Error| {
+        report_error(source, Some(FileType::Directory), &err, context);
+        true
+    };
+    let (mode, device) = match fs::symlink_metadata(source) {
+        Ok(metadata) => (metadata.permissions().mode(), metadata.dev()),
+        Err(err) => return report(err),
+    };
+    let mut has_err = false;
+    let dests = dests
         .iter()
-        .zip(file_names(sources).unwrap_or_else(|err| fatal(err)))
+        .filter(|dest| match fs::create_dir(dest, mode) {
+            Ok(()) => true,
+            Err(err) => {
+                has_err = report(err);
+                false
+            }
+        })
+        .collect::<Vec<_>>();
+    // As in `copy_directory`, a mount point is copied as an empty directory.
+    if context.is_mount_point(device) {
+        for dest in dests {
+            if let Err(err) = fs::copy_attributes(source, dest) {
+                has_err = report(err);
+            }
+        }
+        log_copied(source, context);
+        return has_err;
+    }
+    let context = &context.within(device);
+    let entries = match fs::read_dir(source) {
+        Ok(entries) if !dests.is_empty() => entries,
+        Ok(_) => return has_err,
+        Err(err) => return report(err),
+    };
+    let mut batch = Vec::new();
+    for entry in entries {
+        match entry {
+            Ok(entry) => batch.push((entry.file_name(), fs::entry_file_type(&entry), None)),
+            Err(err) => {
+                eprintln!("{}", err);
+                has_err = true;
+            }
+        }
+    }
+    let (batch, collided) = check_case_collisions(source, batch, context);
+    has_err |= collided;
+    has_err |= batch
+        .into_par_iter()
+        .map(|(file_name, file_type, _)| {
+            let source = source.join(&file_name);
+            let dests = dests
+                .iter()
+                .map(|dest| dest_path(&source, &file_name, dest, context))
+                .collect::<Vec<_>>();
+            tee_file(&source, file_type, &dests, context)
+        })
+        .reduce(|| false, BitOr::bitor);
+    let metadata = SourceMetadata::new(source);
+    for dest in dests {
+        if let Err(err) = sync_directory(dest, context)
+            .and_then(|_| preserve_attributes(&metadata, dest, context))
+            .and_then(|_| copy_acls(source, dest, context))
+            .and_then(|_| fs::copy_finder_metadata(source, dest))
+            .and_then(|_| copy_security_context(source, dest, context))
+            .and_then(|_| copy_flags(&metadata, dest, context))
+        {
+            has_err = report(err);
+        }
+    }
+    log_copied(source, context);
+    has_err
+}
+
+/// The path inside the directory `dest` to which `source`, whose name is `file_name`, is copied.
+/// This is normally just `file_name`, unless a `path_mapper` says otherwise.
+fn dest_path(source: &Path, file_name: &OsStr, dest: &Path, context: &Context) -> PathBuf {
+    match &context.options.path_mapper {
+        Some(path_mapper) => dest.join(path_mapper.map(source)),
+        None => dest.join(file_name),
+    }
+}
+
+/// Copy each file in `sources` into every one of the directories `dests`.
+fn copy_into_each(sources: &[PathBuf], dests: &[PathBuf], context: &Context) -> Result<bool> {
+    for dest in dests {
+        let metadata = fs::metadata(dest).map_err(|err| fs::name_non_directory(dest, err))?;
+        if !metadata.is_dir() {
+            return Err(Error::new(format!("{} is not a directory", dest.display())));
+        }
+        reject_self_copies(sources, dest)?;
+    }
+
+    Ok(sources
+        .iter()
+        .zip(file_names(sources)?)
+        .enumerate()
         .collect::<Box<_>>()
         .into_par_iter()
-        .map(|(source, file_name)| copy_file(source, fs::file_type(source), &dest.join(file_name)))
-        .reduce(|| false, BitOr::bitor)
+        .map(|(index, (source, file_name))| {
+            let dests = dests
+                .iter()
+                .map(|dest| dest_path(source, file_name, dest, context))
+                .collect::<Vec<_>>();
+            tee_file(
+                source,
+                fs::file_type(source),
+                &dests,
+                &context.for_source(index),
+            )
+        })
+        .reduce(|| false, BitOr::bitor))
+}
+
+/// Directories which have been ensured to exist, so that each is created only once even when
+/// many sources being copied concurrently share it.
+#[derive(Default)]
+struct EnsuredDirs {
+    dirs: Mutex<HashMap<PathBuf, Arc<Mutex<bool>>>>,
+    /// The directories created whose attributes are still to be set, along with the source
+    /// directory each corresponds to, if any.
+    created: Mutex<Vec<(PathBuf, Option<PathBuf>)>>,
+}
+
+impl EnsuredDirs {
+    /// Create each missing ancestor of `dest`, the path within `root` which `source` is copied to
+    /// with `--parents`, unless this has already been done. Callers racing to ensure the same
+    /// directory wait for the first of them to finish creating it, rather than all attempting
+    /// to. If that fails, the next caller tries again, and so gets to report the error for
+    /// itself.
+    fn ensure(&self, source: &Path, root: &Path, context: &Context) -> Result<()> {
+        // Each directory beneath `root` corresponds to the source path with the same components,
+        // e.g. `root/a/b` to `/a/b` for the source `/a/b/c.txt`.
+        let (mut source_dir, mut dir) = (PathBuf::new(), root.to_path_buf());
+        let mut ancestors = Vec::new();
+        for component in source.components() {
+            source_dir.push(component);
+            if let Component::Normal(name) = component {
+                dir.push(name);
+                ancestors.push((dir.clone(), source_dir.clone()));
+            }
+        }
+        ancestors.pop();
+        for (dir, source_dir) in ancestors {
+            self.ensure_dir(dir, source_dir, context)?;
+        }
+        Ok(())
+    }
+
+    fn ensure_dir(&self, dir: PathBuf, source_dir: PathBuf, context: &Context) -> Result<()> {
+        // The lock on the whole map is held only long enough to find the directory's entry, so
+        // that directories are still created concurrently with one another.
+        let entry = Arc::clone(self.dirs.lock().unwrap().entry(dir.clone()).or_default());
+        let mut ensured = entry.lock().unwrap();
+        if *ensured || dir.is_dir() {
+            *ensured = true;
+            return Ok(());
+        }
+        let counterpart = fs::symlink_metadata(&source_dir).is_ok_and(|metadata| metadata.is_dir());
+        // Until everything has been copied into it, a directory whose mode is still to be set is
+        // only accessible to its owner, so that a more restrictive mode doesn't get in the way.
+        match (counterpart, context.options.parent_mode) {
+            (true, _) => {
+                fs::create_dir(&dir, 0o700)?;
+                self.created.lock().unwrap().push((dir, Some(source_dir)));
+            }
+            (false, Some(_)) => {
+                fs::create_dir(&dir, 0o700)?;
+                self.created.lock().unwrap().push((dir, None));
+            }
+            (false, None) => fs::create_dir(&dir, 0o755)?,
+        }
+        *ensured = true;
+        Ok(())
+    }
+
+    /// Give each directory created the attributes of its source counterpart, or otherwise the
+    /// mode given by `--parent-mode`, returning whether there were any errors. This is done once
+    /// everything has been copied into them, as otherwise their times wouldn't reflect their
+    /// sources'.
+    fn finish(self, context: &Context) -> bool {
+        let mut created = self.created.into_inner().unwrap();
+        // The deepest directories come first, so that each is still writable while those within
+        // it are finished.
+        created.sort_by_key(|(dir, _)| Reverse(dir.components().count()));
+        let mut has_err = false;
+        for (dir, source_dir) in created {
+            let result = match &source_dir {
+                Some(source_dir) => fs::copy_attributes(source_dir, &dir),
+                None => fs::set_mode(&dir, context.options.parent_mode.unwrap_or(0o755)),
+            };
+            if let Err(err) = result {
+                report_error(
+                    source_dir.as_deref().unwrap_or(&dir),
+                    Some(FileType::Directory),
+                    &err,
+                    context,
+                );
+                has_err = true;
+            }
+        }
+        has_err
+    }
+}
+
+/// Copy each file in `sources` into the directory `dest`.
+fn copy_into(sources: &[PathBuf], dest: &Path, context: &Context) -> Result<bool> {
+    let metadata = fs::metadata(dest).map_err(|err| fs::name_non_directory(dest, err))?;
+    if !metadata.is_dir() {
+        return Err(Error::new(format!("{} is not a directory", dest.display())));
+    }
+    reject_self_copies(sources, dest)?;
+
+    if context.options.parents && context.options.path_mapper.is_none() {
+        // Each source is copied into its own (possibly not yet existing) directory beneath
+        // `dest`, which is created as the source is dispatched.
+        let parent_dirs = EnsuredDirs::default();
+        let has_err = sources
+            .iter()
+            .zip(parent_paths(sources)?)
+            .enumerate()
+            .collect::<Box<_>>()
+            .into_par_iter()
+            .map(|(index, (source, path))| {
+                let context = &context.for_source(index);
+                if let Err(err) = parent_dirs.ensure(source, dest, context) {
+                    report_error(source, None, &err, context);
+                    return true;
+                }
+                copy_file(source, fs::file_type(source), &dest.join(path), context)
+            })
+            .reduce(|| false, BitOr::bitor);
+        return Ok(parent_dirs.finish(context) || has_err);
+    }
+
+    Ok(sources
+        .iter()
+        .zip(file_names(sources)?)
+        .enumerate()
+        .collect::<Box<_>>()
+        .into_par_iter()
+        .map(|(index, (source, file_name))| {
+            copy_file(
+                source,
+                fs::file_type(source),
+                &dest_path(source, file_name, dest, context),
+                &context.for_source(index),
+            )
+        })
+        .reduce(|| false, BitOr::bitor))
 }
 
 // The `allow` here is present because clippy doesn't realize that `source` must be of
 // type `&PathBuf` in order for the call to `array::from_ref` to typecheck.
 #[allow(clippy::ptr_arg)]
-fn copy_single(source: &PathBuf, dest: &Path) -> bool {
-    let source_metadata = fs::symlink_metadata(source).unwrap_or_else(|err| fatal(err));
+fn copy_single(source: &PathBuf, dest: &Path, context: &Context) -> Result<bool> {
+    let source_metadata = fs::symlink_metadata(source)?;
     match (fs::metadata(dest), fs::symlink_metadata(dest)) {
-        (Ok(metadata), _) if metadata.is_dir() => copy_into(array::from_ref(source), dest),
-        (_, Ok(metadata)) if source_metadata.ino() == metadata.ino() => fatal(format!(
+        (Ok(metadata), _) if metadata.is_dir() => copy_into(array::from_ref(source), dest, context),
+        (_, Ok(metadata)) if source_metadata.ino() == metadata.ino() => Err(Error::new(format!(
             "Cannot overwrite file '{}' with itself '{}'",
             source.display(),
             dest.display()
+        ))),
+        _ => {
+            let context = Context {
+                skip_special_on_error: false,
+                ..*context
+            };
+            Ok(copy_file(source, fs::file_type(source), dest, &context))
+        }
+    }
+}
+
+// With `--swap`, `source` is copied to a hidden path beside `dest`, which is only swapped into place
+// once the copy is complete, so that `dest` is never seen partially copied (see
+// `fs::swap_into_place`). If anything couldn't be copied, or the copy was cut short, `dest` is left
+// as it was and the partial copy is removed.
+fn copy_swapped(source: &Path, dest: &Path, context: &Context) -> Result<bool> {
+    let file_name = dest.file_name().ok_or_else(|| {
+        Error::new(format!(
+            "Cannot swap a copy into place at '{}', as it has no file name",
+            dest.display()
+        ))
+    })?;
+    let dir = dest.parent().unwrap_or_else(|| Path::new("."));
+    let staged = fs::temporary_path(dir, file_name);
+    let context = Context {
+        skip_special_on_error: false,
+        ..*context
+    };
+    if !copy_file(source, fs::file_type(source), &staged, &context) {
+        fs::swap_into_place(&staged, dest, context.options.fsync)?;
+        return Ok(false);
+    }
+    let removed = match fs::symlink_metadata(&staged) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&staged),
+        Ok(_) => fs::remove_file(&staged),
+        Err(_) => Ok(()),
+    };
+    if let Err(err) = removed {
+        eprintln!("{}", err);
+    }
+    if !interrupted() {
+        eprintln!(
+            "'{}' was left unchanged, as '{}' couldn't be copied completely",
+            dest.display(),
+            source.display()
+        );
+    }
+    Ok(true)
+}
+
+// Whether `dest` (or its parent, if `dest` is yet to be created) matches names case-insensitively.
+// When this can't be determined, the destination is assumed to be case-sensitive.
+fn is_case_insensitive(dest: &Path) -> bool {
+    let dir = match dest.parent() {
+        _ if dest.is_dir() => dest,
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    fs::is_case_insensitive(dir).unwrap_or(false)
+}
+
+// With `--fsync`, the directory in which each top-level copy was created is synced, making its
+// name durable. That's `dest` itself when copying into it, and otherwise its parent, so both are
+// synced rather than working out which applies.
+fn sync_top_level(dest: &Path) -> Result<()> {
+    if dest.is_dir() {
+        fs::sync_dir(dest)?;
+    }
+    match dest.parent() {
+        Some(parent) if parent != Path::new("") => fs::sync_dir(parent),
+        _ => fs::sync_dir("."),
+    }
+}
+
+/// Copy according to the command-line arguments `args`, returning whether any error occurred
+/// while copying. Errors which prevent the copy from starting at all (e.g. a destination which is
+/// not a directory) are returned as an `Err` instead, leaving it to the caller to decide how to
+/// report them and whether to exit.
+pub fn fcp(args: &[String]) -> Result<bool> {
+    let (options, mut operands) = options::parse(args)?;
+    let listed = match &options.files_from {
+        Some(list) => read_file_list(list)?,
+        None => Vec::new(),
+    };
+    // With `--to` or `--record-only`, every operand is a source. Otherwise the last is the
+    // destination, unless the sources were all listed with `--files-from`, in which case it's
+    // missing.
+    let dest = match options.to.is_empty() && options.record_only.is_none() {
+        true if operands.is_empty() && listed.is_empty() => {
+            return Err(Error::usage(
+                ErrorKind::NoSources,
+                "No sources or destination specified",
+            ));
+        }
+        true if operands.is_empty() => {
+            return Err(Error::usage(
+                ErrorKind::NoDestination,
+                "No destination specified for the sources listed with '--files-from'",
+            ));
+        }
+        true => operands.pop(),
+        false => None,
+    };
+    operands.extend(listed);
+    let sources = operands;
+    match (&dest, sources.is_empty()) {
+        (Some(dest), true) if options.files_from.is_none() => Err(Error::usage(
+            ErrorKind::NoDestination,
+            &format!("No destination specified for '{}'", dest.display()),
         )),
-        _ => copy_file(source, fs::file_type(source), dest),
+        (_, true) => Err(Error::usage(ErrorKind::NoSources, "No sources specified")),
+        (Some(dest), false) => fcp_with_options(&sources, slice::from_ref(dest), &options),
+        (None, false) => fcp_with_options(&sources, &options.to, &options),
     }
 }
 
-pub fn fcp(args: &[String]) -> bool {
-    let args: Box<_> = args.iter().map(PathBuf::from).collect();
-    match args.as_ref() {
-        [] | [_] => fatal("Please provide at least two arguments (run 'fcp --help' for details)"),
-        [source, dest] => copy_single(source, dest),
-        [sources @ .., dest] => copy_into(sources, dest),
+// The paths listed in `list` (or stdin, if it's `-`), one per line. Blank lines are ignored.
+fn read_file_list(list: &Path) -> Result<Vec<PathBuf>> {
+    let read = if list == Path::new("-") {
+        let mut contents = String::new();
+        io::stdin().read_to_string(&mut contents).map(|_| contents)
+    } else {
+        std::fs::read_to_string(list)
+    };
+    let contents = read.map_err(|err| {
+        let message = format!("{}: {}", list.display(), err);
+        Error::with_os_error(message, "read", &err)
+    })?;
+    Ok(contents
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Estimate how much copying each of `sources` according to `options` would create, without
+/// copying anything, so that programs using `fcp` as a library can (e.g.) ask for confirmation
+/// before starting a large copy. The sources are traversed in parallel, as in copying them, and
+/// `no_recursive`, `one_file_system`, `newer_than`, `min_size`, `max_size`, `skeleton`, and
+/// `sockets` are taken into account. Files which can't be read are left out, as copying them
+/// would fail, and directories which `newer_than` would leave empty are still counted.
+pub fn estimate(sources: &[PathBuf], options: &CopyOptions) -> CopyEstimate {
+    sources
+        .par_iter()
+        .map(|source| estimate_path(source, None, options))
+        .reduce(CopyEstimate::default, Add::add)
+}
+
+// `within` is the device of the directory containing `path`, if it's not a source itself.
+fn estimate_path(path: &Path, within: Option<u64>, options: &CopyOptions) -> CopyEstimate {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return CopyEstimate::default(),
+    };
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        if options.no_recursive {
+            return CopyEstimate::default();
+        }
+        let estimate = CopyEstimate {
+            directories: 1,
+            ..CopyEstimate::default()
+        };
+        // As in `copy_directory`, a mount point is copied as an empty directory.
+        if options.one_file_system && within.is_some_and(|device| device != metadata.dev()) {
+            return estimate;
+        }
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(_) => return estimate,
+        };
+        // The identity of `reduce` is used once for each split of the entries, so the directory
+        // itself is only added afterwards.
+        return entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|path| estimate_path(&path, Some(metadata.dev()), options))
+            .reduce(CopyEstimate::default, Add::add)
+            + estimate;
+    }
+    if options.skeleton == Some(Skeleton::DirsOnly) || options.is_filtered(&metadata) {
+        return CopyEstimate::default();
+    }
+    if file_type.is_file() {
+        let bytes = match options.skeleton {
+            Some(Skeleton::EmptyFiles) => 0,
+            _ => metadata.len(),
+        };
+        CopyEstimate {
+            files: 1,
+            bytes,
+            ..CopyEstimate::default()
+        }
+    } else if file_type.is_symlink() {
+        CopyEstimate {
+            symlinks: 1,
+            ..CopyEstimate::default()
+        }
+    } else if file_type.is_socket() && options.sockets != Some(Sockets::Recreate) {
+        CopyEstimate::default()
+    } else {
+        CopyEstimate {
+            special: 1,
+            ..CopyEstimate::default()
+        }
+    }
+}
+
+/// Copy each of `sources` according to `options`, returning whether any error occurred while
+/// copying, as with `fcp`. This is for programs using `fcp` as a library, which can set options
+/// that have no command-line equivalent (e.g. `path_mapper`). `dests` are the destination
+/// operands: either the last operand given on the command line, or the directories given with
+/// `--to` (which must also be in `options.to`), and there are none with `record_only`.
+pub fn fcp_with_options(
+    sources: &[PathBuf],
+    dests: &[PathBuf],
+    options: &CopyOptions,
+) -> Result<bool> {
+    if dests.len() > 1
+        && (options.staging_dir.is_some()
+            || options.newer_than.is_some()
+            || options.parents
+            || options.existing.is_some()
+            || options.attrs_only)
+    {
+        return Err(Error::new(
+            "Options '--staging-dir', '--newer-than', '--parents', '--no-clobber', \
+             '--ignore-existing', '--interactive', and '--attrs-only' cannot be used when copying \
+             to more than one destination"
+                .to_string(),
+        ));
+    }
+    if options.swap
+        && (sources.len() != 1 || !options.to.is_empty() || options.parents || options.attrs_only)
+    {
+        return Err(Error::new(
+            "Option '--swap' requires exactly one SOURCE and DESTINATION, and cannot be used with \
+             '--to', '--parents', or '--attrs-only'"
+                .to_string(),
+        ));
+    }
+    if options.flatten
+        && (dests.len() > 1
+            || options.parents
+            || options.swap
+            || options.attrs_only
+            || options.dry_run.is_some())
+    {
+        return Err(Error::new(
+            "Option '--flatten' requires exactly one DESTINATION, and cannot be used with \
+             '--parents', '--swap', '--attrs-only', or '--dry-run'"
+                .to_string(),
+        ));
+    }
+    if options.record_only.is_some()
+        && (!dests.is_empty() || options.dry_run.is_some() || options.flatten || options.swap)
+    {
+        return Err(Error::new(
+            "Option '--record-only' takes no DESTINATION, and cannot be used with '--to', \
+             '--dry-run', '--flatten', or '--swap'"
+                .to_string(),
+        ));
+    }
+    if options.stable_order && options.readdir_batch.is_some() {
+        return Err(Error::new(
+            "Options '--stable-order' and '--readdir-batch' cannot be used together".to_string(),
+        ));
+    }
+    for dest in dests {
+        reject_dest_inside_source(sources, dest)?;
+    }
+    if options.no_follow_dest_symlinks {
+        for dest in dests {
+            fs::reject_symlinks(dest)?;
+        }
+    }
+    if let Some(staging_dir) = &options.staging_dir {
+        if !fs::metadata(staging_dir)?.is_dir() {
+            return Err(Error::new(format!(
+                "{} is not a directory",
+                staging_dir.display()
+            )));
+        }
+    }
+    if options.dry_run.is_some() {
+        return dry_run::run(sources, dests, options);
+    }
+    if let Some(manifest) = &options.record_only {
+        return record::run(sources, manifest, options);
+    }
+    let storage = options.storage.unwrap_or_else(|| {
+        // A single hard disk drive, whether read or written, is enough to make copying many
+        // files at once thrash.
+        let paths = || sources.iter().chain(dests);
+        if paths().any(|path| fs::is_rotational(path) == Some(true)) {
+            Storage::Hdd
+        } else if paths().all(|path| fs::is_nvme(path) == Some(true)) {
+            Storage::Nvme
+        } else {
+            Storage::Ssd
+        }
+    });
+    let threads = options.jobs.or_else(|| match storage {
+        Storage::Hdd => Some(HDD_THREADS),
+        Storage::Nvme => cpus().map(|cpus| cpus * NVME_THREADS_PER_CPU),
+        Storage::Ssd => None,
+    });
+    // The copy runs in a pool of its own rather than in rayon's global pool, whose size can only
+    // be set once per process, so that a library caller may copy more than once. With no number of
+    // threads given, rayon picks it as it would for its global pool.
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads.unwrap_or(0))
+        .stack_size(THREAD_STACK_SIZE)
+        .build()?;
+    // `--summary-only` hides the lines printed by `--progress=tree`, as it does all but errors.
+    let tree = options.progress_tree && !options.summary_only;
+    let log = options.log_file.as_deref().map(Log::create).transpose()?;
+    // `--progress-to-fd` implies `--json-progress`.
+    let json = options.json_progress || options.progress_fd.is_some();
+    let events = options.progress_fd.map(fs::writable_fd).transpose()?;
+    let reporter = (json || tree)
+        .then(|| Reporter::start(sources, json, tree, events, options.progress_interval));
+    // `--json-progress` takes precedence over `--summary-only`, as its `done` event summarizes the
+    // copy already, and mixing a line of plain text into its output would confuse its consumers.
+    // Likewise over `--report-sizes`, unless its events are written elsewhere with
+    // `--progress-to-fd`.
+    let summary_only = options.summary_only && !json;
+    let report_sizes = options.report_sizes && !options.json_progress;
+    // Without a reporter, the counters are kept just for these.
+    let counters = (reporter.is_none() && (summary_only || report_sizes))
+        .then(|| Progress::for_sources(sources));
+    let limits = Limits::default();
+    let links = Links::default();
+    let descriptors = Descriptors::new();
+    let prompter = (options.existing == Some(Existing::Prompt)).then(Prompter::start);
+    // The timeout is cancelled when this is dropped at the end of the copy.
+    let _timeout = options.timeout.map(|timeout| limits.start_timeout(timeout));
+    let context = Context {
+        options,
+        progress: reporter
+            .as_ref()
+            .map(Reporter::progress)
+            .or(counters.as_ref()),
+        log: log.as_ref(),
+        limits: &limits,
+        links: &links,
+        descriptors: &descriptors,
+        prompter: prompter.as_ref(),
+        batched: None,
+        skip_special_on_error: options.skip_special_on_error,
+        // Nothing is created with `--attrs-only`, so nothing can be overwritten either.
+        case_insensitive: !options.attrs_only && dests.iter().any(|dest| is_case_insensitive(dest)),
+        source: None,
+        device: None,
+    };
+    let copy = || match (sources, dests) {
+        (_, [dest]) if options.flatten => flatten::copy_flattened(sources, dest, &context),
+        // Copying a single source with `--parents` still recreates its path within `dest`, and a
+        // destination given with `--to` is always a directory to copy into.
+        // With `--attrs-only`, a single source corresponds to `dest` itself, as when it was
+        // copied there in the first place, rather than to a path inside it.
+        ([source], [dest]) if options.attrs_only && !options.parents && options.to.is_empty() => {
+            Ok(copy_file(
+                source,
+                fs::file_type(source),
+                dest,
+                &context.for_source(0),
+            ))
+        }
+        ([source], [dest]) if options.swap => copy_swapped(source, dest, &context.for_source(0)),
+        ([source], [dest]) if !options.parents && options.to.is_empty() => {
+            copy_single(source, dest, &context.for_source(0))
+        }
+        (_, [dest]) => copy_into(sources, dest, &context),
+        _ => copy_into_each(sources, dests, &context),
+    };
+    let mut result = pool.install(copy);
+    if result.is_ok() && options.fsync && !options.attrs_only {
+        for dest in dests {
+            result = result.and_then(|has_err| sync_top_level(dest).map(|_| has_err));
+        }
+    }
+    if result.is_ok() && options.is_cancelled() {
+        result = Err(Error::cancelled());
+    } else if result.is_ok() && limits.aborted.load(Ordering::Relaxed) {
+        result = Err(Error::new(
+            "Copy aborted by its error handler after an error".to_string(),
+        ));
+    } else if result.is_ok() && limits.reached.load(Ordering::Relaxed) {
+        let entries = limits.entries.load(Ordering::Relaxed);
+        let reason = match options.max_entries {
+            Some(max) if entries > max => "the limit set by --max-entries was reached".to_string(),
+            _ => format!(
+                "the --timeout of {} seconds elapsed",
+                options.timeout.unwrap_or_default().as_secs()
+            ),
+        };
+        result = Err(Error::new(format!(
+            "Copy aborted after {} entries, as {}",
+            entries.min(options.max_entries.unwrap_or(entries)),
+            reason
+        )));
+    }
+    if let (true, Some(progress)) = (report_sizes, context.progress) {
+        for line in progress.sizes() {
+            println!("{}", line);
+        }
+    }
+    if let Some(reporter) = reporter {
+        reporter.finish(matches!(result, Ok(false)));
+    }
+    if let (true, Some(counters)) = (summary_only, counters) {
+        println!("{}", counters.summary());
     }
+    result
 }
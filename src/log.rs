@@ -0,0 +1,82 @@
+//! Logging of what became of every entry processed to a file given with `--log-file`, as a durable
+//! and parseable record of the whole copy for audit trails, independent of what's printed to
+//! stdout and stderr.
+//!
+//! Each line of the log is a JSON object of the form
+//! `{"timestamp":N,"action":S,"path":S,"result":S,"errno":N}`, where `timestamp` is the time in
+//! seconds (with millisecond precision) since the Unix epoch, `path` is that of the source, and
+//! `action` is one of:
+//! - `copied`, with a `result` of `ok`.
+//! - `skipped`, with the reason for skipping it as the `result`.
+//! - `failed`, with the error as the `result`, and its `errno` (or `null` if it didn't come from
+//!   the OS).
+//!
+//! `errno` is only present for `failed` entries. Lines are written whole from whichever thread
+//! processed the entry, so they're never interleaved, though they're in no particular order.
+
+use crate::error::{Error, Result};
+use crate::progress::{json_or_null, json_string};
+use std::fs::{File, OpenOptions};
+use std::io::{prelude::*, LineWriter};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The file to which a copy is logged with `--log-file`.
+#[derive(Debug)]
+pub struct Log {
+    file: Mutex<LineWriter<File>>,
+}
+
+impl Log {
+    /// Create (or truncate) the log file at `path`.
+    pub fn create(path: &Path) -> Result<Log> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|err| {
+                let message = format!("{}: {}", path.display(), err);
+                Error::with_os_error(message, "open", &err)
+            })?;
+        Ok(Log {
+            file: Mutex::new(LineWriter::new(file)),
+        })
+    }
+
+    /// Log that `path` was copied.
+    pub fn copied(&self, path: &Path) {
+        self.write("copied", path, "ok", None);
+    }
+
+    /// Log that `path` was skipped, for the given `reason`.
+    pub fn skipped(&self, path: &Path, reason: &str) {
+        self.write("skipped", path, reason, None);
+    }
+
+    /// Log that `path` couldn't be copied because of `err`.
+    pub fn failed(&self, path: &Path, err: &Error) {
+        let errno = format!(r#","errno":{}"#, json_or_null(err.raw_os_error()));
+        self.write("failed", path, &err.to_string(), Some(&errno));
+    }
+
+    fn write(&self, action: &str, path: &Path, result: &str, errno: Option<&str>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format!(
+            r#"{{"timestamp":{}.{:03},"action":"{}","path":{},"result":{}{}}}"#,
+            timestamp.as_secs(),
+            timestamp.subsec_millis(),
+            action,
+            json_string(&path.to_string_lossy()),
+            json_string(result),
+            errno.unwrap_or_default()
+        );
+        // A poisoned lock only means another thread panicked mid-write, which needn't stop this
+        // one. Failing to log shouldn't affect the copy, much as with progress events.
+        let mut file = self.file.lock().unwrap_or_else(|err| err.into_inner());
+        let _ = writeln!(file, "{}", line);
+    }
+}
@@ -1,5 +1,6 @@
-use fcp::{fatal, fcp};
+use fcp::fcp;
 use std::env;
+use std::fmt::Display;
 use std::process;
 
 static HELP: &str = concat!(
@@ -13,18 +14,455 @@ USAGE:
     fcp [OPTIONS] SOURCE ... DESTINATION_DIRECTORY
     Copy each SOURCE into DESTINATION_DIRECTORY
 
+    fcp [OPTIONS] --to DESTINATION_DIRECTORY ... SOURCE ...
+    Copy each SOURCE into every DESTINATION_DIRECTORY, reading each SOURCE only once
+
+    fcp [OPTIONS] --record-only MANIFEST SOURCE ...
+    Record the structure of each SOURCE in MANIFEST, without copying anything
+
 OPTIONS:
     -h, --help
             Output this usage information and exit.
 
     -V, --version
-            Output version information and exit."
+            Output version information and exit.
+
+        --explain
+            Report the mechanism used to copy each regular file (e.g. copy_file_range), or, for
+            a further hard link to a file already copied, the copy it was linked to. With
+            --no-clobber, --ignore-existing, or --interactive, also report each path skipped as
+            it already existed.
+
+        --readdir-batch N
+            Copy the entries of each directory in batches of N as they are read, rather than
+            reading the entire directory before copying any of it. This bounds memory usage
+            when copying directories containing enormous numbers of entries. Even without this
+            option, directories of more than 65536 entries are copied in batches of that size.
+
+        --no-follow-dest-symlinks
+            Refuse to follow symlinks in any component of DESTINATION_FILE or
+            DESTINATION_DIRECTORY, or when opening files inside the destination. Use this to
+            guard privileged copies into world-writable directories against symlink attacks.
+
+        --json-progress
+            Report progress on stdout as newline-delimited JSON events, for consumption by other
+            programs: a 'start' event with the total number of files and bytes to be copied,
+            periodic 'progress' events with the number copied so far, an 'error' event for each
+            file which couldn't be copied (with its path, file type, the failed operation, and
+            errno), and a final 'done' event.
+
+        --staging-dir DIRECTORY
+            Copy each regular file into DIRECTORY first, and only move it to its destination once
+            it is complete. Useful when copying to slow or unreliable destinations (e.g. network
+            mounts), where partially-copied files are especially undesirable. When DIRECTORY is
+            on a different filesystem than the destination, the move is itself performed as a
+            copy to a temporary file beside the destination which is then renamed into place.
+
+        --assume-ssd, --assume-hdd
+            Treat the source and destination as residing on solid-state storage or hard disk
+            drives respectively, rather than detecting this automatically (on Linux). When
+            copying from or to a hard disk drive, fcp copies only a couple of files at a time, as
+            copying many files in parallel would cause the disk to thrash between them. When
+            copying between NVMe devices, which can serve many requests at once, it copies with
+            two threads for each CPU rather than one.
+
+    -j N, --jobs N
+            Copy with a pool of N threads, rather than as many as suit the storage (see
+            --assume-ssd and --assume-hdd). Use this to stop fcp from saturating every core and the
+            storage of a shared machine.
+
+        --newer-than TIME
+            Copy only files modified after TIME, which is either an ISO 8601 timestamp (e.g.
+            2021-06-30 or 2021-06-30T12:30:00+02:00, in the local timezone unless an offset is
+            given) or the path of a file whose modification time should be used. Directories
+            are still searched, but are omitted if none of their contents are copied.
+
+        --skip-special-on-error
+            Warn about and skip symlinks, FIFOs, and device files which the destination doesn't
+            permit creating (e.g. on FAT32 filesystems or in restricted containers), rather than
+            treating this as an error. This doesn't apply when copying just a single such file.
+
+        --adaptive-buffer, --no-adaptive-buffer
+            When a file's contents have to be copied through a buffer (rather than by the kernel
+            or filesystem directly), start with a 128 KiB buffer and double it for as long as doing
+            so improves throughput, up to 8 MiB. Disabled by default.
+
+        --buffer-size SIZE
+            Copy file contents which have to be copied through a buffer (rather than by the kernel
+            or filesystem directly) through one of SIZE bytes rather than 128 KiB, with the
+            suffixes of --min-size (e.g. 4M). Larger buffers speed up copies over high-latency
+            links such as NFS or SMB mounts. With --adaptive-buffer, the buffer starts at SIZE.
+
+    -p
+            Same as --preserve=mode,ownership,timestamps, as with cp -p.
+
+        --owner
+            Same as --preserve=ownership: give every copy (including symlinks themselves) the
+            owner and group of its source, as when running as root to make system backups.
+
+        --preserve ATTRIBUTES
+            Preserve the given comma-separated file attributes, which may be:
+            'caps', which copies the capabilities of regular files on Linux (e.g.
+            cap_net_bind_service). This requires running as root; otherwise a warning is printed
+            for each file whose capabilities are dropped.
+            'context', which copies the SELinux security context of every file. With the
+            'selinux' feature, this goes through libselinux, so that the policy validates it;
+            otherwise, on Linux, the 'security.selinux' extended attribute is copied directly,
+            which the kernel still checks. This does nothing where SELinux is disabled.
+            'flags', which copies the file flags of every file on macOS and the BSDs (e.g. uchg,
+            nodump; see chflags(1)). These are set last, so that immutable copies can still be
+            written. Some (e.g. schg) can only be set as root.
+            'mode', which gives directories and special files exactly the permissions of their
+            sources, rather than those permissions masked by the umask (regular files always get
+            exactly their sources' permissions).
+            'overlay', which copies overlayfs layers faithfully on Linux: whiteouts (character
+            devices with device number 0, marking deleted files) are recreated rather than read,
+            and directories marked as opaque (with the trusted.overlay.opaque attribute) are
+            marked in the copy too. This requires running as root.
+            'ownership', which gives every copy the owner and group of its source. Without the
+            privileges to do so (i.e. unless running as root), failing to is silently ignored.
+            'timestamps', which gives every copy the access and modification times of its source.
+            'xattr', which copies the extended attributes of every file on Linux in the user and
+            trusted namespaces (e.g. user.checksum). Those in the trusted namespace can only be
+            read and written as root, and are otherwise left out.
+
+        --parents, --relative
+            Copy each SOURCE to its full path beneath DESTINATION_DIRECTORY (e.g. 'a/b/c.txt' to
+            'DESTINATION_DIRECTORY/a/b/c.txt'), creating any missing directories, rather than to
+            just its file name. This applies even when there is only one SOURCE. Absolute paths
+            are treated as relative to the root directory, and paths containing '..' are rejected.
+            Each directory created which corresponds to a source directory (e.g. 'a' and 'a/b')
+            is given that directory's mode, owner, and times once everything has been copied.
+
+        --parent-mode MODE
+            With --parents, give any other directories created the octal MODE (e.g. 750), which
+            is set regardless of the umask, rather than 755 as modified by the umask.
+
+        --max-entries N
+            Abort the copy once N entries (files, directories, etc.) have been copied, as a
+            safeguard when copying potentially enormous or pathological trees.
+
+        --timeout SECONDS
+            Abort the copy once SECONDS seconds have elapsed. Copies already in progress are
+            allowed to finish, but no new ones are started.
+
+    -t, --to DESTINATION_DIRECTORY
+            Copy into DESTINATION_DIRECTORY, treating every operand as a SOURCE. This may be
+            given more than once, in which case the contents of each SOURCE are read only once
+            while being written to all of the destinations. If copying to one destination fails,
+            the error is reported and copying to the others continues. Cannot be given more than
+            once together with --staging-dir, --newer-than, --parents, --no-clobber,
+            --ignore-existing, --interactive, or --attrs-only.
+
+    -n, --no-clobber
+            Skip files which already exist in the destination rather than overwriting them, but
+            copy into directories which already exist, filling in whatever they're missing. The
+            paths skipped are reported by --explain, and counted by --summary-only.
+
+        --ignore-existing
+            Skip every path which already exists in the destination, including directories and
+            thus everything beneath them, so that only wholly new paths are created (as with
+            rsync's option of the same name).
+
+    -i, --interactive
+            Ask before overwriting each file which already exists in the destination, skipping it
+            unless the answer begins with 'y', and copy into directories which already exist as
+            with --no-clobber. Prompts are written to stderr and answered on stdin, one at a time
+            however many files are being copied at once.
+
+        --dirs-only
+            Recreate only the directories of each SOURCE (with their permissions), skipping
+            everything else.
+
+        --skeleton
+            Recreate the directories of each SOURCE, create its regular files and device files
+            empty (with their permissions), and copy its symlinks and FIFOs as usual.
+
+        --order ORDER
+            Dispatch the entries of each directory to be copied in ORDER, which is one of
+            'largest-first', 'smallest-first', or 'name', rather than in the order in which they
+            are listed (which is the fastest, as it avoids sorting and, for the size-based
+            orders, statting every entry up front). Copying the largest files first tends to
+            have parallel copies finish more uniformly, and so makes progress smoother.
+            Entries of the same size are ordered by name.
+
+        --stable-order
+            Dispatch the entries of every directory in the same order on every run: by name,
+            unless --order is given. Entries are still copied in parallel, so they may finish in
+            a different order, but each is started no later than those after it. Each directory
+            is listed in full before any of it is copied, even if it's huge, so this cannot be
+            used with --readdir-batch.
+
+        --batch-bytes SIZE
+            Copy the small files of each directory (along with its symlinks and special files)
+            in batches of up to SIZE bytes (or 128 entries) each, with the suffixes of --min-size,
+            rather than 1 MiB. Each batch is copied by a single thread, which saves dispatching
+            every file on its own when copying huge numbers of tiny files. With 0, every file is
+            dispatched on its own. Batching doesn't apply with --order or --stable-order.
+
+        --sparse WHEN
+            With 'auto', recreate the holes of regular files which have them, finding their data
+            with SEEK_DATA and SEEK_HOLE (on Linux and FreeBSD) and copying only that, so that
+            copies are as sparse as their sources. With 'always', copy the contents of regular
+            files through a buffer, seeking over any 4 KiB blocks which are entirely zero rather
+            than writing them, so that they become holes in the destination. This makes copies
+            sparser than their sources wherever they contain runs of literal zeroes, at the cost
+            of scanning every block and of not using copy_file_range or copyfile. With 'never'
+            (the default), holes are copied as zeroes. Either way, the summary printed by
+            --summary-only then also gives the number of bytes which were actually written.
+
+        --attrs-only
+            Rather than copying anything, walk each SOURCE alongside an existing copy of it at
+            the destination, and give each destination path the ownership, permissions, and
+            access and modification times of the corresponding source path. It's an error for a
+            source path to have no counterpart in the destination. Use this to repair the
+            metadata of a copy made by a tool which didn't preserve it.
+
+        --summary-only
+            Print nothing while copying but errors and warnings, and then a single line giving the
+            number of files and bytes copied and the number of errors. This overrides --explain,
+            and is itself overridden by --json-progress, whose final 'done' event summarizes the
+            copy.
+
+        --error-on-case-collision
+            When the destination matches names case-insensitively (as APFS and exFAT do by
+            default), entries of a directory whose names differ only in case (e.g. 'File.txt' and
+            'file.txt') would overwrite one another. fcp warns about each such collision, and with
+            this option instead treats it as an error and copies only the first of the colliding
+            entries. With --readdir-batch, and in directories of more than 65536 entries, only
+            entries in the same batch are compared.
+
+        --fsync
+            Flush each copied file to storage with fsync before considering it copied, and each
+            copied directory once everything in it has been copied (which is what makes the names
+            of the files within it durable). With --staging-dir, the directory each file is moved
+            into is also flushed after the move. This guards against a crash, or the server of a
+            network filesystem failing, losing a copy which fcp reported as complete, but makes
+            copying considerably slower.
+
+        --io-uring
+            Copy the contents of the small regular files (of up to 64 KiB) in each directory
+            together through io_uring, opening, reading, writing, and closing all of them with a
+            handful of system calls, rather than several for each file. This speeds up copying
+            directories of many tiny files. It requires Linux 5.6 or later and fcp to have been
+            built with the 'io_uring' feature, and is ignored with --attrs-only, --dirs-only,
+            --skeleton, --staging-dir, --reflink, --sparse, --direct, --fsync, --max-entries,
+            --timeout, and --bwlimit. Where io_uring is unavailable, files are copied as usual.
+
+        --min-size SIZE, --max-size SIZE
+            Skip regular files smaller or larger than SIZE bytes respectively. SIZE may have one of
+            the suffixes K, M, G, or T (e.g. 100M), which multiply it by powers of 1024.
+            Directories are still searched for files within the range. The summary printed by
+            --summary-only then also gives the number of files skipped for their size.
+
+        --progress tree
+            Print a line to stderr every second giving the percentage of all bytes copied so far,
+            along with the percentage copied of each SOURCE which has been started but not yet
+            finished, to show which part of a large copy is holding it up. This may be combined
+            with --json-progress, but is hidden by --summary-only.
+
+        --sockets ACTION
+            What to do with sockets, which is one of 'error' (the default), to fail to copy them;
+            'skip', to warn about and skip them; or 'recreate', to create a socket node in the
+            destination. A recreated socket is only the filesystem's record of one, which nothing
+            is listening on. With --skip-special-on-error, a socket node which the destination
+            doesn't permit creating is skipped with a warning.
+
+        --mechanism-for GLOB=MECHANISM
+            Copy the contents of regular files whose names match GLOB (in which '*' matches any run
+            of characters and '?' any one character, e.g. '*.iso') by MECHANISM, rather than by
+            whichever is fastest. MECHANISM is 'buffered' (read/write through userspace), 'mmap'
+            (mapping the source into memory and writing it out, which can be faster for large files
+            already in the page cache, but kills fcp with SIGBUS should a source shrink while being
+            copied), 'reflink' on Linux and macOS (cloning, as --reflink=always does), or
+            'copy_file_range' or 'sendfile' on Linux, or 'copyfile' on macOS. A forced
+            copy_file_range or sendfile fails where it's unsupported rather than falling back (as
+            copy_file_range otherwise does to sendfile, and sendfile to 'buffered'), as does a
+            forced reflink where the file can't be cloned. May be given more than once, in which
+            case the first rule matching a file applies. Rules don't apply when copying to more
+            than one destination, as the contents are then always buffered.
+
+        --config PATH
+            Read default options from the config file at PATH, rather than from the first of
+            '.fcp.toml' in the current directory and '$XDG_CONFIG_HOME/fcp/config.toml' (or
+            '~/.config/fcp/config.toml') which exists. Each line of a config file is blank, a '#'
+            comment, or 'option = value', where 'option' is the name of one of these options
+            without its leading dashes, and 'value' is true or false (for options taking no
+            value), an integer, a double-quoted string, or a single-line array of these (for
+            options which may be given more than once). For example:
+                summary-only = true
+                mechanism-for = [\"*.iso=buffered\", \"*.img=buffered\"]
+            Options given on the command line override those in the config file.
+
+        --no-config
+            Don't read default options from any config file.
+
+        --no-recursive
+            Refuse to copy directories, reporting an error for each SOURCE which is a directory
+            (as cp does without -R) and copying the rest, rather than copying directories
+            recursively.
+
+        --log-file PATH
+            Write a record of what became of every entry processed to PATH: one JSON object per
+            line, of the form {\"timestamp\":N,\"action\":S,\"path\":S,\"result\":S}. The action is
+            'copied', 'skipped' (with the reason as the result), or 'failed' (with the error as
+            the result, and an additional \"errno\" field). PATH is truncated first, and each line
+            is written as soon as its entry has been processed, so the log is complete even if
+            the copy is interrupted.
+
+        --one-file-system
+            Don't copy the contents of directories on other filesystems than their parents (i.e.
+            mount points within a SOURCE). Each such mount point is still copied as an empty
+            directory with its attributes, so that the copy has somewhere to mount it too.
+
+        --progress-to-fd FD
+            Write the events of --json-progress (which this implies) to the file descriptor FD,
+            which must already be open for writing (e.g. one end of a pipe set up by a parent
+            process), rather than to stdout.
+
+        --files-from FILE
+            Read further SOURCEs from FILE (or stdin, if FILE is '-'), one per line, ignoring blank
+            lines. They're copied after any given as operands, so that with --to (or -t) no SOURCE
+            operands are needed at all.
+
+        --context CONTEXT
+            Give every copy the SELinux security context CONTEXT, which the policy may reject.
+            Like --preserve=context, this uses libselinux when fcp was built with the 'selinux'
+            feature, and does nothing on systems where SELinux is disabled.
+
+        --report-sizes
+            Once copying has finished, print a line 'SOURCE: N files, B bytes' for each SOURCE,
+            giving how much was copied from within it (directories themselves aren't counted).
+            Like --summary-only, this is overridden by --json-progress.
+
+        --swap
+            Replace DESTINATION with a copy of the single SOURCE (typically a directory) as a
+            whole, rather than copying into it: SOURCE is copied to a hidden path beside
+            DESTINATION, which is only renamed into place once the copy is complete, so readers
+            never see a partial copy. If the copy fails or is cut short (e.g. by SIGINT), the
+            partial copy is removed and DESTINATION is left unchanged. As a non-empty directory
+            can't be renamed over another, the old DESTINATION is first renamed aside (and then
+            removed), so for the instant between the two renames nothing is at DESTINATION.
+
+        --ignore-errors-matching [GLOB=]ERRNO
+            Report errors whose OS error is ERRNO (a name like 'EACCES', or a number) only as
+            warnings, which don't affect the exit status. With GLOB, only errors for files whose
+            paths match GLOB (in which '*' matches any run of characters, including '/', and '?'
+            any one character, e.g. '/proc/*=EACCES') are ignored. May be given more than once.
+
+        --dry-run[=check]
+            List what would be copied, as lines of the form 'SOURCE' -> 'DESTINATION', without
+            copying anything. With 'check', also report any problems predicted for the copy (and
+            exit with status 1 if there are any): destinations already in the way, directories
+            to copy into which are missing or not writable, and destinations without enough free
+            space. Destinations which would be overwritten or skipped are noted in the listing.
+            Only --no-recursive, --newer-than, --min-size, and --max-size are taken into account
+            in deciding what would be copied.
+
+        --flatten
+            Copy every regular file found anywhere under the SOURCEs directly into DESTINATION,
+            which is created if it doesn't exist, rather than recreating the structure of the
+            tree (e.g. 'logs/a/x.log' to 'DESTINATION/x.log'). Directories are traversed but not
+            copied, and anything else is skipped.
+
+        --on-collision POLICY
+            With --flatten, what to do with a file whose name is the same as one already copied
+            (files are taken from each SOURCE in turn, in order of their paths): 'error' (the
+            default) to report an error, 'rename' to copy it under the name with the lowest
+            numeric suffix not yet taken (e.g. 'x-1.log'), or 'skip' to skip it with a warning.
+
+        --mtime-from FORMAT
+            Set the modification time of each regular file copied to the time in the local
+            timezone encoded in its name according to FORMAT, in which '%Y' matches a 4-digit
+            year, '%m', '%d', '%H', '%M', and '%S' 2-digit months, days, hours, minutes, and
+            seconds, '%%' a literal '%', and anything else itself (e.g. '%Y-%m-%d' for
+            'backup-2021-06-30.tar'). FORMAT must contain at least '%Y', '%m', and '%d', and is
+            matched at the first position in the name where it can be. Files whose names don't
+            match keep the modification time they would otherwise have.
+
+        --progress-interval MILLISECONDS
+            How often to report progress with --json-progress and --progress=tree, rather than
+            every 250 milliseconds for the former and every second for the latter. Longer
+            intervals keep the output manageable over slow connections, and shorter ones make it
+            smoother.
+
+        --direct
+            Copy the contents of regular files with O_DIRECT on Linux, bypassing the page cache
+            (on filesystems which support it), through a buffer aligned as it requires. This can
+            be slower, especially for small files, but a one-off copy of a dataset larger than
+            memory then doesn't evict everything else from the cache. It's ignored for files
+            copied with --sparse or a mechanism forced by --mechanism-for.
+
+        --no-preallocate
+            Don't reserve the storage for each regular file of at least 1 MiB before copying its
+            contents, as is otherwise done with fallocate on Linux (where the filesystem supports
+            it). Preallocating lets the filesystem lay out the copy contiguously, and makes a copy
+            which won't fit fail before anything is written rather than partway through. It's
+            never done for files copied with --sparse, whose holes it would fill.
+
+        --bwlimit RATE
+            Copy the contents of regular files at no more than RATE bytes per second in total,
+            across all of the files being copied at once, with the suffixes of --min-size (e.g.
+            50M). Use this to run fcp on busy machines without starving everything else of I/O.
+            Files which are cloned (with --reflink) aren't limited, as nothing is copied.
+
+        --split SIZE
+            Copy each regular file of at least SIZE bytes (with the suffixes of --min-size) in
+            ranges, one for each of fcp's threads (but between 1 MiB and 1 GiB), which are
+            copied concurrently, with copy_file_range on Linux and otherwise through a buffer.
+            Without this, each file is copied by a single thread, so copying one huge file is no
+            faster with more CPUs. It's ignored for files copied with --sparse or --direct, or
+            with a mechanism other than copy_file_range or buffered forced by --mechanism-for.
+
+        --reflink WHEN
+            With 'auto', clone each regular file (with the FICLONE ioctl on Linux, or clonefile
+            on macOS), so that the copy shares the source's storage until either is modified,
+            where the filesystem supports it (e.g. Btrfs, XFS, or APFS), and otherwise copy its
+            contents as usual. With 'always', fail to copy any file which can't be cloned (e.g.
+            because the filesystem doesn't support it, or the source and destination are on
+            different filesystems) rather than copying its contents, which verifies that
+            copy-on-write is actually used. With 'never' (the default), files are copied as
+            usual. Cloning takes precedence over --sparse, --direct, and --mechanism-for, and is
+            ignored when copying to multiple destinations.
+
+        --reflink-or-fail
+            The same as --reflink=always.
+
+        --record-only MANIFEST
+            Record the structure of the SOURCEs in MANIFEST rather than copying them, in which case
+            there is no DESTINATION. Each entry is written as a line of the form
+            {\"path\":S,\"type\":S,\"mode\":S,\"uid\":N,\"gid\":N,\"size\":N,\"atime\":N,\"mtime\":N,
+            \"target\":S,\"rdev\":N,\"hash\":S}, where the path is relative to where the entry would
+            be copied to, the mode is in octal, the times are in seconds since the Unix epoch,
+            and the target (of symlinks), rdev (of device files), and hash (a 64-bit FNV-1a hash
+            of the contents of regular files, in hexadecimal) are otherwise null. Directories
+            are followed by their entries, in order of name, so the same tree is always recorded
+            the same way and two trees can be compared by diffing their manifests. Only
+            --parents, --no-recursive, --one-file-system, --newer-than, --min-size, and
+            --max-size are taken into account in deciding what's recorded.
+
+        --no-acls
+            Don't copy POSIX ACLs (as set with setfacl), which are otherwise copied on Linux for
+            every file which has them: the access ACL of every file, and the default ACL of every
+            directory. Copying a file with ACLs to a filesystem which doesn't support them is an
+            error without this option.
+
+EXIT STATUS:
+    0   All files were copied successfully.
+    1   An error occurred.
+    130 Interrupted by SIGINT (e.g. Ctrl-C). Copies already in progress are completed, but no
+        new ones are started."
 );
 
 static VERSION: &str = env!("CARGO_PKG_VERSION");
 
+fn fatal(message: impl Display) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
 fn main() {
-    let args: Box<_> = env::args().skip(1).collect();
+    let args: Box<[String]> = env::args().skip(1).collect();
     for arg in args.iter() {
         match arg.as_str() {
             "-h" | "--help" => fatal(HELP),
@@ -32,5 +470,10 @@ fn main() {
             _ => {}
         }
     }
-    process::exit(fcp(&args) as i32);
+    fcp::handle_interrupts().unwrap_or_else(|err| fatal(err));
+    let has_err = fcp(&args).unwrap_or_else(|err| fatal(err));
+    if fcp::interrupted() {
+        process::exit(130);
+    }
+    process::exit(has_err as i32);
 }
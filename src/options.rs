@@ -0,0 +1,1132 @@
+//! Parsing of `fcp`'s command-line options into the settings which control how a copy is
+//! performed.
+
+use crate::config;
+use crate::error::{Error, Result};
+use crate::filesystem::{self as fs, ContentOptions, CopyMechanism, Reflink, Sparse, Throttle};
+use nix::fcntl::OFlag;
+use nix::libc;
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::Metadata;
+use std::mem;
+use std::ops;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Settings controlling how `fcp` copies files.
+#[derive(Debug, Default)]
+pub struct CopyOptions {
+    /// Report the mechanism used to copy each regular file (e.g. `copy_file_range`).
+    pub explain: bool,
+    /// Dispatch the entries of each directory in batches of this size as they're read, rather
+    /// than reading the entire directory before copying any of it.
+    pub readdir_batch: Option<usize>,
+    /// Refuse to follow symlinks anywhere in the destination path, or when opening files within
+    /// the destination. This guards privileged copies into world-writable directories against
+    /// symlink attacks.
+    pub no_follow_dest_symlinks: bool,
+    /// Emit the copy's progress to stdout as a stream of newline-delimited JSON events (see the
+    /// `progress` module for their format).
+    pub json_progress: bool,
+    /// Copy each regular file into this directory first, and only move it to its destination
+    /// once it's complete, so that partially-copied files never appear in the destination.
+    pub staging_dir: Option<PathBuf>,
+    /// The kind of storage being copied from and to, overriding automatic detection.
+    pub storage: Option<Storage>,
+    /// The number of threads to copy with, rather than that suiting the kind of storage (see
+    /// `Storage`).
+    pub jobs: Option<usize>,
+    /// Copy only files modified after this time, omitting directories which would be left empty.
+    pub newer_than: Option<SystemTime>,
+    /// Set the modification time of each regular file copied to the time encoded in its name
+    /// according to this format (see `time_from_name`), if its name contains one.
+    pub mtime_from: Option<String>,
+    /// Warn about and skip symlinks, FIFOs, and device files which the destination doesn't
+    /// permit creating, rather than treating this as an error.
+    pub skip_special_on_error: bool,
+    /// When copying a file's contents through userspace, grow the buffer for as long as doing so
+    /// improves throughput, rather than using a buffer of fixed size.
+    pub adaptive_buffer: bool,
+    /// The size of the buffer through which the contents of regular files are copied, where they
+    /// have to be copied through userspace, rather than `filesystem::BUFFER_SIZE`.
+    pub buffer_size: Option<usize>,
+    /// Give every copy exactly its source's permissions, as regular files already are, rather
+    /// than those of directories and special files being subject to the umask.
+    pub preserve_mode: bool,
+    /// Give every copy its source's access and modification times.
+    pub preserve_timestamps: bool,
+    /// Give every copy its source's owner and group, where permitted. Without the privileges to
+    /// do so (i.e. unless running as root), failing to is silently ignored, as with `cp -p`.
+    pub preserve_ownership: bool,
+    /// Copy the extended attributes of every file in the `user` and `trusted` namespaces (see
+    /// `filesystem::xattrs`), the latter of which can only be read and written as root.
+    pub preserve_xattr: bool,
+    /// Copy the BSD file flags (see `chflags(2)`) of every file, such as `uchg` and `nodump`,
+    /// after everything else about it, since an immutable file can't be changed further. This
+    /// does nothing on systems without file flags (e.g. Linux).
+    pub preserve_flags: bool,
+    /// Don't copy POSIX ACLs, which are otherwise copied for every file which has them, for
+    /// destinations on filesystems which don't support them.
+    pub no_acls: bool,
+    /// Copy the capabilities (see `capabilities(7)`) of regular files, which requires running as
+    /// root. Otherwise a warning is printed for each file whose capabilities are dropped.
+    pub preserve_capabilities: bool,
+    /// Recreate overlayfs whiteouts as such, rather than copying them as character devices, and
+    /// copy the attribute marking directories as opaque, so that overlayfs layers can be copied.
+    /// This requires running as root.
+    pub preserve_overlay: bool,
+    /// Copy the SELinux security context of every file, through libselinux so that the policy
+    /// validates it. This requires the `selinux` feature, and does nothing if SELinux is disabled.
+    pub preserve_security_context: bool,
+    /// Give every copy this SELinux security context, rather than the default for its location
+    /// (or its source's, with `preserve_security_context`). This also requires the `selinux`
+    /// feature.
+    pub security_context: Option<String>,
+    /// Copy each source to its full path beneath the destination directory (e.g. `a/b/c.txt` to
+    /// `dest/a/b/c.txt`), creating any missing parent directories, instead of just its file name.
+    pub parents: bool,
+    /// The mode of the directories created by `parents` which don't correspond to a source
+    /// directory, set regardless of the umask. Otherwise they're created with mode 0o755, subject
+    /// to the umask. Those which do correspond to one get its mode, owner, and times instead.
+    pub parent_mode: Option<u32>,
+    /// Abort the copy once this many entries have been copied, as a safeguard against
+    /// pathologically large trees.
+    pub max_entries: Option<usize>,
+    /// Abort the copy once this much time has elapsed.
+    pub timeout: Option<Duration>,
+    /// The directories to copy into, given with `--to`, in which case every operand is a source.
+    /// With more than one, each source is read only once while being copied to all of them.
+    pub to: Vec<PathBuf>,
+    /// What to do with destination paths which already exist, rather than simply overwriting
+    /// them.
+    pub existing: Option<Existing>,
+    /// Replicate only the structure of the source rather than the contents of its files.
+    pub skeleton: Option<Skeleton>,
+    /// The order in which the entries of each directory are dispatched to be copied, rather than
+    /// the order in which they're listed.
+    pub order: Option<Order>,
+    /// Dispatch the entries of every directory in the same order on every run: by name, unless
+    /// `order` is given. Each directory is listed in full before any of it is copied, so this
+    /// can't be used with `readdir_batch`.
+    pub stable_order: bool,
+    /// The most bytes of small files which are copied together by a single task, rather than
+    /// `BATCH_BYTES`. Zero copies every entry as a task of its own.
+    pub batch_bytes: Option<u64>,
+    /// Make holes in the copies of regular files, either where their sources have them (`Auto`)
+    /// or wherever they have blocks which are entirely zero (`Always`). `None` copies the holes
+    /// as zeroes.
+    pub sparse: Option<Sparse>,
+    /// Copy the contents of regular files with `O_DIRECT`, bypassing the page cache, where the
+    /// filesystems support it. This is ignored for files copied sparsely or by a forced mechanism.
+    pub direct: bool,
+    /// Don't preallocate the storage for the copies of large regular files before copying their
+    /// contents.
+    pub no_preallocate: bool,
+    /// Copy regular files of at least this many bytes in ranges, concurrently, so that a single
+    /// large file isn't copied by just one thread.
+    pub split: Option<u64>,
+    /// Clone regular files, so that the copies share the sources' storage, either where the
+    /// filesystem supports it (`Auto`) or failing to copy any which can't be cloned rather than
+    /// copying their contents (`Always`). `None` copies their contents as usual.
+    pub reflink: Option<Reflink>,
+    /// Rather than copying anything, apply the ownership, permissions, and times of each source
+    /// path to the corresponding path in an existing destination tree.
+    pub attrs_only: bool,
+    /// Print nothing while copying except for errors, then a single line summarizing how many
+    /// files and bytes were copied and how many errors occurred. This overrides `explain`, and is
+    /// itself overridden by `json_progress`, whose `done` event already summarizes the copy.
+    pub summary_only: bool,
+    /// Treat entries whose names differ only in case, and so would overwrite one another in a
+    /// case-insensitive destination, as errors rather than just warning about them.
+    pub error_on_case_collision: bool,
+    /// Compute the name under which each file is copied into its destination directory from the
+    /// file's source path, rather than just reusing its name (see `PathMapper`). This takes
+    /// precedence over `parents`. It can only be set by programs using `fcp` as a library.
+    pub path_mapper: Option<PathMapper>,
+    /// Flush each copied file and directory to storage before considering it complete, so that a
+    /// crash (or a network filesystem's server failing) can't undo a copy which was reported done.
+    pub fsync: bool,
+    /// Skip regular files smaller than this many bytes.
+    pub min_size: Option<u64>,
+    /// Skip regular files larger than this many bytes.
+    pub max_size: Option<u64>,
+    /// Periodically print the overall progress of the copy to stderr, along with that of each
+    /// source being copied (`--progress=tree`).
+    pub progress_tree: bool,
+    /// How often progress is reported by `json_progress` and `progress_tree`, rather than every
+    /// 250 milliseconds for the former and every second for the latter.
+    pub progress_interval: Option<Duration>,
+    /// What to do with sockets, rather than failing to copy them.
+    pub sockets: Option<Sockets>,
+    /// Rules forcing the mechanism by which the contents of regular files whose names match a
+    /// glob are copied, as `(glob, mechanism)` pairs. The first rule to match a file applies, and
+    /// files matching none are copied by whichever mechanism is fastest.
+    pub mechanisms: Vec<(String, CopyMechanism)>,
+    /// Refuse to copy directories, as `cp` does without `-R`, rather than copying them
+    /// recursively.
+    pub no_recursive: bool,
+    /// Log what became of every entry processed to this file (see the `log` module).
+    pub log_file: Option<PathBuf>,
+    /// Don't descend into directories on other filesystems than the directories containing them,
+    /// though the mount points themselves are still copied, as empty directories.
+    pub one_file_system: bool,
+    /// Write the JSON progress events to this already-open file descriptor, rather than to stdout.
+    pub progress_fd: Option<RawFd>,
+    /// Read further sources from this file, one per line (or from stdin if it's `-`).
+    pub files_from: Option<PathBuf>,
+    /// Once copying has finished, print the number of files and bytes copied from each source.
+    pub report_sizes: bool,
+    /// Copy the single source beside the destination, and only once that's complete swap it into
+    /// place, replacing the destination rather than copying into it.
+    pub swap: bool,
+    /// Errors to report only as warnings, which don't make the copy fail, as `(errno, glob)`
+    /// pairs. An error is ignored if its OS error code is `errno` and (if there's a glob) the path
+    /// of the file it occurred for matches the glob.
+    pub ignored_errors: Vec<(i32, Option<String>)>,
+    /// List what would be copied rather than copying anything (see the `dry_run` module).
+    pub dry_run: Option<DryRun>,
+    /// Record the structure of the sources in this manifest rather than copying anything, in
+    /// which case there are no destinations (see the `record` module).
+    pub record_only: Option<PathBuf>,
+    /// Decides what to do about each error in copying a file (see `ErrorHandler`). It can only be
+    /// set by programs using `fcp` as a library.
+    pub on_error: Option<ErrorHandler>,
+    /// Cancels the copy once set (e.g. from another thread), after which no further copies are
+    /// started, those in progress are stopped, and `fcp_with_options` returns an error of kind
+    /// `ErrorKind::Cancelled`. A regular file stopped partway is removed, rather than being left
+    /// incomplete. It's checked before each entry is copied, and while copying a regular file,
+    /// after every buffer copied through userspace or every 16 MiB copied in the kernel. Files
+    /// cloned, or copied with `copyfile(3)` on macOS, are copied all at once. It can only be set
+    /// by programs using `fcp` as a library.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Limit the rate at which the contents of regular files are copied, in aggregate across
+    /// every thread copying them.
+    pub throttle: Option<Arc<Throttle>>,
+    /// Copy every regular file found anywhere under the sources directly into the destination
+    /// directory (see the `flatten` module).
+    pub flatten: bool,
+    /// With `flatten`, what to do with files whose names are the same as one already copied,
+    /// rather than treating them as errors.
+    pub on_collision: Option<Collision>,
+    /// Copy the contents of the small regular files in each directory together through io_uring
+    /// (see the `uring` module), rather than one at a time. This requires the `io_uring` feature,
+    /// and is only used where nothing else about a copy requires files to be copied one at a
+    /// time (e.g. `staging_dir` or `sparse`). Where io_uring is unavailable, files are copied as
+    /// usual.
+    pub io_uring: bool,
+}
+
+/// A function from the path of a file being copied into a directory to the path relative to that
+/// directory at which it should be copied, for renaming files as they're copied (e.g. to lower
+/// case). It's called for the sources given to `fcp_with_options` as well as for every entry of
+/// the directories among them, whose new paths determine where their own entries go in turn.
+pub struct PathMapper(pub Box<dyn Fn(&Path) -> PathBuf + Send + Sync>);
+
+impl PathMapper {
+    pub fn map(&self, source: &Path) -> PathBuf {
+        (self.0)(source)
+    }
+}
+
+impl fmt::Debug for PathMapper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("PathMapper")
+    }
+}
+
+/// How much copying some sources would create, as estimated by `fcp::estimate`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CopyEstimate {
+    /// The number of regular files.
+    pub files: u64,
+    /// The total size of the regular files in bytes.
+    pub bytes: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    /// The number of FIFOs, sockets, and device files.
+    pub special: u64,
+}
+
+impl ops::Add for CopyEstimate {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        CopyEstimate {
+            files: self.files + other.files,
+            bytes: self.bytes + other.bytes,
+            directories: self.directories + other.directories,
+            symlinks: self.symlinks + other.symlinks,
+            special: self.special + other.special,
+        }
+    }
+}
+
+/// A failure to copy a file, as given to an `ErrorHandler`.
+#[derive(Debug)]
+pub struct CopyError<'a> {
+    /// The path of the file which couldn't be copied.
+    pub source: &'a Path,
+    pub error: &'a Error,
+    /// How many times copying the file has been attempted, including this one.
+    pub attempt: u32,
+}
+
+/// What to do about a failure to copy a file, as decided by an `ErrorHandler`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorAction {
+    /// Report the error and carry on copying everything else, as `fcp` does by default.
+    Continue,
+    /// Copy the file again from scratch, without reporting the error. Whatever was created in the
+    /// failed attempt is left as it was, so e.g. retrying a directory fails if it was created.
+    Retry,
+    /// Report the error and start no further copies (those in progress are completed), after
+    /// which `fcp_with_options` returns an error.
+    Abort,
+}
+
+/// A function deciding what to do about each error in copying a file, in place of `fcp`'s usual
+/// policy of reporting it and carrying on. It's called after `--ignore-errors-matching` and
+/// existing destinations have been taken into account, from whichever thread copied the file.
+pub struct ErrorHandler(pub Box<dyn Fn(&CopyError) -> ErrorAction + Send + Sync>);
+
+impl ErrorHandler {
+    pub fn decide(&self, error: &CopyError) -> ErrorAction {
+        (self.0)(error)
+    }
+}
+
+impl fmt::Debug for ErrorHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ErrorHandler")
+    }
+}
+
+/// The kinds of storage device for which `fcp` adjusts its behavior.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Storage {
+    /// Solid-state storage, which benefits from copying many files in parallel.
+    Ssd,
+    /// Rotational storage (i.e. a hard disk drive), on which copying many files in parallel
+    /// causes the disk to thrash between them.
+    Hdd,
+    /// NVMe storage, which can serve more requests at once than there are CPUs to make them.
+    Nvme,
+}
+
+/// What a dry run does besides listing what would be copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DryRun {
+    /// Only list what would be copied (`--dry-run`).
+    List,
+    /// Also check whether the copy would succeed (`--dry-run=check`).
+    Check,
+}
+
+/// The ways in which `fcp` can avoid overwriting destination paths which already exist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Existing {
+    /// Skip existing files, but copy into existing directories, skipping whichever of their
+    /// children exist in turn (`--no-clobber`).
+    NoClobber,
+    /// Skip every existing path, including directories and thus everything beneath them, so that
+    /// only wholly new paths are created (`--ignore-existing`, as in rsync).
+    Ignore,
+    /// Ask before overwriting each existing file, skipping it unless the answer is yes, but copy
+    /// into existing directories as with `NoClobber` (`--interactive`).
+    Prompt,
+}
+
+/// The ways in which `fcp` can handle sockets other than treating them as errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sockets {
+    /// Warn about and skip each socket (`--sockets=skip`).
+    Skip,
+    /// Create a socket node in the destination, which isn't bound to anything and so can't be
+    /// connected to, but makes the copy of the tree faithful (`--sockets=recreate`).
+    Recreate,
+}
+
+/// The ways in which `fcp --flatten` can handle files with the same name other than treating
+/// them as errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Collision {
+    /// Copy each file under a name with the lowest numeric suffix not yet taken (e.g. `a-1.txt`
+    /// for `a.txt`) (`--on-collision=rename`).
+    Rename,
+    /// Warn about and skip each file (`--on-collision=skip`).
+    Skip,
+}
+
+/// How much of a source's structure is replicated when its file contents aren't copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Skeleton {
+    /// Recreate only directories, skipping everything else (`--dirs-only`).
+    DirsOnly,
+    /// Recreate directories, create regular files (and device files) empty, and copy symlinks
+    /// and FIFOs as usual (`--skeleton`).
+    EmptyFiles,
+}
+
+/// The orders in which the entries of a directory can be dispatched to be copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    /// Largest files first, which tends to have parallel copies finish more uniformly.
+    LargestFirst,
+    /// Smallest files first, which gets through the greatest number of files soonest.
+    SmallestFirst,
+    /// By file name.
+    Name,
+}
+
+impl CopyOptions {
+    /// How the contents of the regular file `source` should be copied.
+    pub fn content(&self, source: &Path) -> ContentOptions<'_> {
+        let name = source.file_name().unwrap_or_default().to_string_lossy();
+        let mechanism = self
+            .mechanisms
+            .iter()
+            .find(|(glob, _)| glob_matches(glob, &name))
+            .map(|&(_, mechanism)| mechanism);
+        // A rule forcing cloning does so as `--reflink=always` would, for the files it matches.
+        let (mechanism, reflink) = match mechanism {
+            Some(CopyMechanism::Clone) => (None, Some(Reflink::Always)),
+            mechanism => (mechanism, self.reflink),
+        };
+        ContentOptions {
+            adaptive_buffer: self.adaptive_buffer,
+            buffer_size: self.buffer_size,
+            sparse: self.sparse,
+            fsync: self.fsync,
+            direct: self.direct,
+            no_preallocate: self.no_preallocate,
+            split: self.split,
+            reflink,
+            cancel: self.cancel.as_deref(),
+            throttle: self.throttle.as_deref(),
+            mechanism,
+        }
+    }
+
+    /// Whether the copy has been cancelled (see `cancel`).
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+
+    /// Whether `err`, which occurred while copying `source`, is to be ignored (see
+    /// `ignored_errors`).
+    pub fn is_ignored(&self, source: &Path, err: &Error) -> bool {
+        let errno = match err.raw_os_error() {
+            Some(errno) => errno,
+            None => return false,
+        };
+        let path = source.to_string_lossy();
+        self.ignored_errors.iter().any(|(ignored, glob)| {
+            *ignored == errno && glob.as_ref().is_none_or(|glob| glob_matches(glob, &path))
+        })
+    }
+
+    /// Whether a file with `metadata`, which isn't a directory, is left out by `newer_than`,
+    /// `min_size`, or `max_size`.
+    pub fn is_filtered(&self, metadata: &Metadata) -> bool {
+        if let Some(threshold) = self.newer_than {
+            if metadata
+                .modified()
+                .is_ok_and(|modified| modified <= threshold)
+            {
+                return true;
+            }
+        }
+        metadata.is_file()
+            && (self.min_size.is_some_and(|min| metadata.len() < min)
+                || self.max_size.is_some_and(|max| metadata.len() > max))
+    }
+
+    /// The additional flags with which destination files should be opened.
+    pub fn dest_flags(&self) -> OFlag {
+        if self.no_follow_dest_symlinks {
+            OFlag::O_NOFOLLOW
+        } else {
+            OFlag::empty()
+        }
+    }
+}
+
+/// Parse the command-line arguments `args` into the options they specify and the operands given,
+/// preceded by the options in the config file (see the `config` module), which those on the
+/// command line therefore override.
+pub fn parse(args: &[String]) -> Result<(CopyOptions, Vec<PathBuf>)> {
+    let config_args = match config::find(args)? {
+        Some(path) => {
+            let config_args = config::args(&path)?;
+            // Parsing the config file's options alone first means that errors in them can be
+            // attributed to it.
+            parse_args(&config_args)
+                .map_err(|err| Error::new(format!("{}: {}", path.display(), err)))?;
+            config_args
+        }
+        None => Vec::new(),
+    };
+    parse_args(&[config_args, args.to_vec()].concat())
+}
+
+/// Split `args` into the options they specify and the remaining operands (i.e. the sources and
+/// the destination). An argument of `--` ends option processing, so that any following arguments
+/// are treated as operands even if they begin with a hyphen.
+fn parse_args(args: &[String]) -> Result<(CopyOptions, Vec<PathBuf>)> {
+    let mut options = CopyOptions::default();
+    let mut operands = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        // Options taking a value accept it either as the following argument or inline after an
+        // equals sign (e.g. both `--readdir-batch 100` and `--readdir-batch=100`).
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value)),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = || {
+            inline_value
+                .or_else(|| args.next().map(String::as_str))
+                .ok_or_else(|| Error::new(format!("Option '{}' requires a value", name)))
+        };
+        match name {
+            "--" => {
+                operands.extend(args.map(PathBuf::from));
+                break;
+            }
+            "--explain" => options.explain = true,
+            "--readdir-batch" => options.readdir_batch = Some(parse_count(name, value()?)?),
+            "--no-follow-dest-symlinks" => options.no_follow_dest_symlinks = true,
+            "--json-progress" => options.json_progress = true,
+            "--staging-dir" => options.staging_dir = Some(PathBuf::from(value()?)),
+            "--assume-ssd" => options.storage = Some(Storage::Ssd),
+            "--assume-hdd" => options.storage = Some(Storage::Hdd),
+            "--jobs" | "-j" => options.jobs = Some(parse_count(name, value()?)?),
+            "--newer-than" => options.newer_than = Some(parse_time(name, value()?)?),
+            "--mtime-from" => options.mtime_from = Some(parse_time_format(name, value()?)?),
+            "--skip-special-on-error" => options.skip_special_on_error = true,
+            "--adaptive-buffer" => options.adaptive_buffer = true,
+            "--no-adaptive-buffer" => options.adaptive_buffer = false,
+            "--buffer-size" => options.buffer_size = Some(parse_buffer_size(name, value()?)?),
+            "--no-clobber" | "-n" => options.existing = Some(Existing::NoClobber),
+            "--ignore-existing" => options.existing = Some(Existing::Ignore),
+            "--interactive" | "-i" => options.existing = Some(Existing::Prompt),
+            "--dirs-only" => options.skeleton = Some(Skeleton::DirsOnly),
+            "--skeleton" => options.skeleton = Some(Skeleton::EmptyFiles),
+            "--stable-order" => options.stable_order = true,
+            "--batch-bytes" => options.batch_bytes = Some(parse_size(name, value()?)?),
+            "--order" => {
+                options.order = Some(match value()? {
+                    "largest-first" => Order::LargestFirst,
+                    "smallest-first" => Order::SmallestFirst,
+                    "name" => Order::Name,
+                    value => {
+                        return Err(Error::new(format!(
+                            "Invalid value '{}' for option '{}': expected one of \
+                             'largest-first', 'smallest-first', or 'name'",
+                            value, name
+                        )))
+                    }
+                })
+            }
+            "--attrs-only" => options.attrs_only = true,
+            "--summary-only" => options.summary_only = true,
+            "--error-on-case-collision" => options.error_on_case_collision = true,
+            "--fsync" => options.fsync = true,
+            "--io-uring" => {
+                if !cfg!(all(feature = "io_uring", target_os = "linux")) {
+                    return Err(Error::new(format!(
+                        "Option '{}' is unavailable: fcp was built without io_uring support (the \
+                         'io_uring' feature, which is only supported on Linux)",
+                        name
+                    )));
+                }
+                options.io_uring = true;
+            }
+            "--no-recursive" => options.no_recursive = true,
+            "--no-acls" => options.no_acls = true,
+            "--no-preallocate" => options.no_preallocate = true,
+            "--log-file" => options.log_file = Some(PathBuf::from(value()?)),
+            "--record-only" => options.record_only = Some(PathBuf::from(value()?)),
+            "--one-file-system" => options.one_file_system = true,
+            "--report-sizes" => options.report_sizes = true,
+            "--swap" => options.swap = true,
+            "--dry-run" => {
+                options.dry_run = match inline_value {
+                    None => Some(DryRun::List),
+                    Some("check") => Some(DryRun::Check),
+                    Some(other) => {
+                        return Err(Error::new(format!(
+                            "Invalid value '{}' for option '{}': expected 'check'",
+                            other, name
+                        )))
+                    }
+                }
+            }
+            "--files-from" => options.files_from = Some(PathBuf::from(value()?)),
+            "--progress-to-fd" => options.progress_fd = Some(parse_fd(name, value()?)?),
+            "--sockets" => {
+                options.sockets = match value()? {
+                    "error" => None,
+                    "skip" => Some(Sockets::Skip),
+                    "recreate" => Some(Sockets::Recreate),
+                    value => {
+                        return Err(Error::new(format!(
+                            "Invalid value '{}' for option '{}': expected one of 'error', \
+                             'skip', or 'recreate'",
+                            value, name
+                        )))
+                    }
+                }
+            }
+            "--flatten" => options.flatten = true,
+            "--on-collision" => {
+                options.on_collision = match value()? {
+                    "error" => None,
+                    "rename" => Some(Collision::Rename),
+                    "skip" => Some(Collision::Skip),
+                    value => {
+                        return Err(Error::new(format!(
+                            "Invalid value '{}' for option '{}': expected one of 'error', \
+                             'rename', or 'skip'",
+                            value, name
+                        )))
+                    }
+                }
+            }
+            "--ignore-errors-matching" => options
+                .ignored_errors
+                .push(parse_ignored_error(name, value()?)?),
+            "--mechanism-for" => options
+                .mechanisms
+                .push(parse_mechanism_rule(name, value()?)?),
+            "--progress" => {
+                options.progress_tree = match value()? {
+                    "tree" => true,
+                    value => {
+                        return Err(Error::new(format!(
+                            "Invalid value '{}' for option '{}': expected 'tree'",
+                            value, name
+                        )))
+                    }
+                }
+            }
+            "--min-size" => options.min_size = Some(parse_size(name, value()?)?),
+            "--max-size" => options.max_size = Some(parse_size(name, value()?)?),
+            "--split" => options.split = Some(parse_size(name, value()?)?),
+            "--bwlimit" => {
+                let value = value()?;
+                let rate = parse_size(name, value)?;
+                if rate == 0 {
+                    return Err(Error::new(format!(
+                        "Invalid value '{}' for option '{}': expected a positive rate",
+                        value, name
+                    )));
+                }
+                options.throttle = Some(Arc::new(Throttle::new(rate)));
+            }
+            "--direct" => options.direct = true,
+            "--reflink-or-fail" => options.reflink = Some(Reflink::Always),
+            "--reflink" => {
+                options.reflink = match value()? {
+                    "auto" => Some(Reflink::Auto),
+                    "always" => Some(Reflink::Always),
+                    "never" => None,
+                    value => {
+                        return Err(Error::new(format!(
+                            "Invalid value '{}' for option '{}': expected 'auto', 'always', or \
+                             'never'",
+                            value, name
+                        )))
+                    }
+                }
+            }
+            "--sparse" => {
+                options.sparse = match value()? {
+                    "auto" => Some(Sparse::Auto),
+                    "always" => Some(Sparse::Always),
+                    "never" => None,
+                    value => {
+                        return Err(Error::new(format!(
+                            "Invalid value '{}' for option '{}': expected 'auto', 'always', or \
+                             'never'",
+                            value, name
+                        )))
+                    }
+                }
+            }
+            "--to" | "-t" => options.to.push(PathBuf::from(value()?)),
+            // These were already dealt with when reading the config file.
+            "--config" => {
+                value()?;
+            }
+            "--no-config" => {}
+            "--max-entries" => options.max_entries = Some(parse_count(name, value()?)?),
+            "--progress-interval" => {
+                let milliseconds = parse_count(name, value()?)?;
+                options.progress_interval = Some(Duration::from_millis(milliseconds as u64));
+            }
+            "--timeout" => {
+                let seconds = parse_count(name, value()?)?;
+                options.timeout = Some(Duration::from_secs(seconds as u64));
+            }
+            "--parents" | "--relative" => options.parents = true,
+            "--parent-mode" => options.parent_mode = Some(parse_mode(name, value()?)?),
+            "--owner" => options.preserve_ownership = true,
+            "-p" => {
+                options.preserve_mode = true;
+                options.preserve_timestamps = true;
+                options.preserve_ownership = true;
+            }
+            "--preserve" => {
+                for attribute in value()?.split(',') {
+                    match attribute {
+                        "mode" => options.preserve_mode = true,
+                        "timestamps" => options.preserve_timestamps = true,
+                        "ownership" => options.preserve_ownership = true,
+                        "xattr" => options.preserve_xattr = true,
+                        "flags" => options.preserve_flags = true,
+                        "caps" => options.preserve_capabilities = true,
+                        "overlay" => options.preserve_overlay = true,
+                        "context" => {
+                            require_selinux(name, "context")?;
+                            options.preserve_security_context = true;
+                        }
+                        _ => {
+                            return Err(Error::new(format!(
+                                "Invalid value '{}' for option '{}': expected a comma-separated \
+                                 list of attributes (supported: caps, context, flags, mode, \
+                                 overlay, ownership, timestamps, xattr)",
+                                attribute, name
+                            )))
+                        }
+                    }
+                }
+            }
+            "--context" => {
+                let context = value()?;
+                require_selinux(name, context)?;
+                if context.is_empty() || context.contains('\0') {
+                    return Err(Error::new(format!(
+                        "Invalid value '{}' for option '{}': expected an SELinux security context",
+                        context, name
+                    )));
+                }
+                options.security_context = Some(context.to_string());
+            }
+            option if option.starts_with('-') && option != "-" => {
+                return Err(Error::new(format!(
+                    "Unrecognized option '{}' (run 'fcp --help' for details)",
+                    option
+                )));
+            }
+            operand => operands.push(PathBuf::from(operand)),
+        }
+    }
+    Ok((options, operands))
+}
+
+fn parse_count(name: &str, value: &str) -> Result<usize> {
+    match value.parse() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(Error::new(format!(
+            "Invalid value '{}' for option '{}': expected a positive integer",
+            value, name
+        ))),
+    }
+}
+
+fn parse_mode(name: &str, value: &str) -> Result<u32> {
+    match u32::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(Error::new(format!(
+            "Invalid value '{}' for option '{}': expected an octal mode (e.g. 755)",
+            value, name
+        ))),
+    }
+}
+
+fn require_selinux(name: &str, value: &str) -> Result<()> {
+    if cfg!(any(feature = "selinux", target_os = "linux")) {
+        return Ok(());
+    }
+    Err(Error::new(format!(
+        "Invalid value '{}' for option '{}': SELinux is only supported on Linux",
+        value, name
+    )))
+}
+
+fn parse_fd(name: &str, value: &str) -> Result<RawFd> {
+    value.parse().ok().filter(|fd| *fd >= 0).ok_or_else(|| {
+        Error::new(format!(
+            "Invalid value '{}' for option '{}': expected a file descriptor",
+            value, name
+        ))
+    })
+}
+
+// Rules are given as `GLOB=MECHANISM`, where only the mechanisms which are available on this
+// platform are accepted.
+fn parse_mechanism_rule(name: &str, value: &str) -> Result<(String, CopyMechanism)> {
+    let linux = cfg!(any(target_os = "linux", target_os = "android"));
+    let mechanism = match value.rsplit_once('=') {
+        Some((glob, "copy_file_range")) if linux => Some((glob, CopyMechanism::CopyFileRange)),
+        Some((glob, "sendfile")) if linux => Some((glob, CopyMechanism::Sendfile)),
+        Some((glob, "copyfile")) if cfg!(target_os = "macos") => {
+            Some((glob, CopyMechanism::Copyfile))
+        }
+        Some((glob, "reflink")) if linux || cfg!(target_os = "macos") => {
+            Some((glob, CopyMechanism::Clone))
+        }
+        Some((glob, "buffered")) => Some((glob, CopyMechanism::Buffered)),
+        Some((glob, "mmap")) => Some((glob, CopyMechanism::Mmap)),
+        _ => None,
+    };
+    match mechanism {
+        Some((glob, mechanism)) if !glob.is_empty() => Ok((glob.to_string(), mechanism)),
+        _ => Err(Error::new(format!(
+            "Invalid value '{}' for option '{}': expected GLOB=MECHANISM, where MECHANISM is {}",
+            value,
+            name,
+            if linux {
+                "'copy_file_range', 'sendfile', 'reflink', 'buffered', or 'mmap'"
+            } else if cfg!(target_os = "macos") {
+                "'copyfile', 'reflink', 'buffered', or 'mmap'"
+            } else {
+                "'buffered' or 'mmap'"
+            }
+        ))),
+    }
+}
+
+/// The names of the errors which may be given to `--ignore-errors-matching`, besides their numbers.
+const ERRNO_NAMES: &[(&str, i32)] = &[
+    ("EPERM", libc::EPERM),
+    ("ENOENT", libc::ENOENT),
+    ("EIO", libc::EIO),
+    ("ENXIO", libc::ENXIO),
+    ("EACCES", libc::EACCES),
+    ("EBUSY", libc::EBUSY),
+    ("EEXIST", libc::EEXIST),
+    ("EXDEV", libc::EXDEV),
+    ("ENODEV", libc::ENODEV),
+    ("ENOTDIR", libc::ENOTDIR),
+    ("EISDIR", libc::EISDIR),
+    ("EINVAL", libc::EINVAL),
+    ("ETXTBSY", libc::ETXTBSY),
+    ("EFBIG", libc::EFBIG),
+    ("ENOSPC", libc::ENOSPC),
+    ("EROFS", libc::EROFS),
+    ("EMLINK", libc::EMLINK),
+    ("ENAMETOOLONG", libc::ENAMETOOLONG),
+    ("ELOOP", libc::ELOOP),
+    ("EOPNOTSUPP", libc::EOPNOTSUPP),
+    ("ENOTSUP", libc::ENOTSUP),
+    ("EDQUOT", libc::EDQUOT),
+];
+
+// Rules are given as `ERRNO` or `GLOB=ERRNO`, where `ERRNO` is either the name of an error (e.g.
+// `EACCES`) or its number.
+fn parse_ignored_error(name: &str, value: &str) -> Result<(i32, Option<String>)> {
+    let (glob, errno) = match value.rsplit_once('=') {
+        Some((glob, errno)) => (Some(glob), errno),
+        None => (None, value),
+    };
+    let errno = ERRNO_NAMES
+        .iter()
+        .find(|(errno_name, _)| errno_name.eq_ignore_ascii_case(errno))
+        .map(|&(_, errno)| errno)
+        .or_else(|| errno.parse().ok().filter(|errno| *errno > 0));
+    match (glob, errno) {
+        (Some(""), _) | (_, None) => Err(Error::new(format!(
+            "Invalid value '{}' for option '{}': expected ERRNO or GLOB=ERRNO, where ERRNO is an \
+             error's number or name (e.g. 'EACCES')",
+            value, name
+        ))),
+        (glob, Some(errno)) => Ok((errno, glob.map(str::to_string))),
+    }
+}
+
+// Whether `name` matches `glob`, in which `*` matches any run of characters and `?` matches any
+// one character.
+fn glob_matches(glob: &str, name: &str) -> bool {
+    let (glob, name) = (
+        glob.chars().collect::<Vec<_>>(),
+        name.chars().collect::<Vec<_>>(),
+    );
+    // The position just after the last `*`, and the position in `name` it's currently matching
+    // up to, to backtrack to when the rest of the glob fails to match.
+    let (mut star, mut g, mut n) = (None, 0, 0);
+    while n < name.len() {
+        match glob.get(g) {
+            Some('*') => {
+                star = Some((g + 1, n));
+                g += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                g += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((after_star, matched)) => {
+                    star = Some((after_star, matched + 1));
+                    g = after_star;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+// Sizes are given in bytes, optionally followed by one of the binary suffixes `K`, `M`, `G`, or
+// `T` (e.g. `100M` for 100 MiB).
+fn parse_size(name: &str, value: &str) -> Result<u64> {
+    let (digits, shift) = match value.char_indices().last() {
+        Some((index, suffix)) if suffix.is_ascii_alphabetic() => {
+            let shift = match suffix.to_ascii_uppercase() {
+                'K' => Some(10),
+                'M' => Some(20),
+                'G' => Some(30),
+                'T' => Some(40),
+                _ => None,
+            };
+            (&value[..index], shift)
+        }
+        _ => (value, Some(0)),
+    };
+    match (digits.parse::<u64>(), shift) {
+        (Ok(size), Some(shift)) if size.leading_zeros() >= shift => Ok(size << shift),
+        _ => Err(Error::new(format!(
+            "Invalid value '{}' for option '{}': expected a number of bytes, optionally followed \
+             by K, M, G, or T",
+            value, name
+        ))),
+    }
+}
+
+// A buffer must hold at least a byte, and fit in memory.
+fn parse_buffer_size(name: &str, value: &str) -> Result<usize> {
+    match parse_size(name, value)?.try_into() {
+        Ok(size) if size > 0 => Ok(size),
+        _ => Err(Error::new(format!(
+            "Invalid value '{}' for option '{}': expected a positive number of bytes which fits \
+             in memory",
+            value, name
+        ))),
+    }
+}
+
+// The time is given either as a timestamp, or as the path of a file whose modification time
+// should be used.
+fn parse_time(name: &str, value: &str) -> Result<SystemTime> {
+    match parse_timestamp(value) {
+        Some(time) => Ok(time),
+        None => fs::metadata(value)
+            .and_then(|metadata| Ok(metadata.modified()?))
+            .map_err(|err| {
+                Error::new(format!(
+                    "Invalid value '{}' for option '{}': expected an ISO 8601 timestamp or the \
+                     path of an existing file ({})",
+                    value, name, err
+                ))
+            }),
+    }
+}
+
+// Formats may contain the directives `%Y`, `%m`, `%d`, `%H`, `%M`, `%S`, and `%%`, and must
+// contain at least the first three of them.
+fn parse_time_format(name: &str, value: &str) -> Result<String> {
+    let invalid = |reason: &str| {
+        Err(Error::new(format!(
+            "Invalid value '{}' for option '{}': {}",
+            value, name, reason
+        )))
+    };
+    let mut directives = Vec::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            continue;
+        }
+        match chars.next() {
+            Some(directive @ ('Y' | 'm' | 'd' | 'H' | 'M' | 'S')) => directives.push(directive),
+            Some('%') => {}
+            Some(directive) => return invalid(&format!("unknown directive '%{}'", directive)),
+            None => return invalid("it ends with a lone '%'"),
+        }
+    }
+    if !['Y', 'm', 'd']
+        .iter()
+        .all(|directive| directives.contains(directive))
+    {
+        return invalid("expected a format containing at least '%Y', '%m', and '%d'");
+    }
+    Ok(value.to_string())
+}
+
+/// The time in the local timezone encoded in the file name `name` according to `format`, in
+/// which `%Y` matches a 4-digit year, `%m`, `%d`, `%H`, `%M`, and `%S` 2-digit months, days,
+/// hours, minutes, and seconds, `%%` a literal `%`, and anything else itself. The time is that
+/// found at the first position in `name` where `format` matches, if there is one.
+pub(crate) fn time_from_name(format: &str, name: &str) -> Option<SystemTime> {
+    name.char_indices()
+        .find_map(|(start, _)| match_time_format(format, &name[start..]))
+}
+
+// The time encoded at the very start of `name` according to `format`, if it matches there.
+fn match_time_format(format: &str, name: &str) -> Option<SystemTime> {
+    let mut fields = [0, 1, 1, 0, 0, 0];
+    let mut rest = name;
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            rest = rest.strip_prefix(c)?;
+            continue;
+        }
+        let (index, width) = match chars.next()? {
+            'Y' => (0, 4),
+            'm' => (1, 2),
+            'd' => (2, 2),
+            'H' => (3, 2),
+            'M' => (4, 2),
+            'S' => (5, 2),
+            // Only `%%` is left, as the format has already been validated.
+            c => {
+                rest = rest.strip_prefix(c)?;
+                continue;
+            }
+        };
+        let digits = rest.get(..width)?;
+        if !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+            return None;
+        }
+        fields[index] = digits.parse().ok()?;
+        rest = &rest[width..];
+    }
+    system_time(fields, None)
+}
+
+/// Parse an ISO 8601 timestamp such as `2021-06-30`, `2021-06-30T12:30`, or
+/// `2021-06-30T12:30:15+02:00`. Timestamps without a UTC offset are in the local timezone.
+fn parse_timestamp(value: &str) -> Option<SystemTime> {
+    fn fields(value: &str, separator: char) -> Option<Vec<i64>> {
+        value
+            .split(separator)
+            .map(
+                |field| match field.bytes().all(|byte| byte.is_ascii_digit()) {
+                    true if !field.is_empty() => field.parse().ok(),
+                    _ => None,
+                },
+            )
+            .collect()
+    }
+
+    let (date, time) = match value.find(&['T', ' '][..]) {
+        Some(index) => (&value[..index], &value[index + 1..]),
+        None => (value, ""),
+    };
+    let (time, offset) = if let Some(time) = time.strip_suffix('Z') {
+        (time, Some(0))
+    } else if let Some(index) = time.rfind(&['+', '-'][..]) {
+        let sign = if time[index..].starts_with('-') {
+            -1
+        } else {
+            1
+        };
+        match fields(&time[index + 1..], ':')?.as_slice() {
+            [hours, minutes] if *hours < 24 && *minutes < 60 => {
+                (&time[..index], Some(sign * (hours * 3600 + minutes * 60)))
+            }
+            _ => return None,
+        }
+    } else {
+        (time, None)
+    };
+    let (year, month, day) = match *fields(date, '-')?.as_slice() {
+        [year, month, day] => (year, month, day),
+        _ => return None,
+    };
+    let (hour, minute, second) = match time {
+        "" => (0, 0, 0),
+        _ => match *fields(time, ':')?.as_slice() {
+            [hour, minute] => (hour, minute, 0),
+            [hour, minute, second] => (hour, minute, second),
+            _ => return None,
+        },
+    };
+    system_time([year, month, day, hour, minute, second], offset)
+}
+
+/// The time given by `[year, month, day, hour, minute, second]` fields, which is in the local
+/// timezone unless a UTC `offset` in seconds is given, if those fields are valid.
+fn system_time(fields: [i64; 6], offset: Option<i64>) -> Option<SystemTime> {
+    let [year, month, day, hour, minute, second] = fields;
+    if !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) {
+        return None;
+    }
+    if hour >= 24 || minute >= 60 || second > 60 {
+        return None;
+    }
+
+    let seconds = match offset {
+        Some(offset) => {
+            let days = days_since_epoch(year, month, day);
+            days * 86400 + hour * 3600 + minute * 60 + second - offset
+        }
+        None => {
+            // Safe because an all-zero `tm` is valid, and `mktime` only accesses the `tm` given.
+            let mut tm: libc::tm = unsafe { mem::zeroed() };
+            tm.tm_year = (year - 1900) as libc::c_int;
+            tm.tm_mon = (month - 1) as libc::c_int;
+            tm.tm_mday = day as libc::c_int;
+            tm.tm_hour = hour as libc::c_int;
+            tm.tm_min = minute as libc::c_int;
+            tm.tm_sec = second as libc::c_int;
+            // Let `mktime` determine whether daylight saving time applies.
+            tm.tm_isdst = -1;
+            // As -1 is both a valid time and what `mktime` returns on failure, failure is told apart
+            // by `mktime` not having filled in the day of the week.
+            tm.tm_wday = -1;
+            // The cast is necessary because `time_t` isn't 64 bits wide on every platform.
+            #[allow(clippy::unnecessary_cast)]
+            let seconds = unsafe { libc::mktime(&mut tm) } as i64;
+            if tm.tm_wday == -1 {
+                return None;
+            }
+            seconds
+        }
+    };
+    if seconds >= 0 {
+        UNIX_EPOCH.checked_add(Duration::from_secs(seconds as u64))
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::from_secs(seconds.unsigned_abs()))
+    }
+}
+
+// The number of days in the given month of the proleptic Gregorian calendar.
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// The number of days between the Unix epoch and the given date in the proleptic Gregorian
+// calendar, using the algorithm from http://howardhinnant.github.io/date_algorithms.html.
+fn days_since_epoch(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
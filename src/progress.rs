@@ -0,0 +1,418 @@
+//! Reporting of a copy's progress as a stream of newline-delimited JSON events on stdout, so that
+//! another program (e.g. a graphical frontend reading from a pipe) can follow along.
+//!
+//! Four kinds of events are emitted:
+//! - `{"event":"start","total_files":N,"total_bytes":N}` once before copying begins.
+//! - `{"event":"progress","files_done":N,"bytes_done":N}` periodically while copying.
+//! - `{"event":"error","path":S,"file_type":S,"operation":S,"errno":N,"message":S}` for each file
+//!   which couldn't be copied. Any of `file_type`, `operation`, and `errno` may be `null` when
+//!   they aren't known (e.g. `errno` for errors which didn't come from the OS).
+//! - `{"event":"done","files_done":N,"bytes_done":N,"success":B}` once copying has finished.
+//!
+//! Directories themselves are not counted as files, only their contents.
+//!
+//! With `--progress-to-fd`, the events are written to an inherited file descriptor instead, which
+//! leaves stdout free for other output.
+//!
+//! The same counters also back `--summary-only`, which prints just a one-line summary of them
+//! once copying has finished, and `--progress=tree`, which periodically prints a line to stderr
+//! giving the overall percentage of bytes copied along with that of each source being copied, so
+//! that it's apparent which part of a large copy is holding it up. Likewise `--report-sizes`,
+//! which prints the number of files and bytes copied from each source once copying has finished.
+
+use crate::error::Error;
+use crate::filesystem::{self as fs, FileType};
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How often `progress` events are emitted by default. This is deliberately infrequent enough
+/// that the consumer of the events isn't flooded with them.
+const INTERVAL: Duration = Duration::from_millis(250);
+
+/// How many `INTERVAL`s pass between the lines printed by `--progress=tree` by default, which are
+/// meant for humans to read, and so are printed less often.
+const TREE_INTERVALS: u32 = 4;
+
+/// Counters tracking how much of a copy has been completed so far.
+#[derive(Debug, Default)]
+pub struct Progress {
+    files_done: AtomicU64,
+    bytes_done: AtomicU64,
+    /// How many of `bytes_done` were left as holes rather than written, with `--sparse`.
+    holes: AtomicU64,
+    errors: AtomicU64,
+    /// How many regular files were skipped for being outside the range given by `--min-size` and
+    /// `--max-size`.
+    skipped_for_size: AtomicU64,
+    /// How many paths were skipped for already existing in the destination, with `--no-clobber`
+    /// or `--ignore-existing`.
+    skipped_existing: AtomicU64,
+    /// Whether to emit an `error` event for each error, rather than only counting them.
+    emit_errors: bool,
+    /// Where to emit events, if not to stdout.
+    events: Option<Mutex<File>>,
+    /// The progress of copying each of the sources given on the command line, when following it
+    /// with `--progress=tree`.
+    sources: Vec<SourceProgress>,
+}
+
+/// The progress of copying one of the sources given on the command line (and everything in it).
+#[derive(Debug)]
+struct SourceProgress {
+    path: PathBuf,
+    total_bytes: u64,
+    files_done: AtomicU64,
+    bytes_done: AtomicU64,
+}
+
+impl Progress {
+    /// Counters for copying `sources`, which (unlike those of a `Reporter`) aren't tallied up
+    /// beforehand, so can't tell how far along the copy of each is.
+    pub fn for_sources(sources: &[PathBuf]) -> Progress {
+        Progress {
+            sources: sources
+                .iter()
+                .map(|path| SourceProgress::new(path, 0))
+                .collect(),
+            ..Progress::default()
+        }
+    }
+
+    /// Record that a file of `bytes` bytes has been copied, from within the source given on the
+    /// command line at index `source` (if known).
+    pub fn record(&self, source: Option<usize>, bytes: u64) {
+        self.files_done.fetch_add(1, Ordering::Relaxed);
+        self.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(source) = source.and_then(|source| self.sources.get(source)) {
+            source.files_done.fetch_add(1, Ordering::Relaxed);
+            source.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// Record that `holes` bytes of a file which has been copied were left as holes rather than
+    /// being written.
+    pub fn record_holes(&self, holes: u64) {
+        self.holes.fetch_add(holes, Ordering::Relaxed);
+    }
+
+    /// Record that a regular file was skipped for being outside the range of sizes to copy.
+    pub fn record_skipped_for_size(&self) {
+        self.skipped_for_size.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that a path was skipped for already existing in the destination.
+    pub fn record_skipped_existing(&self) {
+        self.skipped_existing.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the failure to copy `path`, whose type is `file_type` (if known), emitting an
+    /// `error` event for it when reporting progress as JSON.
+    pub fn record_error(&self, path: &Path, file_type: Option<FileType>, err: &Error) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        if !self.emit_errors {
+            return;
+        }
+        self.emit(&format!(
+            r#"{{"event":"error","path":{},"file_type":{},"operation":{},"errno":{},"message":{}}}"#,
+            json_string(&path.to_string_lossy()),
+            json_or_null(file_type.map(|file_type| json_string(file_type.name()))),
+            json_or_null(err.operation().map(json_string)),
+            json_or_null(err.raw_os_error()),
+            json_string(&err.to_string()),
+        ));
+    }
+
+    /// A one-line summary of everything recorded, as printed with `--summary-only`.
+    pub fn summary(&self) -> String {
+        let (files_done, bytes_done) = self.counts();
+        // Holes are part of the bytes copied, so can't outnumber them, but are bounded by them
+        // regardless so that a miscount can't make this fail.
+        let (holes, errors) = (
+            self.holes.load(Ordering::Relaxed).min(bytes_done),
+            self.errors.load(Ordering::Relaxed),
+        );
+        // The bytes actually written are only worth distinguishing when some weren't.
+        let written = match holes {
+            0 => String::new(),
+            _ => format!(
+                ", {} written and {}% left as holes",
+                bytes_done - holes,
+                holes * 100 / bytes_done
+            ),
+        };
+        let skipped = match self.skipped_for_size.load(Ordering::Relaxed) {
+            0 => String::new(),
+            skipped => format!(
+                ", {} {} skipped for {} size",
+                skipped,
+                plural(skipped, "file"),
+                if skipped == 1 { "its" } else { "their" }
+            ),
+        };
+        let existing = match self.skipped_existing.load(Ordering::Relaxed) {
+            0 => String::new(),
+            existing => format!(
+                ", {} {} skipped as {} already existed",
+                existing,
+                plural(existing, "path"),
+                if existing == 1 { "it" } else { "they" }
+            ),
+        };
+        format!(
+            "Copied {} {} ({} {}{}){}{}, with {} {}",
+            files_done,
+            plural(files_done, "file"),
+            bytes_done,
+            plural(bytes_done, "byte"),
+            written,
+            skipped,
+            existing,
+            errors,
+            plural(errors, "error"),
+        )
+    }
+
+    /// A line for each source giving the number of files and bytes copied from it, as printed by
+    /// `--report-sizes`.
+    pub fn sizes(&self) -> Vec<String> {
+        self.sources
+            .iter()
+            .map(|source| {
+                let files_done = source.files_done.load(Ordering::Relaxed);
+                let bytes_done = source.bytes_done.load(Ordering::Relaxed);
+                format!(
+                    "{}: {} {}, {} {}",
+                    source.path.display(),
+                    files_done,
+                    plural(files_done, "file"),
+                    bytes_done,
+                    plural(bytes_done, "byte")
+                )
+            })
+            .collect()
+    }
+
+    /// A line giving the percentage of all bytes which have been copied, and of those within each
+    /// source which has been started but not yet finished, as printed by `--progress=tree`.
+    fn tree(&self, total_bytes: u64) -> String {
+        let mut line = format!(
+            "Progress: {}% of {} bytes",
+            percentage(self.counts().1, total_bytes),
+            total_bytes
+        );
+        let active = self.sources.iter().filter_map(|source| {
+            let bytes_done = source.bytes_done.load(Ordering::Relaxed);
+            match bytes_done {
+                0 => None,
+                _ if bytes_done >= source.total_bytes => None,
+                _ => Some(format!(
+                    "'{}' {}%",
+                    source.path.display(),
+                    percentage(bytes_done, source.total_bytes)
+                )),
+            }
+        });
+        for (index, source) in active.enumerate() {
+            line.push_str(if index == 0 { "; in progress: " } else { ", " });
+            line.push_str(&source);
+        }
+        line
+    }
+
+    fn counts(&self) -> (u64, u64) {
+        (
+            self.files_done.load(Ordering::Relaxed),
+            self.bytes_done.load(Ordering::Relaxed),
+        )
+    }
+
+    // Failing to report progress (e.g. because the reader went away) shouldn't affect the copy.
+    fn emit(&self, event: &str) {
+        match &self.events {
+            Some(events) => {
+                let mut events = events.lock().unwrap_or_else(|err| err.into_inner());
+                let _ = writeln!(events, "{}", event);
+            }
+            None => {
+                let stdout = io::stdout();
+                let mut stdout = stdout.lock();
+                let _ = writeln!(stdout, "{}", event).and_then(|_| stdout.flush());
+            }
+        }
+    }
+}
+
+impl SourceProgress {
+    fn new(path: &Path, total_bytes: u64) -> SourceProgress {
+        SourceProgress {
+            path: path.to_path_buf(),
+            total_bytes,
+            files_done: AtomicU64::new(0),
+            bytes_done: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Emits the events describing a copy's progress, the periodic ones from a background thread.
+pub struct Reporter {
+    json: bool,
+    progress: Arc<Progress>,
+    stop: mpsc::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Reporter {
+    /// Tally up the files in `sources`, then begin periodically reporting the progress of copying
+    /// them: as JSON events (starting with the `start` event) if `json` is set, and as lines on
+    /// stderr for `--progress=tree` if `tree` is set. The JSON events are written to `events` if
+    /// given, and otherwise to stdout. Both are reported every `interval` if given, rather than
+    /// at their default rates.
+    pub fn start(
+        sources: &[PathBuf],
+        json: bool,
+        tree: bool,
+        events: Option<File>,
+        interval: Option<Duration>,
+    ) -> Reporter {
+        let totals = sources
+            .iter()
+            .map(|source| totals(source))
+            .collect::<Vec<_>>();
+        let (total_files, total_bytes) = totals.iter().copied().fold((0, 0), add);
+        let progress = Arc::new(Progress {
+            emit_errors: json,
+            events: events.map(Mutex::new),
+            sources: sources
+                .iter()
+                .zip(totals)
+                .map(|(path, (_, total_bytes))| SourceProgress::new(path, total_bytes))
+                .collect(),
+            ..Progress::default()
+        });
+        if json {
+            progress.emit(&format!(
+                r#"{{"event":"start","total_files":{},"total_bytes":{}}}"#,
+                total_files, total_bytes
+            ));
+        }
+
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn({
+            let progress = Arc::clone(&progress);
+            move || {
+                let (mut last_counts, mut last_tree_counts) = ((0, 0), (0, 0));
+                let mut intervals = 0;
+                let tree_intervals = match interval {
+                    Some(_) => 1,
+                    None => TREE_INTERVALS,
+                };
+                let interval = interval.unwrap_or(INTERVAL);
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    let counts = progress.counts();
+                    if json && counts != last_counts {
+                        progress.emit(&format!(
+                            r#"{{"event":"progress","files_done":{},"bytes_done":{}}}"#,
+                            counts.0, counts.1
+                        ));
+                        last_counts = counts;
+                    }
+                    intervals += 1;
+                    if tree && intervals % tree_intervals == 0 && counts != last_tree_counts {
+                        eprintln!("{}", progress.tree(total_bytes));
+                        last_tree_counts = counts;
+                    }
+                }
+            }
+        });
+        Reporter {
+            json,
+            progress,
+            stop,
+            thread,
+        }
+    }
+
+    pub fn progress(&self) -> &Progress {
+        &self.progress
+    }
+
+    /// Stop reporting progress periodically, and emit the `done` event if reporting as JSON.
+    pub fn finish(self, success: bool) {
+        // An error here would mean the thread has already exited, which is what we want anyway.
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+        if !self.json {
+            return;
+        }
+        let (files_done, bytes_done) = self.progress.counts();
+        self.progress.emit(&format!(
+            r#"{{"event":"done","files_done":{},"bytes_done":{},"success":{}}}"#,
+            files_done, bytes_done, success
+        ));
+    }
+}
+
+fn percentage(done: u64, total: u64) -> u64 {
+    match total {
+        0 => 100,
+        // Files may have grown since they were tallied.
+        _ => (done * 100 / total).min(100),
+    }
+}
+
+fn plural(count: u64, noun: &str) -> String {
+    match count {
+        1 => noun.to_string(),
+        _ => format!("{}s", noun),
+    }
+}
+
+fn add(a: (u64, u64), b: (u64, u64)) -> (u64, u64) {
+    (a.0 + b.0, a.1 + b.1)
+}
+
+// Errors are deliberately ignored here, as they'll be reported when the copy itself encounters
+// them.
+fn totals(path: &Path) -> (u64, u64) {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => match fs::read_dir(path) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .collect::<Vec<_>>()
+                .into_par_iter()
+                .map(|path| totals(&path))
+                .reduce(|| (0, 0), add),
+            Err(_) => (0, 0),
+        },
+        Ok(metadata) if metadata.is_file() => (1, metadata.len()),
+        Ok(_) => (1, 0),
+        Err(_) => (0, 0),
+    }
+}
+
+pub(crate) fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+pub(crate) fn json_or_null<T: Display>(value: Option<T>) -> String {
+    value.map_or_else(|| "null".to_string(), |value| value.to_string())
+}
@@ -0,0 +1,54 @@
+//! Prompts for `--interactive`, which asks before overwriting each existing destination file.
+//!
+//! Files are copied by many threads at once, but their questions are all sent to a single thread
+//! which asks them one at a time, so that prompts never interleave on stderr and each answer read
+//! from stdin goes to the question it was given for. A thread waiting for its answer blocks only
+//! itself, while the others carry on copying.
+
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::thread;
+
+type Question = (PathBuf, Sender<bool>);
+
+pub struct Prompter {
+    questions: Mutex<Sender<Question>>,
+}
+
+impl Prompter {
+    /// Start the thread which asks the questions. It isn't joined, as it may be left waiting for
+    /// an answer which never comes (e.g. once the copy is interrupted), and exits once this is
+    /// dropped and any question it's asking is answered.
+    pub fn start() -> Prompter {
+        let (questions, asked) = mpsc::channel::<Question>();
+        thread::spawn(move || {
+            for (dest, answer) in asked {
+                eprint!("overwrite '{}'? ", dest.display());
+                let _ = io::stderr().flush();
+                // As with `cp -i`, only an answer beginning with 'y' is a yes, and failing to
+                // read one (e.g. at the end of stdin) is taken as a no.
+                let mut line = String::new();
+                let yes = io::stdin().read_line(&mut line).is_ok_and(|read| read > 0)
+                    && matches!(line.trim_start().chars().next(), Some('y' | 'Y'));
+                // The thread which asked is waiting for this, so it can't have gone away.
+                let _ = answer.send(yes);
+            }
+        });
+        Prompter {
+            questions: Mutex::new(questions),
+        }
+    }
+
+    /// Ask whether to overwrite `dest`, waiting for the answer.
+    pub fn overwrite(&self, dest: &Path) -> bool {
+        let (answer, answered) = mpsc::channel();
+        let asked = self
+            .questions
+            .lock()
+            .unwrap()
+            .send((dest.to_path_buf(), answer));
+        asked.is_ok() && answered.recv().unwrap_or(false)
+    }
+}
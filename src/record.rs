@@ -0,0 +1,196 @@
+//! Recording with `--record-only`, which writes a manifest of the structure of the sources
+//! instead of copying them, so that two trees can be compared cheaply (by diffing their
+//! manifests), or a copy planned, without writing any destination files.
+//!
+//! Each line of the manifest is a JSON object of the form
+//! `{"path":S,"type":S,"mode":S,"uid":N,"gid":N,"size":N,"atime":N,"mtime":N,"target":S,"rdev":N,"hash":S}`,
+//! where:
+//! - `path` is where the entry would be copied to relative to a destination directory (i.e. it
+//!   starts with the source's file name, or its whole path with `--parents`).
+//! - `type` is one of `regular`, `directory`, `symlink`, `fifo`, `socket`, `character_device`,
+//!   and `block_device`.
+//! - `mode` is the permission bits, in octal (e.g. `"0644"`).
+//! - `atime` and `mtime` are in seconds since the Unix epoch, with nanosecond precision.
+//! - `target` is what a symlink points to, and `null` for anything else.
+//! - `rdev` is the device number of a device file, and `null` for anything else.
+//! - `hash` is the 64-bit FNV-1a hash of the contents of a regular file, in hexadecimal, and
+//!   `null` for anything else. It's only meant for telling whether contents differ.
+//!
+//! The entries of each directory are recorded in order of their names, after the directory
+//! itself, so recording the same tree twice produces the same manifest. Only `--parents`,
+//! `--no-recursive`, `--one-file-system`, `--newer-than`, `--min-size`, and `--max-size` are taken
+//! into account in deciding what's recorded.
+
+use crate::error::{Error, Result};
+use crate::filesystem::{self as fs, FileType};
+use crate::options::CopyOptions;
+use crate::progress::{json_or_null, json_string};
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{self, prelude::*, BufWriter};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// The size of the buffer through which regular files are read to be hashed.
+const HASH_BUFFER_SIZE: usize = 128 * 1024;
+
+/// The state of a recording, accumulated while walking the sources.
+struct Recording<'a> {
+    options: &'a CopyOptions,
+    manifest: BufWriter<File>,
+    has_err: bool,
+}
+
+/// Record the structure of `sources` in the manifest at `path`, which is created (or truncated),
+/// returning whether any error occurred in reading the sources.
+pub(crate) fn run(sources: &[PathBuf], path: &Path, options: &CopyOptions) -> Result<bool> {
+    let names = match options.parents {
+        true => crate::parent_paths(sources)?,
+        false => crate::file_names(sources)?
+            .into_iter()
+            .map(PathBuf::from)
+            .collect(),
+    };
+    let error = |err: io::Error| {
+        let message = format!("{}: {}", path.display(), err);
+        Error::with_os_error(message, "write", &err)
+    };
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .map_err(error)?;
+    let mut recording = Recording {
+        options,
+        manifest: BufWriter::new(file),
+        has_err: false,
+    };
+    for (source, name) in sources.iter().zip(names) {
+        recording.walk(source, &name, None).map_err(error)?;
+    }
+    recording.manifest.flush().map_err(error)?;
+    Ok(recording.has_err)
+}
+
+impl Recording<'_> {
+    // Record `source` as `path`, along with everything beneath it. `within` is the device of the
+    // directory containing `source`, if it's not a source itself. Only errors in writing the
+    // manifest are returned, while those in reading the source are reported and recorded in
+    // `has_err`.
+    fn walk(&mut self, source: &Path, path: &Path, within: Option<u64>) -> io::Result<()> {
+        let metadata = match fs::symlink_metadata(source) {
+            Ok(metadata) => metadata,
+            Err(err) => return self.report(err),
+        };
+        if metadata.is_dir() && self.options.no_recursive {
+            return self.report(Error::new(format!(
+                "-R not specified; omitting directory '{}'",
+                source.display()
+            )));
+        }
+        if !metadata.is_dir() && self.options.is_filtered(&metadata) {
+            return Ok(());
+        }
+        let hash = match metadata.is_file() {
+            true => match hash_contents(source) {
+                Ok(hash) => Some(format!(r#""{:016x}""#, hash)),
+                Err(err) => return self.report(err),
+            },
+            false => None,
+        };
+        let target = match metadata.file_type().is_symlink() {
+            true => match fs::read_link(source) {
+                Ok(target) => Some(json_string(&target.to_string_lossy())),
+                Err(err) => return self.report(err),
+            },
+            false => None,
+        };
+        self.write(path, &metadata, target, hash)?;
+        // As in `copy_directory`, a mount point is recorded, but not what's beneath it.
+        if !metadata.is_dir()
+            || (self.options.one_file_system && within.is_some_and(|dev| dev != metadata.dev()))
+        {
+            return Ok(());
+        }
+        let entries = match fs::read_dir(source) {
+            Ok(entries) => entries,
+            Err(err) => return self.report(err),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            match entry {
+                Ok(entry) => names.push(entry.file_name()),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    self.has_err = true;
+                }
+            }
+        }
+        names.sort_unstable();
+        for name in names {
+            self.walk(&source.join(&name), &path.join(&name), Some(metadata.dev()))?;
+        }
+        Ok(())
+    }
+
+    fn write(
+        &mut self,
+        path: &Path,
+        metadata: &Metadata,
+        target: Option<String>,
+        hash: Option<String>,
+    ) -> io::Result<()> {
+        let file_type = FileType::from(metadata.file_type());
+        let rdev = match file_type {
+            FileType::CharacterDevice | FileType::BlockDevice => Some(metadata.rdev()),
+            _ => None,
+        };
+        writeln!(
+            self.manifest,
+            r#"{{"path":{},"type":"{}","mode":"{:04o}","uid":{},"gid":{},"size":{},"atime":{}.{:09},"mtime":{}.{:09},"target":{},"rdev":{},"hash":{}}}"#,
+            json_string(&path.to_string_lossy()),
+            file_type.name(),
+            metadata.mode() & 0o7777,
+            metadata.uid(),
+            metadata.gid(),
+            metadata.len(),
+            metadata.atime(),
+            metadata.atime_nsec(),
+            metadata.mtime(),
+            metadata.mtime_nsec(),
+            json_or_null(target),
+            json_or_null(rdev),
+            json_or_null(hash)
+        )
+    }
+
+    fn report(&mut self, err: Error) -> io::Result<()> {
+        eprintln!("{}", err);
+        self.has_err = true;
+        Ok(())
+    }
+}
+
+// The 64-bit FNV-1a hash of the contents of the regular file at `path`.
+fn hash_contents(path: &Path) -> Result<u64> {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let mut file = fs::open(path)?;
+    let mut buffer = vec![0; HASH_BUFFER_SIZE];
+    let mut hash = OFFSET_BASIS;
+    loop {
+        let count = match file.read(&mut buffer) {
+            Ok(0) => return Ok(hash),
+            Ok(count) => count,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                let message = format!("{}: {}", path.display(), err);
+                return Err(Error::with_os_error(message, "read", &err));
+            }
+        };
+        for &byte in &buffer[..count] {
+            hash = (hash ^ u64::from(byte)).wrapping_mul(PRIME);
+        }
+    }
+}
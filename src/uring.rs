@@ -0,0 +1,587 @@
+//! Copying many small regular files at once through io_uring (with `--io-uring`), which is only
+//! built with the `io_uring` feature, and only on Linux (5.6 or later).
+//!
+//! Copying a small file is dominated by the syscalls around its contents (opening both files,
+//! reading, writing, and closing them) rather than by moving the contents themselves. Here the
+//! files are copied in phases, each of which submits the same operation for every file to a single
+//! ring and waits for all of them to complete, so that a batch of files takes a handful of
+//! `io_uring_enter` calls rather than half a dozen syscalls per file.
+//!
+//! Only the contents are copied here (giving each copy its source's permissions, as `fs::copy`
+//! does). Everything else about copying the files, such as their other attributes, is left to the
+//! caller, as for files copied individually.
+
+use nix::fcntl::OFlag;
+use nix::libc;
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// The number of submission queue entries of each ring, which is also the most files copied by
+/// each phase at once (and so bounds the number of files open, and the memory used for their
+/// contents).
+const RING_ENTRIES: u32 = 128;
+
+/// The most file descriptors `copy_small_files` holds open at once: those of the files it's
+/// copying, and that of the ring itself.
+pub(crate) const DESCRIPTORS: usize = RING_ENTRIES as usize + 1;
+
+// The parts of the io_uring ABI used here, from `linux/io_uring.h`.
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x0800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+const IORING_ENTER_GETEVENTS: libc::c_uint = 1;
+const IORING_OP_OPENAT: u8 = 18;
+const IORING_OP_CLOSE: u8 = 19;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+/// A submission queue entry, laid out as `struct io_uring_sqe`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    file_index: u32,
+    addr3: u64,
+    pad: u64,
+}
+
+/// A completion queue entry, laid out as `struct io_uring_cqe`.
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+impl Sqe {
+    fn openat(path: &CString, flags: OFlag, mode: u32) -> Sqe {
+        Sqe {
+            opcode: IORING_OP_OPENAT,
+            fd: libc::AT_FDCWD,
+            addr: path.as_ptr() as u64,
+            len: mode,
+            op_flags: (flags | OFlag::O_CLOEXEC).bits() as u32,
+            ..Sqe::default()
+        }
+    }
+
+    fn read(fd: RawFd, buffer: *mut u8, len: usize, offset: u64) -> Sqe {
+        Sqe {
+            opcode: IORING_OP_READ,
+            fd,
+            off: offset,
+            addr: buffer as u64,
+            len: len as u32,
+            ..Sqe::default()
+        }
+    }
+
+    fn write(fd: RawFd, buffer: &[u8]) -> Sqe {
+        Sqe {
+            opcode: IORING_OP_WRITE,
+            fd,
+            addr: buffer.as_ptr() as u64,
+            len: buffer.len() as u32,
+            ..Sqe::default()
+        }
+    }
+
+    fn close(fd: RawFd) -> Sqe {
+        Sqe {
+            opcode: IORING_OP_CLOSE,
+            fd,
+            ..Sqe::default()
+        }
+    }
+}
+
+/// A region of memory shared with the kernel, unmapped when dropped.
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Mapping> {
+        // Safe because a fresh mapping is requested, which aliases no existing memory.
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping { ptr, len })
+    }
+
+    /// The field at `offset` bytes into the mapping, as given by the kernel.
+    fn at<T>(&self, offset: u32) -> *mut T {
+        // Safe because the kernel's offsets all lie within the mapping.
+        unsafe { self.ptr.cast::<u8>().add(offset as usize).cast() }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // Safe because nothing refers to the mapping once it's dropped.
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// An io_uring instance, closed when dropped.
+struct Ring {
+    fd: RawFd,
+    params: Params,
+    sq: Mapping,
+    cq: Mapping,
+    sqes: Mapping,
+    /// The error with which entering the ring failed, after which nothing more is submitted.
+    failed: Option<io::Error>,
+    /// Whether entries may still be in flight, as waiting for them failed, in which case whatever
+    /// they refer to must be leaked rather than freed.
+    stranded: bool,
+}
+
+impl Ring {
+    fn new(entries: u32) -> io::Result<Ring> {
+        let mut params = Params::default();
+        // Safe because `params` outlives the call, and the kernel writes only within it.
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = fd as RawFd;
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * std::mem::size_of::<Sqe>();
+        let mapped = (|| {
+            Ok((
+                Mapping::new(fd, sq_len, IORING_OFF_SQ_RING)?,
+                Mapping::new(fd, cq_len, IORING_OFF_CQ_RING)?,
+                Mapping::new(fd, sqes_len, IORING_OFF_SQES)?,
+            ))
+        })();
+        match mapped {
+            Ok((sq, cq, sqes)) => Ok(Ring {
+                fd,
+                params,
+                sq,
+                cq,
+                sqes,
+                failed: None,
+                stranded: false,
+            }),
+            Err(err) => {
+                // Safe because nothing else refers to the ring.
+                unsafe { libc::close(fd) };
+                Err(err)
+            }
+        }
+    }
+
+    fn sq_field(&self, offset: u32) -> &AtomicU32 {
+        // Safe because the field lies within the mapping, which lives as long as `self`, and is
+        // only ever accessed atomically.
+        unsafe { &*self.sq.at::<AtomicU32>(offset) }
+    }
+
+    fn cq_field(&self, offset: u32) -> &AtomicU32 {
+        // As above.
+        unsafe { &*self.cq.at::<AtomicU32>(offset) }
+    }
+
+    /// Submit `sqes`, waiting for all of them to complete, and return the result of each in
+    /// order. Every buffer and path they refer to must remain valid until this returns, and for
+    /// good if the ring is then `stranded`. Should entering the ring fail, the entries already
+    /// submitted are still waited for, and those which weren't run at all (along with any given to
+    /// the ring after that) have no result, the error being kept in `failed`.
+    fn run(&mut self, sqes: &[Sqe]) -> Vec<Option<i32>> {
+        let mut results = vec![None; sqes.len()];
+        let capacity = self.params.sq_entries as usize;
+        for (chunk_index, chunk) in sqes.chunks(capacity).enumerate() {
+            if self.failed.is_some() {
+                break;
+            }
+            let sq_mask = self
+                .sq_field(self.params.sq_off.ring_mask)
+                .load(Ordering::Relaxed);
+            let mut tail = self
+                .sq_field(self.params.sq_off.tail)
+                .load(Ordering::Relaxed);
+            for (i, sqe) in chunk.iter().enumerate() {
+                let index = tail & sq_mask;
+                // Safe because `index` is masked to lie within both arrays, and the kernel doesn't
+                // read these entries until the tail is advanced past them.
+                unsafe {
+                    let entry = self.sqes.at::<Sqe>(0).add(index as usize);
+                    *entry = Sqe {
+                        user_data: (chunk_index * capacity + i) as u64,
+                        ..*sqe
+                    };
+                    *self
+                        .sq
+                        .at::<u32>(self.params.sq_off.array)
+                        .add(index as usize) = index;
+                }
+                tail = tail.wrapping_add(1);
+            }
+            self.sq_field(self.params.sq_off.tail)
+                .store(tail, Ordering::Release);
+            let mut pending = chunk.len();
+            let mut to_submit = chunk.len();
+            while pending > 0 {
+                // Safe because the ring is valid and no signal mask is given.
+                let submitted = unsafe {
+                    libc::syscall(
+                        libc::SYS_io_uring_enter,
+                        self.fd,
+                        to_submit as libc::c_uint,
+                        1 as libc::c_uint,
+                        IORING_ENTER_GETEVENTS,
+                        ptr::null::<libc::c_void>(),
+                        0usize,
+                    )
+                };
+                if submitted < 0 {
+                    let err = io::Error::last_os_error();
+                    // These errors are transient (the latter two meaning that the kernel is
+                    // momentarily short of resources).
+                    if let Some(libc::EINTR | libc::EAGAIN | libc::EBUSY) = err.raw_os_error() {
+                        continue;
+                    }
+                    if to_submit == 0 {
+                        // Entries already submitted still refer to their buffers, but can no
+                        // longer be waited for.
+                        self.stranded = true;
+                        self.failed = Some(err);
+                        break;
+                    }
+                    // The entries not yet submitted are withdrawn (the kernel only reads them
+                    // when they're submitted), and those already submitted waited for.
+                    tail = tail.wrapping_sub(to_submit as u32);
+                    self.sq_field(self.params.sq_off.tail)
+                        .store(tail, Ordering::Release);
+                    pending -= to_submit;
+                    to_submit = 0;
+                    self.failed = Some(err);
+                    continue;
+                }
+                to_submit -= (submitted as usize).min(to_submit);
+                pending -= self.reap(&mut results);
+            }
+        }
+        results
+    }
+
+    /// The error with which the ring failed, for the files it left unfinished.
+    fn failure(&self) -> io::Error {
+        match &self.failed {
+            Some(err) => match err.raw_os_error() {
+                Some(errno) => io::Error::from_raw_os_error(errno),
+                None => io::Error::new(err.kind(), err.to_string()),
+            },
+            None => io::ErrorKind::Other.into(),
+        }
+    }
+
+    /// Record the results of the completed entries, returning how many there were.
+    fn reap(&self, results: &mut [Option<i32>]) -> usize {
+        let cq_mask = self
+            .cq_field(self.params.cq_off.ring_mask)
+            .load(Ordering::Relaxed);
+        let cq_head = self.cq_field(self.params.cq_off.head);
+        let tail = self
+            .cq_field(self.params.cq_off.tail)
+            .load(Ordering::Acquire);
+        let mut head = cq_head.load(Ordering::Relaxed);
+        let mut reaped = 0;
+        while head != tail {
+            // Safe because the index is masked to lie within the array, and the kernel doesn't
+            // reuse the entry until the head is advanced past it.
+            let cqe = unsafe {
+                &*self
+                    .cq
+                    .at::<Cqe>(self.params.cq_off.cqes)
+                    .add((head & cq_mask) as usize)
+            };
+            results[cqe.user_data as usize] = Some(cqe.res);
+            head = head.wrapping_add(1);
+            reaped += 1;
+        }
+        cq_head.store(head, Ordering::Release);
+        reaped
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        // Safe because the ring's mappings remain valid without its file descriptor.
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// A regular file to be copied by `copy_small_files`.
+pub(crate) struct SmallFile<'a> {
+    pub source: &'a Path,
+    pub dest: &'a Path,
+    /// The size of the source, all of which is read at once. Files which may have contents beyond
+    /// their reported size (such as those in procfs) don't belong here.
+    pub len: u64,
+    /// The permissions to give the copy.
+    pub mode: u32,
+}
+
+/// Copy the contents of each of `files`, opening their destinations with `dest_flags`, returning
+/// the number of bytes copied of each, or the error with which copying it failed. Only failing to
+/// set up io_uring at all (e.g. on kernels without it, or where it's been disabled) is returned as
+/// an error overall, in which case nothing has been copied.
+pub(crate) fn copy_small_files(
+    files: &[SmallFile],
+    dest_flags: OFlag,
+) -> io::Result<Vec<io::Result<u64>>> {
+    let mut ring = Ring::new(RING_ENTRIES)?;
+    let mut results = Vec::with_capacity(files.len());
+    for files in files.chunks(RING_ENTRIES as usize / 2) {
+        results.extend(copy_chunk(&mut ring, files, dest_flags)?);
+    }
+    Ok(results)
+}
+
+fn copy_chunk(
+    ring: &mut Ring,
+    files: &[SmallFile],
+    dest_flags: OFlag,
+) -> io::Result<Vec<io::Result<u64>>> {
+    let mut results = files
+        .iter()
+        .map(|_| Ok(0))
+        .collect::<Vec<io::Result<u64>>>();
+    // The paths and buffers which entries refer to are leaked rather than freed should the ring be
+    // left stranded, as the kernel may still be using them.
+    let paths = ManuallyDrop::new(
+        files
+            .iter()
+            .map(|file| {
+                let source = CString::new(file.source.as_os_str().as_bytes())?;
+                let dest = CString::new(file.dest.as_os_str().as_bytes())?;
+                Ok((source, dest))
+            })
+            .collect::<io::Result<Vec<_>>>()?,
+    );
+    // Run one phase for the files at `indices` which haven't failed yet, recording the errors of
+    // those for which it fails, and returning the results of the rest. Once the ring has failed,
+    // the files it didn't run the phase for fail with its error.
+    let mut phase = |indices: &[usize],
+                     results: &mut Vec<io::Result<u64>>,
+                     sqe: &mut dyn FnMut(usize) -> Sqe|
+     -> Vec<(usize, i32)> {
+        let indices = indices
+            .iter()
+            .copied()
+            .filter(|&i| results[i].is_ok())
+            .collect::<Vec<_>>();
+        let sqes = indices.iter().map(|&i| sqe(i)).collect::<Vec<_>>();
+        let mut succeeded = Vec::with_capacity(indices.len());
+        for (i, res) in indices.into_iter().zip(ring.run(&sqes)) {
+            match res {
+                Some(res) if res < 0 => results[i] = Err(io::Error::from_raw_os_error(-res)),
+                Some(res) => succeeded.push((i, res)),
+                None => results[i] = Err(ring.failure()),
+            }
+        }
+        succeeded
+    };
+    let all = (0..files.len()).collect::<Vec<_>>();
+
+    // Open the sources, and then the destinations of those which could be opened, so that no
+    // destination is created for a source which can't be read.
+    let sources = phase(&all, &mut results, &mut |i| {
+        Sqe::openat(&paths[i].0, OFlag::O_RDONLY, 0)
+    });
+    let source_indices = sources.iter().map(|&(i, _)| i).collect::<Vec<_>>();
+    let flags = OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC | dest_flags;
+    let dests = phase(&source_indices, &mut results, &mut |i| {
+        Sqe::openat(&paths[i].1, flags, files[i].mode)
+    });
+    let mut fds = vec![(-1, -1); files.len()];
+    for &(i, fd) in &sources {
+        fds[i].0 = fd;
+    }
+    for &(i, fd) in &dests {
+        fds[i].1 = fd;
+    }
+    let opened = dests.iter().map(|&(i, _)| i).collect::<Vec<_>>();
+
+    // Read each source in its entirety. A read may return less than was asked for without the
+    // source having shrunk (e.g. on FUSE or NFS), so the rest is read until it's all been, or a
+    // read returns nothing, in which case the source shrank since it was statted and is copied as
+    // it now is.
+    let mut buffers = ManuallyDrop::new(
+        files
+            .iter()
+            .map(|file| vec![0; file.len.try_into().unwrap_or(0)])
+            .collect::<Vec<Vec<u8>>>(),
+    );
+    let pointers = buffers
+        .iter_mut()
+        .map(|buffer| (buffer.as_mut_ptr(), buffer.len()))
+        .collect::<Vec<_>>();
+    let mut filled = vec![0; files.len()];
+    let mut reading = opened;
+    while !reading.is_empty() {
+        let read = phase(&reading, &mut results, &mut |i| {
+            let (buffer, len) = pointers[i];
+            Sqe::read(
+                fds[i].0,
+                buffer.wrapping_add(filled[i]),
+                len - filled[i],
+                filled[i] as u64,
+            )
+        });
+        reading = Vec::new();
+        for (i, count) in read {
+            match count as usize {
+                0 => buffers[i].truncate(filled[i]),
+                count => {
+                    filled[i] += count;
+                    if filled[i] < buffers[i].len() {
+                        reading.push(i);
+                    }
+                }
+            }
+        }
+    }
+    let read = (0..files.len())
+        .filter(|&i| fds[i].1 >= 0 && results[i].is_ok())
+        .collect::<Vec<_>>();
+
+    // Write each destination, having given it its source's permissions, as the mode given when
+    // opening it is subject to the umask.
+    let mut writing = Vec::with_capacity(read.len());
+    for &i in &read {
+        // Safe because the file descriptor is open.
+        match unsafe { libc::fchmod(fds[i].1, files[i].mode as libc::mode_t) } {
+            0 => writing.push(i),
+            _ => results[i] = Err(io::Error::last_os_error()),
+        }
+    }
+    let written = phase(&writing, &mut results, &mut |i| {
+        Sqe::write(fds[i].1, &buffers[i])
+    });
+    for (i, count) in written {
+        results[i] = match count as usize == buffers[i].len() {
+            true => Ok(count as u64),
+            false => Err(io::ErrorKind::WriteZero.into()),
+        };
+    }
+
+    // Close everything which was opened, whatever became of the phases before. Failing to close a
+    // destination may mean that writing to it failed after all (e.g. on NFS).
+    let mut closes = Vec::new();
+    for (i, &(source, dest)) in fds.iter().enumerate() {
+        closes.extend(Some((None, source)).filter(|_| source >= 0));
+        closes.extend(Some((Some(i), dest)).filter(|_| dest >= 0));
+    }
+    let sqes = closes
+        .iter()
+        .map(|&(_, fd)| Sqe::close(fd))
+        .collect::<Vec<_>>();
+    for (&(dest, fd), res) in closes.iter().zip(ring.run(&sqes)) {
+        // What the ring couldn't close (having failed) is closed directly, unless it's been left
+        // stranded, in which case the kernel may yet close it (or use it), so it's leaked rather
+        // than risk closing a descriptor since opened by another thread under the same number.
+        let res = res.unwrap_or_else(|| match ring.stranded {
+            true => -ring.failure().raw_os_error().unwrap_or(libc::EIO),
+            // Safe because the file descriptor is open, and nothing else closes it.
+            false => match unsafe { libc::close(fd) } {
+                0 => 0,
+                _ => -io::Error::last_os_error()
+                    .raw_os_error()
+                    .unwrap_or(libc::EIO),
+            },
+        });
+        if let (Some(i), true) = (dest, res < 0) {
+            if results[i].is_ok() {
+                results[i] = Err(io::Error::from_raw_os_error(-res));
+            }
+        }
+    }
+    if !ring.stranded {
+        drop(ManuallyDrop::into_inner(paths));
+        drop(ManuallyDrop::into_inner(buffers));
+    }
+    Ok(results)
+}
@@ -23,8 +23,9 @@ use dev_utils::*;
 use fcp::{self, filesystem as fs};
 use std::ffi::OsStr;
 use std::io::prelude::*;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::Path;
-use std::process::{Command, ExitStatus};
+use std::process::{Command, ExitStatus, Stdio};
 use std::string::String;
 
 const FILE_MODE: u32 = 0o644;
@@ -44,6 +45,7 @@ fn diff(filename: &str) -> ExitStatus {
 }
 
 struct CommandResult {
+    stdout: String,
     stderr: String,
     success: bool,
 }
@@ -54,6 +56,7 @@ fn fcp_run<T: AsRef<OsStr>>(args: &[T]) -> CommandResult {
         .output()
         .unwrap();
     CommandResult {
+        stdout: String::from_utf8(result.stdout).unwrap(),
         stderr: String::from_utf8(result.stderr).unwrap(),
         success: result.status.success(),
     }
@@ -107,6 +110,38 @@ fn socket() {
     assert!(result.stderr.contains("sockets cannot be copied"));
 }
 
+#[test]
+fn sockets_skip_and_recreate() {
+    initialize();
+    let fixture_file = "socket.json";
+    hydrate_fixture(fixture_file);
+    let source = HYDRATED_DIR.join("socket");
+    let destination = COPIES_DIR.join("sockets_skip_and_recreate");
+    remove(&destination);
+    let result = fcp_run(&[
+        OsStr::new("--sockets=skip"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(
+        result.stderr,
+        format!("Warning: skipping socket '{}'\n", source.display())
+    );
+    assert!(!destination.exists());
+    let result = fcp_run(&[
+        OsStr::new("--sockets=recreate"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert!(matches!(
+        fs::file_type(&destination).unwrap(),
+        fs::FileType::Socket
+    ));
+}
+
 #[test]
 fn fifo() {
     initialize();
@@ -139,17 +174,64 @@ fn character_device() {
     let mut output = fs::open(destination).unwrap();
     let mut output_contents = Vec::with_capacity(contents.len());
     output.read_to_end(&mut output_contents).unwrap();
+    // The terminal, rather than fcp, translates the carriage return into a newline.
     assert_eq!(
         String::from_utf8(output_contents).unwrap(),
         contents.replace('\r', "\n")
     );
 }
 
+#[test]
+fn character_device_byte_exact() {
+    initialize();
+    let destination = COPIES_DIR.join("character_device_byte_exact");
+    remove(&destination);
+    let contents = "Hello world\r";
+    // With the terminal no longer translating carriage returns, the copy is exactly what was
+    // typed.
+    let result = Command::new("tests/character_device.exp")
+        .args(&[
+            fcp_executable_path().to_str().unwrap(),
+            destination.to_str().unwrap(),
+            contents,
+            "-icrnl",
+        ])
+        .output()
+        .unwrap();
+    assert!(result.status.success());
+    assert_eq!(String::from_utf8(result.stderr).unwrap(), "");
+    assert_eq!(std::fs::read(&destination).unwrap(), contents.as_bytes());
+}
+
 #[test]
 fn too_few_arguments() {
     initialize();
-    assert!(!fcp_run::<&str>(&[]).success);
-    assert!(!fcp_run(&["source"]).success);
+    let list = COPIES_DIR.join("too_few_arguments.list");
+    remove(&list);
+    writeln!(fs::create(&list, FILE_MODE).unwrap(), "source").unwrap();
+    let empty_list = COPIES_DIR.join("too_few_arguments.empty");
+    remove(&empty_list);
+    fs::create(&empty_list, FILE_MODE).unwrap();
+    for (args, message) in [
+        (vec![], "No sources or destination specified"),
+        (vec!["source"], "No destination specified for 'source'"),
+        (vec!["--to", "dest"], "No sources specified"),
+        (
+            vec!["--files-from", list.to_str().unwrap()],
+            "No destination specified for the sources listed with '--files-from'",
+        ),
+        (
+            vec!["--files-from", empty_list.to_str().unwrap(), "dest"],
+            "No sources specified",
+        ),
+    ] {
+        let result = fcp_run(&args);
+        assert!(!result.success);
+        assert_eq!(
+            result.stderr,
+            format!("{} (run 'fcp --help' for details)\n", message)
+        );
+    }
 }
 
 #[test]
@@ -350,6 +432,19 @@ fn prevent_copying_into_self() {
     assert!(result.stderr.contains("Cannot overwrite file"));
 }
 
+#[test]
+fn prevent_copying_into_own_subdirectory() {
+    initialize();
+    let source = HYDRATED_DIR.join("prevent_copying_into_own_subdirectory");
+    remove(&source);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    fs::create(source.join("file"), FILE_MODE).unwrap();
+    let result = fcp_run(&[&source, &source.join("sub")]);
+    assert!(!result.success);
+    assert!(result.stderr.contains("Cannot copy directory"));
+    assert!(!source.join("sub").exists());
+}
+
 #[test]
 fn prevent_duplicate_sources() {
     initialize();
@@ -378,3 +473,2956 @@ fn prevent_duplicate_sources() {
     assert!(!result.success);
     assert!(result.stderr.contains("paths have the same file name"));
 }
+
+#[test]
+fn explain() {
+    initialize();
+    let source = HYDRATED_DIR.join("explain");
+    let destination = COPIES_DIR.join("explain");
+    remove(&source);
+    remove(&destination);
+    fs::create(&source, FILE_MODE).unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--explain"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert!(result
+        .stdout
+        .starts_with(&format!("copied '{}' via ", source.display())));
+    assert!(destination.exists());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn copy_file_range_across_filesystems() {
+    initialize();
+    // Older kernels fail `copy_file_range` with `EXDEV` between filesystems, and tmpfs supports
+    // it not at all on some, either of which must fall back to copying through userspace.
+    let shm = Path::new("/dev/shm");
+    if !shm.is_dir() {
+        return;
+    }
+    let source = shm.join(format!("fcp-copy_file_range-{}", std::process::id()));
+    let destination = COPIES_DIR.join("copy_file_range_across_filesystems");
+    remove(&destination);
+    let contents = (0..3 << 20).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    std::fs::write(&source, &contents).unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--explain"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    std::fs::remove_file(&source).unwrap();
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert!(
+        result.stdout.ends_with(" via copy_file_range\n")
+            || result.stdout.ends_with(" via sendfile\n")
+            || result.stdout.ends_with(" via buffered read/write\n")
+    );
+    assert_eq!(std::fs::read(&destination).unwrap(), contents);
+}
+
+#[test]
+fn io_uring() {
+    initialize();
+    let source = HYDRATED_DIR.join("io_uring");
+    let destination = COPIES_DIR.join("io_uring");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    for i in 0..200 {
+        let contents = format!("{}", i).repeat(i + 1);
+        std::fs::write(source.join(format!("{}.txt", i)), contents).unwrap();
+    }
+    std::fs::write(source.join("large"), vec![1; 1 << 20]).unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--io-uring"),
+        OsStr::new("--explain"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    if !cfg!(all(feature = "io_uring", target_os = "linux")) {
+        assert!(!result.success);
+        assert!(result
+            .stderr
+            .contains("fcp was built without io_uring support"));
+        return;
+    }
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert!(diff("io_uring.json").success());
+    // Only the small files are copied through io_uring, where it's available at all.
+    let large = format!("copied '{}' via io_uring", source.join("large").display());
+    assert!(!result.stdout.contains(&large));
+}
+
+#[test]
+fn mechanism_for() {
+    initialize();
+    let source = HYDRATED_DIR.join("mechanism_for");
+    let destination = COPIES_DIR.join("mechanism_for");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    write!(
+        fs::create(source.join("file.txt"), FILE_MODE).unwrap(),
+        "text"
+    )
+    .unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--explain"),
+        OsStr::new("--mechanism-for"),
+        OsStr::new("*.t?t=buffered"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert_eq!(
+        result.stdout,
+        format!(
+            "copied '{}' via buffered read/write\n",
+            source.join("file.txt").display()
+        )
+    );
+    if cfg!(target_os = "linux") {
+        remove(&destination);
+        let result = fcp_run(&[
+            OsStr::new("--explain"),
+            OsStr::new("--mechanism-for=*.txt=sendfile"),
+            source.as_os_str(),
+            destination.as_os_str(),
+        ]);
+        assert!(result.success);
+        assert_eq!(result.stderr, "");
+        assert_eq!(
+            result.stdout,
+            format!(
+                "copied '{}' via sendfile\n",
+                source.join("file.txt").display()
+            )
+        );
+        assert_eq!(
+            std::fs::read(destination.join("file.txt")).unwrap(),
+            b"text"
+        );
+    }
+    remove(&destination);
+    let result = fcp_run(&[
+        OsStr::new("--explain"),
+        OsStr::new("--mechanism-for=*.txt=mmap"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert_eq!(
+        result.stdout,
+        format!("copied '{}' via mmap\n", source.join("file.txt").display())
+    );
+    assert_eq!(
+        std::fs::read(destination.join("file.txt")).unwrap(),
+        b"text"
+    );
+    if cfg!(any(target_os = "linux", target_os = "macos")) {
+        remove(&destination);
+        let result = fcp_run(&[
+            OsStr::new("--explain"),
+            OsStr::new("--mechanism-for=*.txt=reflink"),
+            source.as_os_str(),
+            destination.as_os_str(),
+        ]);
+        // As with `--reflink=always`, the file is either cloned or not copied at all, depending on
+        // the filesystem the tests are run on.
+        if result.success {
+            assert_eq!(result.stderr, "");
+            assert!(
+                result.stdout.contains(" via FICLONE\n")
+                    || result.stdout.contains(" via clonefile\n")
+            );
+            assert_eq!(
+                std::fs::read(destination.join("file.txt")).unwrap(),
+                b"text"
+            );
+        } else {
+            assert!(result.stderr.contains("couldn't be cloned to"));
+            assert!(!destination.join("file.txt").exists());
+        }
+    }
+    let result = fcp_run(&[
+        OsStr::new("--mechanism-for=*.txt=splice"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert!(result
+        .stderr
+        .starts_with("Invalid value '*.txt=splice' for option '--mechanism-for'"));
+}
+
+#[test]
+fn readdir_batch_small() {
+    initialize();
+    let source = HYDRATED_DIR.join("readdir_batch_small");
+    let destination = COPIES_DIR.join("readdir_batch_small");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir_all(source.join("dir")).unwrap();
+    // 45 entries in all, which is six full batches of 7 and a final partial one of 3, with a
+    // directory among them which is itself copied in batches.
+    for i in 0..40 {
+        std::fs::write(source.join(i.to_string()), i.to_string()).unwrap();
+    }
+    for i in 0..10 {
+        std::fs::write(source.join("dir").join(i.to_string()), i.to_string()).unwrap();
+    }
+    fs::symlink("0", source.join("link")).unwrap();
+    fs::create_dir(source.join("empty"), DIR_MODE).unwrap();
+    std::fs::write(source.join("large"), vec![b'a'; 100_000]).unwrap();
+    fs::create(source.join("zero"), FILE_MODE).unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--readdir-batch=7"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert!(diff("readdir_batch_small.json").success());
+    assert_eq!(fs::read_dir(&destination).unwrap().count(), 45);
+}
+
+#[test]
+#[ignore]
+fn readdir_batch() {
+    initialize();
+    let source = HYDRATED_DIR.join("readdir_batch");
+    let destination = COPIES_DIR.join("readdir_batch");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    for i in 0..200_000 {
+        fs::create(source.join(i.to_string()), FILE_MODE).unwrap();
+    }
+    let result = fcp_run(&[
+        OsStr::new("--readdir-batch"),
+        OsStr::new("1000"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert!(diff("readdir_batch.json").success());
+}
+
+#[test]
+fn jobs() {
+    initialize();
+    let source = HYDRATED_DIR.join("jobs");
+    let destination = COPIES_DIR.join("jobs");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    for i in 0..100 {
+        std::fs::write(source.join(i.to_string()), i.to_string()).unwrap();
+    }
+    for jobs in &["--jobs=1", "-j"] {
+        remove(&destination);
+        let mut args = vec![OsStr::new(jobs)];
+        if *jobs == "-j" {
+            args.push(OsStr::new("3"));
+        }
+        args.extend(&[source.as_os_str(), destination.as_os_str()]);
+        let result = fcp_run(&args);
+        assert!(result.success);
+        assert_eq!(result.stderr, "");
+        assert!(diff("jobs.json").success());
+    }
+    let result = fcp_run(&[
+        OsStr::new("--jobs=0"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert!(result
+        .stderr
+        .contains("Invalid value '0' for option '--jobs'"));
+}
+
+#[test]
+fn batch_bytes() {
+    initialize();
+    let source = HYDRATED_DIR.join("batch_bytes");
+    let destination = COPIES_DIR.join("batch_bytes");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir_all(source.join("dir")).unwrap();
+    for i in 0..300 {
+        std::fs::write(source.join(i.to_string()), vec![b'a'; i]).unwrap();
+    }
+    std::fs::write(source.join("large"), vec![b'b'; 2 * 1024 * 1024]).unwrap();
+    fs::symlink("0", source.join("link")).unwrap();
+    std::fs::write(source.join("dir").join("file"), "file").unwrap();
+    for batch_bytes in &["--batch-bytes=0", "--batch-bytes=1000", "--batch-bytes=1M"] {
+        remove(&destination);
+        let result = fcp_run(&[
+            OsStr::new(batch_bytes),
+            source.as_os_str(),
+            destination.as_os_str(),
+        ]);
+        assert!(result.success);
+        assert_eq!(result.stderr, "");
+        assert!(diff("batch_bytes.json").success());
+    }
+}
+
+#[test]
+#[ignore]
+fn streaming_huge_directory() {
+    initialize();
+    let source = HYDRATED_DIR.join("streaming_huge_directory");
+    let destination = COPIES_DIR.join("streaming_huge_directory");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    // Enough entries to pass the threshold beyond which directories are copied in batches.
+    for i in 0..200_000 {
+        fs::create(source.join(i.to_string()), FILE_MODE).unwrap();
+    }
+    let result = fcp_run(&[source.as_os_str(), destination.as_os_str()]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert_eq!(fs::read_dir(&destination).unwrap().count(), 200_000);
+}
+
+#[test]
+fn no_follow_dest_symlinks() {
+    initialize();
+    let fixture_path = COPIES_DIR.join("no_follow_dest_symlinks");
+    remove(&fixture_path);
+    fs::create_dir(&fixture_path, DIR_MODE).unwrap();
+    let source = fixture_path.join("source");
+    fs::create(&source, FILE_MODE).unwrap();
+    fs::create_dir(fixture_path.join("directory"), DIR_MODE).unwrap();
+    fs::symlink("directory", fixture_path.join("symlink")).unwrap();
+    for destination in [
+        fixture_path.join("symlink"),
+        fixture_path.join("symlink").join("file"),
+    ] {
+        let result = fcp_run(&[
+            OsStr::new("--no-follow-dest-symlinks"),
+            source.as_os_str(),
+            destination.as_os_str(),
+        ]);
+        assert!(!result.success);
+        assert!(result.stderr.contains("refusing to follow symlink"));
+    }
+    assert!(fs::read_dir(fixture_path.join("directory"))
+        .unwrap()
+        .next()
+        .is_none());
+    let destination = fixture_path.join("directory").join("file");
+    let result = fcp_run(&[
+        OsStr::new("--no-follow-dest-symlinks"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert!(destination.exists());
+}
+
+#[test]
+#[cfg_attr(not(target_os = "macos"), ignore)]
+fn resource_fork() {
+    initialize();
+    let source = HYDRATED_DIR.join("resource_fork");
+    let destination = COPIES_DIR.join("resource_fork");
+    remove(&source);
+    let contents = "resource fork contents";
+    fs::create(&source, FILE_MODE).unwrap();
+    let status = Command::new("xattr")
+        .args(&[
+            OsStr::new("-w"),
+            OsStr::new("com.apple.ResourceFork"),
+            OsStr::new(contents),
+            source.as_os_str(),
+        ])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    // The second invocation exercises the path where fcp opens the destination itself.
+    for options in [&[][..], &[OsStr::new("--no-follow-dest-symlinks")][..]] {
+        remove(&destination);
+        let mut args = options.to_vec();
+        args.extend(&[source.as_os_str(), destination.as_os_str()]);
+        let result = fcp_run(&args);
+        assert!(result.success);
+        assert_eq!(result.stderr, "");
+        let output = Command::new("xattr")
+            .args(&[
+                OsStr::new("-p"),
+                OsStr::new("com.apple.ResourceFork"),
+                destination.as_os_str(),
+            ])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap().trim_end(),
+            contents
+        );
+    }
+}
+
+#[test]
+fn json_progress() {
+    initialize();
+    let source = HYDRATED_DIR.join("json_progress");
+    let destination = COPIES_DIR.join("json_progress");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    for (name, contents) in [
+        ("one.txt", "one"),
+        ("two.txt", "two"),
+        ("three.txt", "three"),
+    ] {
+        write!(
+            fs::create(source.join(name), FILE_MODE).unwrap(),
+            "{}",
+            contents
+        )
+        .unwrap();
+    }
+    let result = fcp_run(&[
+        OsStr::new("--json-progress"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    let events = result.stdout.lines().collect::<Vec<_>>();
+    assert_eq!(
+        events.first(),
+        Some(&r#"{"event":"start","total_files":3,"total_bytes":11}"#)
+    );
+    assert_eq!(
+        events.last(),
+        Some(&r#"{"event":"done","files_done":3,"bytes_done":11,"success":true}"#)
+    );
+    assert!(diff("json_progress.json").success());
+}
+
+#[test]
+fn case_collisions() {
+    initialize();
+    let source = HYDRATED_DIR.join("case_collisions");
+    let destination = COPIES_DIR.join("case_collisions");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    fs::create(source.join("File.txt"), FILE_MODE).unwrap();
+    fs::create(source.join("file.txt"), FILE_MODE).unwrap();
+    // Names differing only in case don't collide on a case-sensitive destination.
+    let result = fcp_run(&[
+        OsStr::new("--error-on-case-collision"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert!(destination.join("File.txt").exists());
+    assert!(destination.join("file.txt").exists());
+}
+
+#[test]
+fn summary_only() {
+    initialize();
+    let source = HYDRATED_DIR.join("summary_only");
+    let destination = COPIES_DIR.join("summary_only");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    for (name, contents) in [("one.txt", "one"), ("two.txt", "two")] {
+        write!(
+            fs::create(source.join(name), FILE_MODE).unwrap(),
+            "{}",
+            contents
+        )
+        .unwrap();
+    }
+    let result = fcp_run(&[
+        OsStr::new("--summary-only"),
+        OsStr::new("--explain"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert_eq!(result.stdout, "Copied 2 files (6 bytes), with 0 errors\n");
+    assert!(destination.join("two.txt").exists());
+}
+
+#[test]
+fn json_progress_errors() {
+    initialize();
+    let source = HYDRATED_DIR.join("json_progress_errors");
+    let destination = COPIES_DIR.join("json_progress_errors/missing/file");
+    remove(&source);
+    fs::create(&source, FILE_MODE).unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--json-progress"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(!result.success);
+    let error = result
+        .stdout
+        .lines()
+        .find(|event| event.starts_with(r#"{"event":"error""#))
+        .unwrap();
+    assert!(error.starts_with(&format!(
+        r#"{{"event":"error","path":"{}","file_type":"regular","operation":"copy","errno":2,"#,
+        source.display()
+    )));
+}
+
+#[test]
+fn staging_dir() {
+    initialize();
+    let fixture_file = "staging_dir.json";
+    let staging_dir = COPIES_DIR.join("staging_dir_staging");
+    remove(&staging_dir);
+    fs::create_dir(&staging_dir, DIR_MODE).unwrap();
+    let source = HYDRATED_DIR.join("staging_dir");
+    let destination = COPIES_DIR.join("staging_dir");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    for name in ["one.txt", "two.txt", "three.txt"] {
+        write!(
+            fs::create(source.join(name), FILE_MODE).unwrap(),
+            "{}",
+            name
+        )
+        .unwrap();
+    }
+    let result = fcp_run(&[
+        OsStr::new("--staging-dir"),
+        staging_dir.as_os_str(),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert!(diff(fixture_file).success());
+    assert!(fs::read_dir(&staging_dir).unwrap().next().is_none());
+}
+
+#[test]
+fn newer_than() {
+    initialize();
+    let source = HYDRATED_DIR.join("newer_than");
+    let destination = COPIES_DIR.join("newer_than");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    fs::create_dir(source.join("old_directory"), DIR_MODE).unwrap();
+    for file in ["old.txt", "new.txt", "old_directory/old.txt"] {
+        fs::create(source.join(file), FILE_MODE).unwrap();
+    }
+    for file in ["old.txt", "old_directory/old.txt"] {
+        let status = Command::new("touch")
+            .args(&[
+                OsStr::new("-t"),
+                OsStr::new("200001010000"),
+                source.join(file).as_os_str(),
+            ])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+    let result = fcp_run(&[
+        OsStr::new("--newer-than"),
+        OsStr::new("2010-01-01T00:00:00Z"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert!(destination.join("new.txt").exists());
+    assert!(!destination.join("old.txt").exists());
+    assert!(!destination.join("old_directory").exists());
+}
+
+#[test]
+fn newer_than_invalid_day() {
+    initialize();
+    let source = HYDRATED_DIR.join("newer_than_invalid_day");
+    let destination = COPIES_DIR.join("newer_than_invalid_day");
+    remove(&source);
+    remove(&destination);
+    fs::create(&source, FILE_MODE).unwrap();
+    // February only has a 29th in leap years, and never a 30th or 31st.
+    for (date, valid) in [
+        ("2024-02-29", true),
+        ("2023-02-29", false),
+        ("2024-02-31", false),
+        ("2024-04-31", false),
+    ] {
+        remove(&destination);
+        let result = fcp_run(&[
+            OsStr::new("--newer-than"),
+            OsStr::new(date),
+            source.as_os_str(),
+            destination.as_os_str(),
+        ]);
+        assert_eq!(result.success, valid, "{}", date);
+        if !valid {
+            assert!(result.stderr.contains("Invalid value"), "{}", date);
+        }
+    }
+}
+
+#[test]
+fn newer_than_last_second_before_epoch() {
+    initialize();
+    let source = HYDRATED_DIR.join("newer_than_last_second_before_epoch");
+    let destination = COPIES_DIR.join("newer_than_last_second_before_epoch");
+    remove(&source);
+    remove(&destination);
+    fs::create(&source, FILE_MODE).unwrap();
+    // In UTC, this local time is -1 seconds since the epoch, which `mktime` also returns on
+    // failure.
+    let result = Command::new(fcp_executable_path())
+        .env("TZ", "UTC")
+        .args(&[
+            OsStr::new("--newer-than"),
+            OsStr::new("1969-12-31T23:59:59"),
+            source.as_os_str(),
+            destination.as_os_str(),
+        ])
+        .output()
+        .unwrap();
+    assert!(result.status.success());
+    assert_eq!(String::from_utf8(result.stderr).unwrap(), "");
+    assert!(destination.exists());
+}
+
+#[test]
+fn skip_special_on_error() {
+    initialize();
+    let is_root = Command::new("id").arg("-u").output().unwrap().stdout == b"0\n";
+    if is_root {
+        // Permissions don't apply to root, so the destination can't be made to reject the files.
+        return;
+    }
+    let source = HYDRATED_DIR.join("skip_special_on_error");
+    let destination = COPIES_DIR.join("skip_special_on_error");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    fs::mkfifo(source.join("fifo"), PermissionsExt::from_mode(FILE_MODE)).unwrap();
+    fs::symlink("fifo", source.join("symlink")).unwrap();
+    // The destination directory is created with the same mode as the source, so making the
+    // source read-only means that the FIFO and symlink can't be created in the destination.
+    let set_mode = |mode| std::fs::set_permissions(&source, PermissionsExt::from_mode(mode));
+    set_mode(0o555).unwrap();
+    let strict = fcp_run(&[&source, &destination]);
+    remove(&destination);
+    let lenient = fcp_run(&[
+        OsStr::new("--skip-special-on-error"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    set_mode(DIR_MODE).unwrap();
+    assert!(!strict.success);
+    assert!(strict.stderr.contains("Permission denied"));
+    assert!(lenient.success);
+    assert_eq!(lenient.stderr.matches("Warning: skipping").count(), 2);
+    assert!(fs::read_dir(&destination).unwrap().next().is_none());
+}
+
+#[test]
+#[cfg_attr(not(target_os = "linux"), ignore)]
+fn preserve_caps() {
+    initialize();
+    let is_root = Command::new("id").arg("-u").output().unwrap().stdout == b"0\n";
+    if !is_root || Command::new("setcap").arg("-v").output().is_err() {
+        // Only root can give a file capabilities in the first place.
+        return;
+    }
+    let source = HYDRATED_DIR.join("preserve_caps");
+    let destination = COPIES_DIR.join("preserve_caps");
+    remove(&source);
+    remove(&destination);
+    fs::create(&source, 0o755).unwrap();
+    let status = Command::new("setcap")
+        .args(&[OsStr::new("cap_net_bind_service=+ep"), source.as_os_str()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let result = fcp_run(&[
+        OsStr::new("--preserve=caps"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    let getcap = Command::new("getcap").arg(&destination).output().unwrap();
+    assert!(String::from_utf8(getcap.stdout)
+        .unwrap()
+        .contains("cap_net_bind_service"));
+}
+
+#[test]
+#[cfg_attr(not(target_os = "linux"), ignore)]
+fn preserve_overlay() {
+    use std::os::unix::fs::FileTypeExt;
+
+    initialize();
+    let is_root = Command::new("id").arg("-u").output().unwrap().stdout == b"0\n";
+    if !is_root || Command::new("setfattr").arg("--version").output().is_err() {
+        // Only root can create whiteouts and set trusted attributes in the first place.
+        return;
+    }
+    let source = HYDRATED_DIR.join("preserve_overlay");
+    let destination = COPIES_DIR.join("preserve_overlay");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir_all(source.join("opaque")).unwrap();
+    let status = Command::new("mknod")
+        .args(&[
+            source.join("whiteout").as_os_str(),
+            OsStr::new("c"),
+            OsStr::new("0"),
+            OsStr::new("0"),
+        ])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let status = Command::new("setfattr")
+        .args(&[
+            OsStr::new("-n"),
+            OsStr::new("trusted.overlay.opaque"),
+            OsStr::new("-v"),
+            OsStr::new("y"),
+            source.join("opaque").as_os_str(),
+        ])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let result = fcp_run(&[
+        OsStr::new("--preserve=overlay"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    let whiteout = std::fs::symlink_metadata(destination.join("whiteout")).unwrap();
+    assert!(whiteout.file_type().is_char_device());
+    assert_eq!(whiteout.rdev(), 0);
+    let getfattr = Command::new("getfattr")
+        .args(&[
+            OsStr::new("--only-values"),
+            OsStr::new("-n"),
+            OsStr::new("trusted.overlay.opaque"),
+            destination.join("opaque").as_os_str(),
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(getfattr.stdout, b"y");
+}
+
+#[test]
+fn parents() {
+    initialize();
+    let source = HYDRATED_DIR.join("parents");
+    let destination = COPIES_DIR.join("parents");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir_all(source.join("a/b")).unwrap();
+    fs::create_dir(&destination, DIR_MODE).unwrap();
+    // fcp is run from within `source`, so the destination must not be relative.
+    let destination = destination.canonicalize().unwrap();
+    write!(
+        fs::create(source.join("a/b/c.txt"), FILE_MODE).unwrap(),
+        "c"
+    )
+    .unwrap();
+    let result = Command::new(fcp_executable_path())
+        .args(&[
+            OsStr::new("--parents"),
+            OsStr::new("a/b/c.txt"),
+            destination.as_os_str(),
+        ])
+        .current_dir(&source)
+        .output()
+        .unwrap();
+    assert!(result.status.success());
+    assert_eq!(String::from_utf8(result.stderr).unwrap(), "");
+    assert_eq!(
+        std::fs::read_to_string(destination.join("a/b/c.txt")).unwrap(),
+        "c"
+    );
+}
+
+#[test]
+fn parents_sharing_directories() {
+    initialize();
+    let source = HYDRATED_DIR.join("parents_sharing_directories");
+    let destination = COPIES_DIR.join("parents_sharing_directories");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir_all(source.join("a/b")).unwrap();
+    fs::create_dir(&destination, DIR_MODE).unwrap();
+    // fcp is run from within `source`, so the destination must not be relative.
+    let destination = destination.canonicalize().unwrap();
+    let files = ["a/b/c.txt", "a/b/d.txt", "a/e.txt"];
+    for file in files {
+        write!(
+            fs::create(source.join(file), FILE_MODE).unwrap(),
+            "{}",
+            file
+        )
+        .unwrap();
+    }
+    let result = Command::new(fcp_executable_path())
+        .arg("--parents")
+        .args(&files)
+        .arg(&destination)
+        .current_dir(&source)
+        .output()
+        .unwrap();
+    assert!(result.status.success());
+    assert_eq!(String::from_utf8(result.stderr).unwrap(), "");
+    for file in files {
+        assert_eq!(
+            std::fs::read_to_string(destination.join(file)).unwrap(),
+            file
+        );
+    }
+}
+
+#[test]
+fn parent_mode() {
+    initialize();
+    let source = HYDRATED_DIR.join("parent_mode");
+    let destination = COPIES_DIR.join("parent_mode");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir_all(source.join("a/b")).unwrap();
+    fs::create_dir(&destination, DIR_MODE).unwrap();
+    // fcp is run from within `source`, so the destination must not be relative.
+    let destination = destination.canonicalize().unwrap();
+    for file in ["a/b/c.txt", "a/b/d.txt"] {
+        write!(
+            fs::create(source.join(file), FILE_MODE).unwrap(),
+            "{}",
+            file
+        )
+        .unwrap();
+    }
+    // The directory reached through the symlink has no source directory of its own.
+    fs::symlink("a/b", source.join("link")).unwrap();
+    let set_mode =
+        |path: &Path, mode| std::fs::set_permissions(path, PermissionsExt::from_mode(mode));
+    set_mode(&source.join("a"), 0o750).unwrap();
+    set_mode(&source.join("a/b"), 0o500).unwrap();
+    let status = Command::new("touch")
+        .args(&[
+            OsStr::new("-t"),
+            OsStr::new("200001010000"),
+            source.join("a/b").as_os_str(),
+        ])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let result = Command::new(fcp_executable_path())
+        .args(&[
+            OsStr::new("--parents"),
+            OsStr::new("--parent-mode"),
+            OsStr::new("711"),
+            OsStr::new("a/b/c.txt"),
+            OsStr::new("link/d.txt"),
+            destination.as_os_str(),
+        ])
+        .current_dir(&source)
+        .output()
+        .unwrap();
+    set_mode(&source.join("a/b"), DIR_MODE).unwrap();
+    assert_eq!(String::from_utf8(result.stderr).unwrap(), "");
+    assert!(result.status.success());
+    let metadata = |path: &Path| std::fs::metadata(path).unwrap();
+    assert_eq!(metadata(&destination.join("a")).mode() & 0o7777, 0o750);
+    assert_eq!(metadata(&destination.join("a/b")).mode() & 0o7777, 0o500);
+    assert_eq!(
+        metadata(&destination.join("a/b")).mtime(),
+        metadata(&source.join("a/b")).mtime()
+    );
+    assert_eq!(metadata(&destination.join("link")).mode() & 0o7777, 0o711);
+    assert_eq!(
+        std::fs::read_to_string(destination.join("a/b/c.txt")).unwrap(),
+        "a/b/c.txt"
+    );
+    set_mode(&destination.join("a/b"), DIR_MODE).unwrap();
+}
+
+#[test]
+fn max_entries() {
+    initialize();
+    let source = HYDRATED_DIR.join("max_entries");
+    let destination = COPIES_DIR.join("max_entries");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    for i in 0..10 {
+        fs::create(source.join(format!("{}.txt", i)), FILE_MODE).unwrap();
+    }
+    let result = fcp_run(&[
+        OsStr::new("--max-entries"),
+        OsStr::new("3"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert!(result.stderr.contains("Copy aborted after 3 entries"));
+    // The directory itself counts as one of the entries.
+    assert_eq!(fs::read_dir(&destination).unwrap().count(), 2);
+}
+
+#[test]
+fn to() {
+    initialize();
+    let source = HYDRATED_DIR.join("to");
+    let mirrors = [COPIES_DIR.join("to_first"), COPIES_DIR.join("to_second")];
+    remove(&source);
+    fs::create_dir_all(source.join("nested")).unwrap();
+    for name in ["one.txt", "nested/two.txt"] {
+        write!(
+            fs::create(source.join(name), FILE_MODE).unwrap(),
+            "{}",
+            name
+        )
+        .unwrap();
+    }
+    for mirror in &mirrors {
+        remove(mirror);
+        fs::create_dir(mirror, DIR_MODE).unwrap();
+    }
+    let result = fcp_run(&[
+        OsStr::new("--to"),
+        mirrors[0].as_os_str(),
+        OsStr::new("--to"),
+        mirrors[1].as_os_str(),
+        source.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    for mirror in &mirrors {
+        let status = Command::new("diff")
+            .args(&[
+                OsStr::new("-r"),
+                source.as_os_str(),
+                mirror.join("to").as_os_str(),
+            ])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+}
+
+#[test]
+fn no_clobber_and_ignore_existing() {
+    initialize();
+    let source = HYDRATED_DIR.join("no_clobber_and_ignore_existing");
+    remove(&source);
+    fs::create_dir_all(source.join("directory")).unwrap();
+    for name in ["one.txt", "directory/two.txt", "directory/three.txt"] {
+        write!(fs::create(source.join(name), FILE_MODE).unwrap(), "new").unwrap();
+    }
+    for (option, copies_three) in [("-n", true), ("--ignore-existing", false)] {
+        let destination = COPIES_DIR.join(option.trim_start_matches('-'));
+        remove(&destination);
+        fs::create_dir_all(destination.join("directory")).unwrap();
+        for name in ["one.txt", "directory/two.txt"] {
+            write!(
+                fs::create(destination.join(name), FILE_MODE).unwrap(),
+                "old"
+            )
+            .unwrap();
+        }
+        let result = fcp_run(&[
+            OsStr::new(option),
+            OsStr::new("--summary-only"),
+            source.join("one.txt").as_os_str(),
+            source.join("directory").as_os_str(),
+            destination.as_os_str(),
+        ]);
+        assert!(result.success);
+        assert_eq!(result.stderr, "");
+        // With `--ignore-existing`, the existing directory is itself the second path skipped.
+        let copied = if copies_three {
+            "1 file (3 bytes)"
+        } else {
+            "0 files (0 bytes)"
+        };
+        assert_eq!(
+            result.stdout,
+            format!(
+                "Copied {}, 2 paths skipped as they already existed, with 0 errors\n",
+                copied
+            )
+        );
+        let read = |name| std::fs::read_to_string(destination.join(name)).ok();
+        assert_eq!(read("one.txt").as_deref(), Some("old"));
+        assert_eq!(read("directory/two.txt").as_deref(), Some("old"));
+        assert_eq!(read("directory/three.txt").is_some(), copies_three);
+    }
+}
+
+#[test]
+fn interactive() {
+    initialize();
+    let source = HYDRATED_DIR.join("interactive");
+    remove(&source);
+    fs::create_dir_all(&source).unwrap();
+    for name in ["one.txt", "two.txt", "three.txt"] {
+        write!(fs::create(source.join(name), FILE_MODE).unwrap(), "new").unwrap();
+    }
+    // Which file is asked about first depends on the order in which they're copied, so the
+    // answers given to each run are all the same.
+    for (answers, expected) in [("y\ny\n", "new"), ("n\nn\n", "old"), ("", "old")] {
+        let destination = COPIES_DIR.join("interactive");
+        remove(&destination);
+        fs::create_dir_all(&destination).unwrap();
+        for name in ["one.txt", "two.txt"] {
+            write!(
+                fs::create(destination.join(name), FILE_MODE).unwrap(),
+                "old"
+            )
+            .unwrap();
+        }
+        let mut child = Command::new(fcp_executable_path())
+            .args(&[
+                OsStr::new("-i"),
+                source.join("one.txt").as_os_str(),
+                source.join("two.txt").as_os_str(),
+                source.join("three.txt").as_os_str(),
+                destination.as_os_str(),
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(answers.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        for name in ["one.txt", "two.txt"] {
+            let prompt = format!("overwrite '{}'? ", destination.join(name).display());
+            assert!(stderr.contains(&prompt), "{}", stderr);
+        }
+        let read = |name| std::fs::read_to_string(destination.join(name)).unwrap();
+        assert_eq!(read("one.txt"), expected);
+        assert_eq!(read("two.txt"), expected);
+        assert_eq!(read("three.txt"), "new");
+    }
+}
+
+#[test]
+fn dirs_only_and_skeleton() {
+    initialize();
+    let source = HYDRATED_DIR.join("dirs_only_and_skeleton");
+    remove(&source);
+    fs::create_dir_all(source.join("directory/nested")).unwrap();
+    write!(
+        fs::create(source.join("directory/file.txt"), FILE_MODE).unwrap(),
+        "contents"
+    )
+    .unwrap();
+    fs::symlink("file.txt", source.join("directory/symlink")).unwrap();
+    for (option, file_length) in [("--dirs-only", None), ("--skeleton", Some(0))] {
+        let destination = COPIES_DIR.join(option.trim_start_matches('-'));
+        remove(&destination);
+        let result = fcp_run(&[
+            OsStr::new(option),
+            source.as_os_str(),
+            destination.as_os_str(),
+        ]);
+        assert!(result.success);
+        assert_eq!(result.stderr, "");
+        assert!(destination.join("directory/nested").is_dir());
+        let file = std::fs::metadata(destination.join("directory/file.txt"));
+        assert_eq!(file.ok().map(|metadata| metadata.len()), file_length);
+        let symlink = std::fs::symlink_metadata(destination.join("directory/symlink"));
+        assert_eq!(symlink.is_ok(), file_length.is_some());
+    }
+}
+
+#[test]
+fn order() {
+    initialize();
+    let source = HYDRATED_DIR.join("order");
+    remove(&source);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    for (name, size) in [("small", 1), ("medium", 1000), ("large", 100_000)] {
+        write!(
+            fs::create(source.join(name), FILE_MODE).unwrap(),
+            "{}",
+            "x".repeat(size)
+        )
+        .unwrap();
+    }
+    for order in ["largest-first", "smallest-first", "name"] {
+        let destination = COPIES_DIR.join(format!("order_{}", order));
+        remove(&destination);
+        let result = fcp_run(&[
+            OsStr::new("--order"),
+            OsStr::new(order),
+            source.as_os_str(),
+            destination.as_os_str(),
+        ]);
+        assert!(result.success);
+        assert_eq!(result.stderr, "");
+        let status = Command::new("diff")
+            .args(&[
+                OsStr::new("-r"),
+                source.as_os_str(),
+                destination.as_os_str(),
+            ])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+    let result = fcp_run(&[
+        OsStr::new("--order=random"),
+        source.as_os_str(),
+        COPIES_DIR.join("order_random").as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert!(result.stderr.contains("Invalid value 'random'"));
+}
+
+#[test]
+fn stable_order() {
+    initialize();
+    let source = HYDRATED_DIR.join("stable_order");
+    let destination = COPIES_DIR.join("stable_order");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir_all(source.join("directory")).unwrap();
+    for name in ["b", "a", "directory/c"] {
+        write!(
+            fs::create(source.join(name), FILE_MODE).unwrap(),
+            "{}",
+            name
+        )
+        .unwrap();
+    }
+    let result = fcp_run(&[
+        OsStr::new("--stable-order"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    let status = Command::new("diff")
+        .args(&[
+            OsStr::new("-r"),
+            source.as_os_str(),
+            destination.as_os_str(),
+        ])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let result = fcp_run(&[
+        OsStr::new("--stable-order"),
+        OsStr::new("--readdir-batch=10"),
+        source.as_os_str(),
+        COPIES_DIR.join("stable_order_batched").as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert_eq!(
+        result.stderr,
+        "Options '--stable-order' and '--readdir-batch' cannot be used together\n"
+    );
+}
+
+#[test]
+fn sparse_always() {
+    initialize();
+    let source = HYDRATED_DIR.join("sparse_always");
+    let destination = COPIES_DIR.join("sparse_always");
+    remove(&source);
+    remove(&destination);
+    let mut file = fs::create(&source, FILE_MODE).unwrap();
+    // Literal zeroes rather than a hole, so the source is fully allocated.
+    file.write_all(&vec![0; 4 * 1024 * 1024]).unwrap();
+    file.write_all(b"end").unwrap();
+    file.sync_all().unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--sparse=always"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert_eq!(
+        std::fs::read(&source).unwrap(),
+        std::fs::read(&destination).unwrap()
+    );
+    let blocks = |path: &Path| std::fs::metadata(path).unwrap().blocks();
+    assert!(blocks(&destination) < blocks(&source));
+    remove(&destination);
+    let result = fcp_run(&[
+        OsStr::new("--sparse=always"),
+        OsStr::new("--summary-only"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(
+        result.stdout,
+        "Copied 1 file (4194307 bytes, 3 written and 99% left as holes), with 0 errors\n"
+    );
+}
+
+#[test]
+fn sparse_auto() {
+    initialize();
+    let source = HYDRATED_DIR.join("sparse_auto");
+    let destination = COPIES_DIR.join("sparse_auto");
+    remove(&source);
+    remove(&destination);
+    let mut file = fs::create(&source, FILE_MODE).unwrap();
+    file.write_all(b"start").unwrap();
+    file.set_len(4 * 1024 * 1024).unwrap();
+    file.seek(std::io::SeekFrom::Start(2 * 1024 * 1024))
+        .unwrap();
+    file.write_all(b"middle").unwrap();
+    file.sync_all().unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--sparse=auto"),
+        OsStr::new("--summary-only"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert_eq!(
+        std::fs::read(&source).unwrap(),
+        std::fs::read(&destination).unwrap()
+    );
+    // Only filesystems supporting holes can recreate them.
+    let blocks = |path: &Path| std::fs::metadata(path).unwrap().blocks();
+    if blocks(&source) * 512 < 4 * 1024 * 1024 && cfg!(target_os = "linux") {
+        assert!(blocks(&destination) * 512 < 4 * 1024 * 1024);
+        assert!(result.stdout.contains("left as holes"));
+    }
+}
+
+#[test]
+fn preallocate() {
+    initialize();
+    let source = HYDRATED_DIR.join("preallocate");
+    let destination = COPIES_DIR.join("preallocate");
+    remove(&source);
+    remove(&destination);
+    let contents: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&source, &contents).unwrap();
+    let result = fcp_run(&[source.as_os_str(), destination.as_os_str()]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert_eq!(std::fs::read(&destination).unwrap(), contents);
+    // Preallocating doesn't change the size of the copy, even where it has to be overwritten.
+    std::fs::write(&source, &contents[..2 * 1024 * 1024]).unwrap();
+    let result = fcp_run(&[source.as_os_str(), destination.as_os_str()]);
+    assert!(result.success);
+    assert_eq!(
+        std::fs::read(&destination).unwrap(),
+        &contents[..2 * 1024 * 1024]
+    );
+    remove(&destination);
+    let result = fcp_run(&[
+        OsStr::new("--no-preallocate"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(
+        std::fs::read(&destination).unwrap(),
+        &contents[..2 * 1024 * 1024]
+    );
+}
+
+#[test]
+fn split() {
+    initialize();
+    let source = HYDRATED_DIR.join("split");
+    let destination = COPIES_DIR.join("split");
+    remove(&source);
+    remove(&destination);
+    // Not a multiple of the range size, so the last range is shorter than the rest.
+    let contents: Vec<u8> = (0..5 * 1024 * 1024 + 3).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&source, &contents).unwrap();
+    // Both by whichever mechanism is fastest, and through a buffer.
+    for rule in &["*.none=buffered", "*=buffered"] {
+        remove(&destination);
+        let result = fcp_run(&[
+            OsStr::new("--split=1M"),
+            OsStr::new("--mechanism-for"),
+            OsStr::new(rule),
+            source.as_os_str(),
+            destination.as_os_str(),
+        ]);
+        assert!(result.success);
+        assert_eq!(result.stderr, "");
+        assert_eq!(std::fs::read(&destination).unwrap(), contents);
+    }
+}
+
+#[test]
+fn buffer_size() {
+    initialize();
+    let source = HYDRATED_DIR.join("buffer_size");
+    let destination = COPIES_DIR.join("buffer_size");
+    remove(&source);
+    remove(&destination);
+    let contents: Vec<u8> = (0..1_000_003).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&source, &contents).unwrap();
+    for size in &["--buffer-size=3", "--buffer-size=4M"] {
+        remove(&destination);
+        let result = fcp_run(&[
+            OsStr::new(size),
+            OsStr::new("--mechanism-for"),
+            OsStr::new("*=buffered"),
+            source.as_os_str(),
+            destination.as_os_str(),
+        ]);
+        assert!(result.success);
+        assert_eq!(result.stderr, "");
+        assert_eq!(std::fs::read(&destination).unwrap(), contents);
+    }
+    let result = fcp_run(&[
+        OsStr::new("--buffer-size=0"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert!(result
+        .stderr
+        .contains("Invalid value '0' for option '--buffer-size'"));
+}
+
+#[test]
+fn bwlimit() {
+    initialize();
+    let source = HYDRATED_DIR.join("bwlimit");
+    let destination = COPIES_DIR.join("bwlimit");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    let contents: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    for name in &["a", "b"] {
+        std::fs::write(source.join(name), &contents).unwrap();
+    }
+    let start = std::time::Instant::now();
+    let result = fcp_run(&[
+        OsStr::new("--bwlimit=4M"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert!(diff("bwlimit.json").success());
+    // The limit applies to both files together, even though they're copied at the same time.
+    assert!(start.elapsed() >= std::time::Duration::from_millis(450));
+}
+
+#[test]
+fn attrs_only() {
+    initialize();
+    let source = HYDRATED_DIR.join("attrs_only");
+    let destination = COPIES_DIR.join("attrs_only");
+    remove(&source);
+    remove(&destination);
+    for root in [&source, &destination] {
+        fs::create_dir(root, DIR_MODE).unwrap();
+        write!(
+            fs::create(root.join("file"), FILE_MODE).unwrap(),
+            "{}",
+            root.display()
+        )
+        .unwrap();
+    }
+    let set_mode =
+        |path: &Path, mode| std::fs::set_permissions(path, PermissionsExt::from_mode(mode));
+    set_mode(&source.join("file"), 0o600).unwrap();
+    let status = Command::new("touch")
+        .args(&[
+            OsStr::new("-t"),
+            OsStr::new("200001010000"),
+            source.join("file").as_os_str(),
+        ])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let result = fcp_run(&[
+        OsStr::new("--attrs-only"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    let (source_file, dest_file) = (source.join("file"), destination.join("file"));
+    let metadata = |path: &Path| std::fs::metadata(path).unwrap();
+    assert_eq!(metadata(&dest_file).mode() & 0o777, 0o600);
+    assert_eq!(metadata(&dest_file).mtime(), metadata(&source_file).mtime());
+    // Contents are left alone.
+    assert_eq!(
+        std::fs::read_to_string(&dest_file).unwrap(),
+        destination.display().to_string()
+    );
+    // A source path without a counterpart in the destination is an error.
+    fs::create(source.join("missing"), FILE_MODE).unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--attrs-only"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert!(result.stderr.contains("no destination corresponding to"));
+    assert!(!destination.join("missing").exists());
+}
+
+#[test]
+fn copy_fds() {
+    initialize();
+    let source = HYDRATED_DIR.join("copy_fds");
+    let destination = COPIES_DIR.join("copy_fds");
+    remove(&source);
+    remove(&destination);
+    write!(fs::create(&source, FILE_MODE).unwrap(), "hello world").unwrap();
+    let mut source_file = fs::open(&source).unwrap();
+    let dest_file = fs::create(&destination, FILE_MODE).unwrap();
+    let content = fs::ContentOptions::default();
+    source_file.seek(std::io::SeekFrom::Start(6)).unwrap();
+    let (copied, _) = fs::copy_fds(&source_file, &dest_file, Some(3), content).unwrap();
+    assert_eq!(copied, 3);
+    assert_eq!(std::fs::read_to_string(&destination).unwrap(), "wor");
+    // Without a length, the rest of the source is copied from where the last copy left off.
+    let (copied, _) = fs::copy_fds(&source_file, &dest_file, None, content).unwrap();
+    assert_eq!(copied, 2);
+    assert_eq!(std::fs::read_to_string(&destination).unwrap(), "world");
+}
+
+#[test]
+fn path_mapper() {
+    initialize();
+    let source = HYDRATED_DIR.join("path_mapper");
+    let destination = COPIES_DIR.join("path_mapper");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir_all(source.join("Sub")).unwrap();
+    fs::create(source.join("Sub/File.TXT"), FILE_MODE).unwrap();
+    fs::create_dir(&destination, DIR_MODE).unwrap();
+    let options = fcp::options::CopyOptions {
+        path_mapper: Some(fcp::options::PathMapper(Box::new(|source: &Path| {
+            source
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_lowercase()
+                .into()
+        }))),
+        ..Default::default()
+    };
+    let dests = std::slice::from_ref(&destination);
+    let has_err = fcp::fcp_with_options(&[source], dests, &options).unwrap();
+    assert!(!has_err);
+    assert!(destination.join("path_mapper/sub/file.txt").exists());
+}
+
+#[test]
+fn fsync() {
+    initialize();
+    let source = HYDRATED_DIR.join("fsync");
+    let destination = COPIES_DIR.join("fsync");
+    let staging = COPIES_DIR.join("fsync_staging");
+    remove(&source);
+    remove(&destination);
+    remove(&staging);
+    fs::create_dir_all(source.join("dir")).unwrap();
+    fs::create_dir(&staging, DIR_MODE).unwrap();
+    write!(
+        fs::create(source.join("dir/file"), FILE_MODE).unwrap(),
+        "file"
+    )
+    .unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--fsync"),
+        OsStr::new("--staging-dir"),
+        staging.as_os_str(),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert_eq!(
+        std::fs::read_to_string(destination.join("dir/file")).unwrap(),
+        "file"
+    );
+}
+
+#[test]
+fn min_and_max_size() {
+    initialize();
+    let source = HYDRATED_DIR.join("min_and_max_size");
+    let destination = COPIES_DIR.join("min_and_max_size");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir_all(source.join("dir")).unwrap();
+    for (name, size) in [("small", 1), ("dir/medium", 100), ("large", 2048)] {
+        let file = fs::create(source.join(name), FILE_MODE).unwrap();
+        file.set_len(size).unwrap();
+    }
+    let result = fcp_run(&[
+        OsStr::new("--min-size=10"),
+        OsStr::new("--max-size=1K"),
+        OsStr::new("--summary-only"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert_eq!(
+        result.stdout,
+        "Copied 1 file (100 bytes), 2 files skipped for their size, with 0 errors\n"
+    );
+    assert!(destination.join("dir/medium").exists());
+    assert!(!destination.join("small").exists());
+    assert!(!destination.join("large").exists());
+}
+
+#[test]
+fn progress_tree() {
+    initialize();
+    let source = HYDRATED_DIR.join("progress_tree");
+    let destination = COPIES_DIR.join("progress_tree");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    fs::create(source.join("file"), FILE_MODE).unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--progress=tree"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stdout, "");
+    assert!(destination.join("file").exists());
+    let result = fcp_run(&[
+        OsStr::new("--progress=bar"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert_eq!(
+        result.stderr,
+        "Invalid value 'bar' for option '--progress': expected 'tree'\n"
+    );
+}
+
+#[test]
+fn config_file() {
+    initialize();
+    let source = HYDRATED_DIR.join("config_file");
+    // fcp is run from within `source`, so neither the destination nor the config file given with
+    // `--config` may be relative.
+    let destination = COPIES_DIR.canonicalize().unwrap().join("config_file");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    let source = source.canonicalize().unwrap();
+    fs::create(source.join("file"), FILE_MODE).unwrap();
+    write!(
+        fs::create(source.join(".fcp.toml"), FILE_MODE).unwrap(),
+        "# Shared settings\nsummary_only = true\nmechanism-for = [\"*=buffered\"]\n"
+    )
+    .unwrap();
+    let run = |args: &[&OsStr]| {
+        remove(&destination);
+        let output = Command::new(fcp_executable_path())
+            .args(args)
+            .args(&[OsStr::new("file"), destination.as_os_str()])
+            .current_dir(&source)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    assert_eq!(run(&[]), "Copied 1 file (0 bytes), with 0 errors\n");
+    assert_eq!(run(&[OsStr::new("--no-config")]), "");
+    let config = source.join(".fcp.toml");
+    let explain = run(&[
+        OsStr::new("--explain"),
+        OsStr::new("--config"),
+        config.as_os_str(),
+    ]);
+    assert_eq!(explain, "Copied 1 file (0 bytes), with 0 errors\n");
+    writeln!(fs::create(&config, FILE_MODE).unwrap(), "explain = yes").unwrap();
+    let result = Command::new(fcp_executable_path())
+        .args(&[OsStr::new("file"), destination.as_os_str()])
+        .current_dir(&source)
+        .output()
+        .unwrap();
+    assert!(!result.status.success());
+    assert_eq!(
+        String::from_utf8(result.stderr).unwrap(),
+        format!(
+            "{}:1: invalid value (expected 'option = value')\n",
+            Path::new(".fcp.toml").display()
+        )
+    );
+}
+
+#[test]
+fn growing_file() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    initialize();
+    let source = HYDRATED_DIR.join("growing_file");
+    let destination = COPIES_DIR.join("growing_file");
+    remove(&source);
+    remove(&destination);
+    let chunk = vec![1; 64 * 1024];
+    let mut file = fs::create(&source, FILE_MODE).unwrap();
+    for _ in 0..1024 {
+        file.write_all(&chunk).unwrap();
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    let appender = thread::spawn({
+        let stop = Arc::clone(&stop);
+        move || {
+            while !stop.load(Ordering::Relaxed) {
+                file.write_all(&chunk).unwrap();
+            }
+        }
+    });
+    let result = fcp_run(&[source.as_os_str(), destination.as_os_str()]);
+    stop.store(true, Ordering::Relaxed);
+    appender.join().unwrap();
+    assert!(result.success);
+    // The copy is a snapshot of some moment during the copy rather than of wherever the end of the
+    // source happened to be when copying stopped, and any difference is warned about.
+    let (source, destination) = (
+        std::fs::read(source).unwrap(),
+        std::fs::read(destination).unwrap(),
+    );
+    assert!(destination.len() >= 64 * 1024 * 1024);
+    assert_eq!(destination, source[..destination.len()]);
+    if !result.stderr.is_empty() {
+        assert!(result.stderr.starts_with("Warning: "));
+        assert!(result.stderr.ends_with(&format!(
+            "while being copied, so {} bytes of it were copied\n",
+            destination.len()
+        )));
+    }
+}
+
+#[test]
+fn no_recursive() {
+    initialize();
+    let source = HYDRATED_DIR.join("no_recursive");
+    let destination = COPIES_DIR.join("no_recursive");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir_all(source.join("dir")).unwrap();
+    fs::create(source.join("file"), FILE_MODE).unwrap();
+    fs::create_dir(&destination, DIR_MODE).unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--no-recursive"),
+        source.join("dir").as_os_str(),
+        source.join("file").as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert_eq!(
+        result.stderr,
+        format!(
+            "-R not specified; omitting directory '{}'\n",
+            source.join("dir").display()
+        )
+    );
+    assert!(destination.join("file").exists());
+    assert!(!destination.join("dir").exists());
+}
+
+#[test]
+fn log_file() {
+    initialize();
+    let source = HYDRATED_DIR.join("log_file");
+    let destination = COPIES_DIR.join("log_file");
+    let log_file = COPIES_DIR.join("log_file.log");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    fs::create_dir(&destination, DIR_MODE).unwrap();
+    fs::create(source.join("copied"), FILE_MODE).unwrap();
+    fs::create(source.join("existing"), FILE_MODE).unwrap();
+    fs::create(destination.join("existing"), FILE_MODE).unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--no-clobber"),
+        OsStr::new("--log-file"),
+        log_file.as_os_str(),
+        source.join("copied").as_os_str(),
+        source.join("existing").as_os_str(),
+        source.join("missing").as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(!result.success);
+    let mut records = std::fs::read_to_string(&log_file)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            assert!(line.starts_with(r#"{"timestamp":"#));
+            line[line.find(r#","action""#).unwrap()..].to_string()
+        })
+        .collect::<Vec<_>>();
+    records.sort();
+    assert_eq!(records.len(), 3);
+    assert_eq!(
+        records[0],
+        format!(
+            r#","action":"copied","path":"{}","result":"ok"}}"#,
+            source.join("copied").display()
+        )
+    );
+    assert!(records[1].starts_with(&format!(
+        r#","action":"failed","path":"{}","result":"#,
+        source.join("missing").display()
+    )));
+    assert_eq!(
+        records[2],
+        format!(
+            r#","action":"skipped","path":"{}","result":"already exists"}}"#,
+            source.join("existing").display()
+        )
+    );
+}
+
+#[test]
+#[cfg_attr(not(target_os = "linux"), ignore)]
+fn one_file_system() {
+    initialize();
+    let is_root = Command::new("id").arg("-u").output().unwrap().stdout == b"0\n";
+    if !is_root {
+        // Only root can mount a filesystem to nest within the source.
+        return;
+    }
+    let source = HYDRATED_DIR.join("one_file_system");
+    let destination = COPIES_DIR.join("one_file_system");
+    let mount_point = source.join("mnt");
+    let _ = Command::new("umount").arg(&mount_point).status();
+    remove(&source);
+    remove(&destination);
+    fs::create_dir_all(&mount_point).unwrap();
+    let mounted = Command::new("mount")
+        .args(&[OsStr::new("-t"), OsStr::new("tmpfs"), OsStr::new("tmpfs")])
+        .arg(&mount_point)
+        .status()
+        .is_ok_and(|status| status.success());
+    if !mounted {
+        return;
+    }
+    fs::create_dir(mount_point.join("dir"), DIR_MODE).unwrap();
+    fs::create(mount_point.join("dir/file"), FILE_MODE).unwrap();
+    std::fs::set_permissions(&mount_point, PermissionsExt::from_mode(0o750)).unwrap();
+    fs::create(source.join("file"), FILE_MODE).unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--one-file-system"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    Command::new("umount").arg(&mount_point).status().unwrap();
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert!(destination.join("file").exists());
+    let copied_mount_point = fs::metadata(destination.join("mnt")).unwrap();
+    assert!(copied_mount_point.is_dir());
+    assert_eq!(copied_mount_point.permissions().mode() & 0o777, 0o750);
+    assert!(fs::read_dir(destination.join("mnt"))
+        .unwrap()
+        .next()
+        .is_none());
+}
+
+#[test]
+fn descriptor_limit() {
+    initialize();
+    let source = HYDRATED_DIR.join("descriptor_limit");
+    let destination = COPIES_DIR.join("descriptor_limit");
+    remove(&source);
+    remove(&destination);
+    // A tree both deep and wide, so that copying all of it at once would need far more
+    // descriptors than the limit allows.
+    let mut dir = source.clone();
+    for depth in 0..20 {
+        fs::create_dir_all(&dir).unwrap();
+        for i in 0..20 {
+            std::fs::write(dir.join(format!("file{}", i)), format!("{} {}", depth, i)).unwrap();
+        }
+        dir = dir.join("dir");
+    }
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(r#"ulimit -n 96 && "$0" --jobs=64 "$1" "$2""#)
+        .args(&[
+            fcp_executable_path().as_os_str(),
+            source.as_os_str(),
+            destination.as_os_str(),
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+    assert!(output.status.success());
+    assert!(diff("descriptor_limit.json").success());
+}
+
+#[test]
+fn progress_to_fd() {
+    initialize();
+    let source = HYDRATED_DIR.join("progress_to_fd");
+    let destination = COPIES_DIR.join("progress_to_fd");
+    let events = COPIES_DIR.join("progress_to_fd.events");
+    remove(&source);
+    remove(&destination);
+    write!(fs::create(&source, FILE_MODE).unwrap(), "progress").unwrap();
+    // The shell opens file descriptor 3 for fcp, as a parent process embedding it would.
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(r#""$0" --progress-to-fd 3 "$1" "$2" 3>"$3""#)
+        .args(&[
+            fcp_executable_path().as_os_str(),
+            source.as_os_str(),
+            destination.as_os_str(),
+            events.as_os_str(),
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    let events = std::fs::read_to_string(&events).unwrap();
+    let events = events.lines().collect::<Vec<_>>();
+    assert_eq!(
+        events.first(),
+        Some(&r#"{"event":"start","total_files":1,"total_bytes":8}"#)
+    );
+    assert_eq!(
+        events.last(),
+        Some(&r#"{"event":"done","files_done":1,"bytes_done":8,"success":true}"#)
+    );
+
+    let result = fcp_run(&[
+        OsStr::new("--progress-to-fd=99"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert!(result.stderr.contains("File descriptor 99: "));
+}
+
+#[test]
+fn files_from() {
+    initialize();
+    let source = HYDRATED_DIR.join("files_from");
+    let destination = COPIES_DIR.join("files_from");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    fs::create_dir(&destination, DIR_MODE).unwrap();
+    for name in ["listed", "operand", "neither"] {
+        fs::create(source.join(name), FILE_MODE).unwrap();
+    }
+    let list = source.join("neither.list");
+    write!(
+        fs::create(&list, FILE_MODE).unwrap(),
+        "{}\n\n",
+        source.join("listed").display()
+    )
+    .unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--files-from"),
+        list.as_os_str(),
+        source.join("operand").as_os_str(),
+        OsStr::new("-t"),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert!(destination.join("listed").exists());
+    assert!(destination.join("operand").exists());
+    assert!(!destination.join("neither").exists());
+}
+
+#[test]
+fn hard_links() {
+    initialize();
+    let source = HYDRATED_DIR.join("hard_links");
+    let destination = COPIES_DIR.join("hard_links");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir_all(source.join("nested")).unwrap();
+    std::fs::write(source.join("file"), "linked").unwrap();
+    std::fs::hard_link(source.join("file"), source.join("link")).unwrap();
+    std::fs::hard_link(source.join("file"), source.join("nested/link")).unwrap();
+    std::fs::write(source.join("other"), "linked").unwrap();
+    let result = fcp_run(&[source.as_os_str(), destination.as_os_str()]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert!(diff("hard_links.json").success());
+    let metadata = |path: &Path| std::fs::metadata(path).unwrap();
+    let check_links = |copy: &Path| {
+        assert_eq!(metadata(&copy.join("file")).nlink(), 3);
+        assert_eq!(
+            metadata(&copy.join("link")).ino(),
+            metadata(&copy.join("file")).ino()
+        );
+        assert_eq!(
+            metadata(&copy.join("nested/link")).ino(),
+            metadata(&copy.join("file")).ino()
+        );
+        assert_eq!(metadata(&copy.join("other")).nlink(), 1);
+    };
+    check_links(&destination);
+    // Each destination given with `--to` gets links to its own copy.
+    let mirrors = [
+        COPIES_DIR.join("hard_links_to_1"),
+        COPIES_DIR.join("hard_links_to_2"),
+    ];
+    for mirror in &mirrors {
+        remove(mirror);
+        fs::create_dir(mirror, DIR_MODE).unwrap();
+    }
+    let result = fcp_run(&[
+        OsStr::new("--to"),
+        mirrors[0].as_os_str(),
+        OsStr::new("--to"),
+        mirrors[1].as_os_str(),
+        source.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    for mirror in &mirrors {
+        check_links(&mirror.join("hard_links"));
+    }
+}
+
+#[test]
+fn preserve_context() {
+    initialize();
+    let source = HYDRATED_DIR.join("preserve_context");
+    let destination = COPIES_DIR.join("preserve_context");
+    remove(&source);
+    remove(&destination);
+    fs::create(&source, FILE_MODE).unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--preserve=context"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    if cfg!(any(feature = "selinux", target_os = "linux")) {
+        // Without SELinux enabled this is a no-op, so the copy succeeds either way.
+        assert!(result.success);
+        assert_eq!(result.stderr, "");
+        assert!(destination.exists());
+        if Path::new("/sys/fs/selinux/enforce").exists() {
+            let context = |path: &Path| {
+                let output = Command::new("stat").arg("-c%C").arg(path).output().unwrap();
+                output.stdout
+            };
+            assert_eq!(context(&destination), context(&source));
+        }
+    } else {
+        assert!(!result.success);
+        assert!(result.stderr.contains("SELinux is only supported on Linux"));
+        assert!(!destination.exists());
+    }
+}
+
+#[test]
+fn report_sizes() {
+    initialize();
+    let source = HYDRATED_DIR.join("report_sizes");
+    let destination = COPIES_DIR.join("report_sizes");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir_all(source.join("dir/nested")).unwrap();
+    fs::create_dir(&destination, DIR_MODE).unwrap();
+    for (name, contents) in [
+        ("dir/one.txt", "one"),
+        ("dir/nested/three.txt", "three"),
+        ("file.txt", "file"),
+    ] {
+        write!(
+            fs::create(source.join(name), FILE_MODE).unwrap(),
+            "{}",
+            contents
+        )
+        .unwrap();
+    }
+    let result = fcp_run(&[
+        OsStr::new("--report-sizes"),
+        OsStr::new("--summary-only"),
+        source.join("dir").as_os_str(),
+        source.join("file.txt").as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert_eq!(
+        result.stdout,
+        format!(
+            "{}: 2 files, 8 bytes\n{}: 1 file, 4 bytes\nCopied 3 files (12 bytes), with 0 errors\n",
+            source.join("dir").display(),
+            source.join("file.txt").display()
+        )
+    );
+}
+
+#[test]
+fn swap() {
+    initialize();
+    let source = HYDRATED_DIR.join("swap");
+    let parent = COPIES_DIR.join("swap");
+    let destination = parent.join("published");
+    remove(&source);
+    remove(&parent);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    for name in ["one.txt", "two.txt", "three.txt"] {
+        fs::create(source.join(name), FILE_MODE).unwrap();
+    }
+    fs::create_dir_all(&destination).unwrap();
+    fs::create(destination.join("old.txt"), FILE_MODE).unwrap();
+    let published = || {
+        let mut names = fs::read_dir(&destination)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+    let siblings = || fs::read_dir(&parent).unwrap().count();
+
+    // A copy cut short is never published, and leaves nothing behind.
+    let result = fcp_run(&[
+        OsStr::new("--swap"),
+        OsStr::new("--max-entries=2"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert_eq!(published(), ["old.txt"]);
+    assert_eq!(siblings(), 1);
+
+    let result = fcp_run(&[
+        OsStr::new("--swap"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert_eq!(published(), ["one.txt", "three.txt", "two.txt"]);
+    assert_eq!(siblings(), 1);
+}
+
+#[test]
+fn ignore_errors_matching() {
+    initialize();
+    let source = HYDRATED_DIR.join("ignore_errors_matching");
+    let destination = COPIES_DIR.join("ignore_errors_matching");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    fs::create_dir(&destination, DIR_MODE).unwrap();
+    fs::create(source.join("file"), FILE_MODE).unwrap();
+    let copy = |rule: &str| {
+        fcp_run(&[
+            OsStr::new("--ignore-errors-matching"),
+            OsStr::new(rule),
+            source.join("missing").as_os_str(),
+            source.join("file").as_os_str(),
+            destination.as_os_str(),
+        ])
+    };
+    for rule in ["ENOENT", "*/missing=enoent", "*/missing=2"] {
+        let result = copy(rule);
+        assert!(result.success);
+        assert!(result.stderr.starts_with("Warning: ignoring error: "));
+        assert!(destination.join("file").exists());
+    }
+    for rule in ["EACCES", "*/other=ENOENT"] {
+        assert!(!copy(rule).success);
+    }
+    let result = copy("*=ENOTANERROR");
+    assert!(!result.success);
+    assert!(result
+        .stderr
+        .starts_with("Invalid value '*=ENOTANERROR' for option '--ignore-errors-matching'"));
+}
+
+#[test]
+fn dry_run() {
+    initialize();
+    let source = HYDRATED_DIR.join("dry_run");
+    let destination = COPIES_DIR.join("dry_run");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir_all(source.join("dir")).unwrap();
+    fs::create(source.join("dir/file"), FILE_MODE).unwrap();
+    fs::create_dir(&destination, DIR_MODE).unwrap();
+
+    let result = fcp_run(&[
+        OsStr::new("--dry-run"),
+        source.join("dir").as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert_eq!(
+        result.stdout,
+        format!(
+            "'{}' -> '{}'\n'{}' -> '{}'\n",
+            source.join("dir").display(),
+            destination.join("dir").display(),
+            source.join("dir/file").display(),
+            destination.join("dir/file").display()
+        )
+    );
+    assert!(!destination.join("dir").exists());
+
+    // A directory already in the way is a problem, as is a missing destination directory.
+    fs::create_dir(destination.join("dir"), DIR_MODE).unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--dry-run=check"),
+        source.join("dir").as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert_eq!(
+        result.stderr,
+        format!(
+            "'{}': already exists, so '{}' couldn't be copied to it\n",
+            destination.join("dir").display(),
+            source.join("dir").display()
+        )
+    );
+    let result = fcp_run(&[
+        OsStr::new("--dry-run=check"),
+        source.join("dir").as_os_str(),
+        destination.join("missing/dir").as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert!(result.stderr.starts_with(&format!(
+        "'{}': directory does not exist\n",
+        destination.join("missing").display()
+    )));
+    assert!(fs::read_dir(destination.join("dir"))
+        .unwrap()
+        .next()
+        .is_none());
+}
+
+#[test]
+fn on_error() {
+    use fcp::options::{CopyOptions, ErrorAction, ErrorHandler};
+
+    initialize();
+    let source = HYDRATED_DIR.join("on_error");
+    let destination = COPIES_DIR.join("on_error");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    fs::create_dir(&destination, DIR_MODE).unwrap();
+    fs::create(source.join("file"), FILE_MODE).unwrap();
+    let dests = std::slice::from_ref(&destination);
+
+    // The handler creates the missing file before having its copy retried.
+    let late = source.join("late");
+    let options = CopyOptions {
+        on_error: Some(ErrorHandler(Box::new(|error| {
+            assert_eq!(error.error.raw_os_error(), Some(2));
+            match error.attempt {
+                1 => {
+                    fs::create(error.source, FILE_MODE).unwrap();
+                    ErrorAction::Retry
+                }
+                _ => ErrorAction::Continue,
+            }
+        }))),
+        ..Default::default()
+    };
+    let sources = [late, source.join("file")];
+    let has_err = fcp::fcp_with_options(&sources, dests, &options).unwrap();
+    assert!(!has_err);
+    assert!(destination.join("late").exists());
+
+    let options = CopyOptions {
+        on_error: Some(ErrorHandler(Box::new(|_| ErrorAction::Abort))),
+        ..Default::default()
+    };
+    let sources = [source.join("missing"), source.join("file")];
+    let err = fcp::fcp_with_options(&sources, dests, &options).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Copy aborted by its error handler after an error"
+    );
+}
+
+#[test]
+fn flatten() {
+    initialize();
+    let source = HYDRATED_DIR.join("flatten");
+    remove(&source);
+    for dir in ["a", "b/c"] {
+        fs::create_dir_all(source.join(dir)).unwrap();
+    }
+    for file in ["x.log", "a/x.log", "a/y.log", "b/c/x.log"] {
+        write!(
+            fs::create(source.join(file), FILE_MODE).unwrap(),
+            "{}",
+            file
+        )
+        .unwrap();
+    }
+    fs::symlink("a/y.log", source.join("link")).unwrap();
+    let read = |path: &Path| std::fs::read_to_string(path).unwrap();
+
+    let destination = COPIES_DIR.join("flatten_rename");
+    remove(&destination);
+    let result = fcp_run(&[
+        OsStr::new("--flatten"),
+        OsStr::new("--on-collision=rename"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert_eq!(read(&destination.join("x.log")), "a/x.log");
+    assert_eq!(read(&destination.join("x-1.log")), "b/c/x.log");
+    assert_eq!(read(&destination.join("x-2.log")), "x.log");
+    assert_eq!(read(&destination.join("y.log")), "a/y.log");
+    assert_eq!(std::fs::read_dir(&destination).unwrap().count(), 4);
+
+    let destination = COPIES_DIR.join("flatten_skip");
+    remove(&destination);
+    let result = fcp_run(&[
+        OsStr::new("--flatten"),
+        OsStr::new("--on-collision=skip"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr.lines().count(), 2);
+    assert_eq!(std::fs::read_dir(&destination).unwrap().count(), 2);
+
+    let destination = COPIES_DIR.join("flatten_error");
+    remove(&destination);
+    let result = fcp_run(&[
+        OsStr::new("--flatten"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert!(result.stderr.contains(&format!(
+        "{}: not copied, as '{}' was already copied to '{}'",
+        source.join("b/c/x.log").display(),
+        source.join("a/x.log").display(),
+        destination.join("x.log").display()
+    )));
+    assert_eq!(read(&destination.join("y.log")), "a/y.log");
+}
+
+#[test]
+fn mtime_from() {
+    initialize();
+    let source = HYDRATED_DIR.join("mtime_from");
+    let destination = COPIES_DIR.join("mtime_from");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    for file in ["backup-2021-06-30_1230.tar", "other.tar", "reference"] {
+        fs::create(source.join(file), FILE_MODE).unwrap();
+    }
+    let status = Command::new("touch")
+        .args(&[
+            OsStr::new("-t"),
+            OsStr::new("202106301230"),
+            source.join("reference").as_os_str(),
+        ])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let result = fcp_run(&[
+        OsStr::new("--mtime-from"),
+        OsStr::new("%Y-%m-%d_%H%M"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    let mtime = |path: &Path| std::fs::metadata(path).unwrap().mtime();
+    assert_eq!(
+        mtime(&destination.join("backup-2021-06-30_1230.tar")),
+        mtime(&source.join("reference"))
+    );
+    assert!(mtime(&destination.join("other.tar")) > mtime(&source.join("reference")));
+
+    let result = fcp_run(&[
+        OsStr::new("--mtime-from=%Y-%m"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert!(result
+        .stderr
+        .contains("expected a format containing at least"));
+}
+
+#[test]
+fn dest_component_not_a_directory() {
+    initialize();
+    let source = HYDRATED_DIR.join("dest_component_not_a_directory");
+    let destination = COPIES_DIR.join("dest_component_not_a_directory");
+    remove(&source);
+    remove(&destination);
+    fs::create(&source, FILE_MODE).unwrap();
+    fs::create(&destination, FILE_MODE).unwrap();
+    let result = fcp_run(&[source.as_os_str(), destination.join("file").as_os_str()]);
+    assert!(!result.success);
+    assert_eq!(
+        result.stderr,
+        format!("'{}' is not a directory\n", destination.display())
+    );
+}
+
+#[test]
+fn progress_interval() {
+    initialize();
+    let source = HYDRATED_DIR.join("progress_interval");
+    let destination = COPIES_DIR.join("progress_interval");
+    remove(&source);
+    remove(&destination);
+    fs::create(&source, FILE_MODE).unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--json-progress"),
+        OsStr::new("--progress-interval=1"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert_eq!(
+        result.stdout.lines().last(),
+        Some(r#"{"event":"done","files_done":1,"bytes_done":0,"success":true}"#)
+    );
+    let result = fcp_run(&[
+        OsStr::new("--progress-interval=0"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(!result.success);
+    assert_eq!(
+        result.stderr,
+        "Invalid value '0' for option '--progress-interval': expected a positive integer\n"
+    );
+}
+
+#[test]
+fn estimate() {
+    use fcp::options::{CopyEstimate, CopyOptions};
+
+    initialize();
+    let source = HYDRATED_DIR.join("estimate");
+    remove(&source);
+    fs::create_dir_all(source.join("directory")).unwrap();
+    for (name, size) in [("small", 10), ("directory/large", 1000)] {
+        write!(
+            fs::create(source.join(name), FILE_MODE).unwrap(),
+            "{}",
+            "x".repeat(size)
+        )
+        .unwrap();
+    }
+    fs::symlink("small", source.join("symlink")).unwrap();
+    let sources = [source];
+    let estimate = fcp::estimate(&sources, &CopyOptions::default());
+    assert_eq!(
+        estimate,
+        CopyEstimate {
+            files: 2,
+            bytes: 1010,
+            directories: 2,
+            symlinks: 1,
+            special: 0,
+        }
+    );
+    let options = CopyOptions {
+        max_size: Some(100),
+        ..Default::default()
+    };
+    let estimate = fcp::estimate(&sources, &options);
+    assert_eq!((estimate.files, estimate.bytes), (1, 10));
+}
+
+#[test]
+fn direct() {
+    initialize();
+    let source = HYDRATED_DIR.join("direct");
+    let destination = COPIES_DIR.join("direct");
+    remove(&source);
+    remove(&destination);
+    // Larger than the buffer, and not a multiple of any block size, so there's an unaligned tail.
+    let contents = (0..300_001).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    fs::create(&source, FILE_MODE)
+        .unwrap()
+        .write_all(&contents)
+        .unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--direct"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert_eq!(std::fs::read(&destination).unwrap(), contents);
+    // Large enough to be preallocated first, which mustn't leave the copy any longer.
+    let contents = (0..4 * 1024 * 1024 + 1)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    std::fs::write(&source, &contents).unwrap();
+    remove(&destination);
+    let result = fcp_run(&[
+        OsStr::new("--direct"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert_eq!(std::fs::read(&destination).unwrap(), contents);
+}
+
+#[test]
+fn reflink_or_fail() {
+    initialize();
+    let source = HYDRATED_DIR.join("reflink_or_fail");
+    let destination = COPIES_DIR.join("reflink_or_fail");
+    remove(&source);
+    remove(&destination);
+    fs::create(&source, FILE_MODE)
+        .unwrap()
+        .write_all(b"cloned")
+        .unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--reflink-or-fail"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    // Whether the file can be cloned depends on the filesystem the tests are run on, but it must
+    // either be cloned or not copied at all.
+    if result.success {
+        assert_eq!(result.stderr, "");
+        assert_eq!(std::fs::read(&destination).unwrap(), b"cloned");
+    } else {
+        assert!(result.stderr.contains("couldn't be cloned to"));
+        assert!(!destination.exists());
+    }
+}
+
+#[test]
+fn reflink_auto() {
+    initialize();
+    let source = HYDRATED_DIR.join("reflink_auto");
+    let destination = COPIES_DIR.join("reflink_auto");
+    remove(&source);
+    remove(&destination);
+    fs::create(&source, FILE_MODE)
+        .unwrap()
+        .write_all(b"cloned")
+        .unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--reflink=auto"),
+        OsStr::new("--explain"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    // Whether or not the filesystem supports cloning, the file is copied.
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    assert_eq!(std::fs::read(&destination).unwrap(), b"cloned");
+    assert!(result.stdout.starts_with("copied "));
+}
+
+#[test]
+fn record_only() {
+    initialize();
+    let source = HYDRATED_DIR.join("record_only");
+    let manifest = COPIES_DIR.join("record_only.json");
+    remove(&source);
+    fs::create_dir_all(source.join("dir")).unwrap();
+    fs::create(source.join("dir/file"), FILE_MODE)
+        .unwrap()
+        .write_all(b"abc")
+        .unwrap();
+    fs::symlink("dir/file", source.join("link")).unwrap();
+    let result = fcp_run(&[
+        OsStr::new("--record-only"),
+        manifest.as_os_str(),
+        source.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    let manifest = std::fs::read_to_string(&manifest).unwrap();
+    let lines = manifest.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].starts_with(r#"{"path":"record_only","type":"directory","#));
+    assert!(lines[1].starts_with(r#"{"path":"record_only/dir","type":"directory","#));
+    assert!(lines[2].starts_with(r#"{"path":"record_only/dir/file","type":"regular","#));
+    assert!(lines[2].contains(r#","size":3,"#));
+    assert!(lines[2].ends_with(r#","target":null,"rdev":null,"hash":"e71fa2190541574b"}"#));
+    assert!(lines[3].starts_with(r#"{"path":"record_only/link","type":"symlink","#));
+    assert!(lines[3].ends_with(r#","target":"dir/file","rdev":null,"hash":null}"#));
+}
+
+#[test]
+fn direct_between_block_sizes() {
+    initialize();
+    let is_root = Command::new("id").arg("-u").output().unwrap().stdout == b"0\n";
+    if !is_root || Command::new("losetup").arg("--version").output().is_err() {
+        return;
+    }
+    let root = COPIES_DIR.join("direct_between_block_sizes");
+    remove(&root);
+    fs::create_dir_all(&root).unwrap();
+    // A filesystem on a loop device with each logical block size, so that the source and
+    // destination require different alignments for `O_DIRECT`.
+    let mut mounts = Vec::new();
+    for sector_size in ["512", "4096"] {
+        let image = root.join(format!("{}.img", sector_size));
+        let mount = root.join(sector_size);
+        fs::create_dir_all(&mount).unwrap();
+        std::fs::File::create(&image)
+            .unwrap()
+            .set_len(16 << 20)
+            .unwrap();
+        let device = Command::new("losetup")
+            .args(["--find", "--show", "--sector-size", sector_size])
+            .arg(&image)
+            .output()
+            .unwrap();
+        if !device.status.success() {
+            break;
+        }
+        let device = String::from_utf8(device.stdout).unwrap().trim().to_string();
+        let mounted = Command::new("mkfs.ext4")
+            .args(["-q", "-F", &device])
+            .status()
+            .is_ok_and(|status| status.success())
+            && Command::new("mount")
+                .arg(&device)
+                .arg(&mount)
+                .status()
+                .unwrap()
+                .success();
+        Command::new("losetup")
+            .args(["-d", &device])
+            .status()
+            .unwrap();
+        if !mounted {
+            break;
+        }
+        mounts.push(mount);
+    }
+    if let [small, large] = mounts.as_slice() {
+        let contents = (0..300_001).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        for (from, to) in [(small, large), (large, small)] {
+            let source = from.join("source");
+            let destination = to.join("destination");
+            std::fs::write(&source, &contents).unwrap();
+            let result = fcp_run(&[
+                OsStr::new("--direct"),
+                source.as_os_str(),
+                destination.as_os_str(),
+            ]);
+            assert!(result.success);
+            assert_eq!(result.stderr, "");
+            assert_eq!(std::fs::read(&destination).unwrap(), contents);
+        }
+    }
+    for mount in &mounts {
+        Command::new("umount").arg(mount).status().unwrap();
+    }
+}
+
+#[test]
+fn cancel() {
+    use fcp::error::ErrorKind;
+    use fcp::options::CopyOptions;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    initialize();
+    let source = HYDRATED_DIR.join("cancel");
+    let destination = COPIES_DIR.join("cancel");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    write!(
+        fs::create(source.join("file"), FILE_MODE).unwrap(),
+        "contents"
+    )
+    .unwrap();
+
+    // A copy cancelled before it starts copies nothing.
+    let options = CopyOptions {
+        cancel: Some(Arc::new(AtomicBool::new(true))),
+        ..Default::default()
+    };
+    let err = fcp::fcp_with_options(
+        std::slice::from_ref(&source),
+        std::slice::from_ref(&destination),
+        &options,
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), Some(ErrorKind::Cancelled));
+    assert!(!destination.exists());
+
+    // Copying a file's contents stops at the next check.
+    let cancelled = AtomicBool::new(true);
+    let content = fs::ContentOptions {
+        cancel: Some(&cancelled),
+        ..Default::default()
+    };
+    let source_file = fs::open(source.join("file")).unwrap();
+    let dest_file = fs::create(&destination, FILE_MODE).unwrap();
+    let err = fs::copy_fds(&source_file, &dest_file, None, content).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(125));
+    assert_eq!(std::fs::read(&destination).unwrap(), b"");
+}
+
+#[test]
+fn preserve() {
+    initialize();
+    let source = HYDRATED_DIR.join("preserve");
+    let destination = COPIES_DIR.join("preserve");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    fs::create(source.join("file"), FILE_MODE).unwrap();
+    // Unlike a regular file's, a directory's mode is otherwise subject to the umask.
+    std::fs::set_permissions(&source, std::fs::Permissions::from_mode(0o777)).unwrap();
+    for path in [source.join("file"), source.clone()] {
+        let status = Command::new("touch")
+            .args(&[
+                OsStr::new("-d"),
+                OsStr::new("2001-02-03 04:05:06.123456789"),
+                path.as_os_str(),
+            ])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+    let result = fcp_run(&[
+        OsStr::new("-p"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    for (source, destination) in [
+        (source.join("file"), destination.join("file")),
+        (source, destination),
+    ] {
+        let (expected, actual) = (
+            std::fs::metadata(&source).unwrap(),
+            std::fs::metadata(&destination).unwrap(),
+        );
+        assert_eq!(actual.mode(), expected.mode());
+        assert_eq!(
+            (actual.mtime(), actual.mtime_nsec()),
+            (expected.mtime(), expected.mtime_nsec())
+        );
+        assert_eq!(
+            (actual.uid(), actual.gid()),
+            (expected.uid(), expected.gid())
+        );
+    }
+}
+
+#[test]
+#[cfg_attr(not(target_os = "linux"), ignore)]
+fn preserve_xattr() {
+    initialize();
+    let source = HYDRATED_DIR.join("preserve_xattr");
+    let destination = COPIES_DIR.join("preserve_xattr");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    fs::create(source.join("file"), FILE_MODE).unwrap();
+    for (name, path) in [
+        ("user.dir", source.clone()),
+        ("user.file", source.join("file")),
+    ] {
+        let status = Command::new("setfattr")
+            .args(&[
+                OsStr::new("-n"),
+                OsStr::new(name),
+                OsStr::new("-v"),
+                OsStr::new("tag"),
+            ])
+            .arg(&path)
+            .status();
+        if !status.is_ok_and(|status| status.success()) {
+            // Either `setfattr` is missing, or the filesystem doesn't support user attributes.
+            return;
+        }
+    }
+    let result = fcp_run(&[
+        OsStr::new("--preserve=xattr"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    let tag = |name: &str| vec![(name.as_bytes().to_vec(), b"tag".to_vec())];
+    assert_eq!(fs::xattrs(&destination).unwrap(), tag("user.dir"));
+    assert_eq!(
+        fs::xattrs(destination.join("file")).unwrap(),
+        tag("user.file")
+    );
+}
+
+#[test]
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+fn preserve_flags() {
+    initialize();
+    let source = HYDRATED_DIR.join("preserve_flags");
+    let destination = COPIES_DIR.join("preserve_flags");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    fs::create(source.join("file"), FILE_MODE).unwrap();
+    for path in [&source, &source.join("file")] {
+        let status = Command::new("chflags").arg("nodump").arg(path).status();
+        assert!(status.unwrap().success());
+    }
+    let result = fcp_run(&[
+        OsStr::new("--preserve=flags"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    for path in [&destination, &destination.join("file")] {
+        let metadata = std::fs::symlink_metadata(path).unwrap();
+        assert_eq!(
+            fs::flags(&metadata),
+            fs::flags(&std::fs::metadata(&source).unwrap())
+        );
+        assert_ne!(fs::flags(&metadata), 0);
+    }
+}
+
+#[test]
+#[cfg(target_os = "macos")]
+fn finder_metadata() {
+    initialize();
+    let source = HYDRATED_DIR.join("finder_metadata");
+    let destination = COPIES_DIR.join("finder_metadata");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    fs::create(source.join("file"), FILE_MODE).unwrap();
+    fs::symlink("file", source.join("symlink")).unwrap();
+    let xattr = |args: &[&OsStr]| Command::new("xattr").args(args).output().unwrap();
+    for path in [&source, &source.join("file")] {
+        let output = xattr(&[
+            OsStr::new("-w"),
+            OsStr::new("com.apple.quarantine"),
+            OsStr::new("0081;00000000;fcp;"),
+            path.as_os_str(),
+        ]);
+        assert!(output.status.success());
+    }
+    let output = xattr(&[
+        OsStr::new("-s"),
+        OsStr::new("-w"),
+        OsStr::new("com.apple.FinderInfo"),
+        OsStr::new("0000000000000000400000000000000000000000000000000000000000000000"),
+        source.join("symlink").as_os_str(),
+    ]);
+    let symlink_tagged = output.status.success();
+    // Sparse copies don't go through `copyfile`, so its attributes have to be copied separately.
+    let result = fcp_run(&[
+        OsStr::new("--sparse=always"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    for (name, path, symlink) in [
+        ("com.apple.quarantine", destination.clone(), false),
+        ("com.apple.quarantine", destination.join("file"), false),
+        ("com.apple.FinderInfo", destination.join("symlink"), true),
+    ] {
+        if symlink && !symlink_tagged {
+            continue;
+        }
+        let mut args = vec![OsStr::new("-p")];
+        if symlink {
+            args.push(OsStr::new("-s"));
+        }
+        args.extend(&[OsStr::new(name), path.as_os_str()]);
+        assert!(xattr(&args).status.success(), "{} of {:?}", name, path);
+    }
+}
+
+#[test]
+#[cfg_attr(not(target_os = "linux"), ignore)]
+fn acls() {
+    initialize();
+    let source = HYDRATED_DIR.join("acls");
+    remove(&source);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    fs::create(source.join("file"), FILE_MODE).unwrap();
+    for (option, path) in [("-m", source.join("file")), ("-dm", source.clone())] {
+        let status = Command::new("setfacl")
+            .args(&[option, "u:0:r"])
+            .arg(&path)
+            .status();
+        if !status.is_ok_and(|status| status.success()) {
+            // Either `setfacl` is missing, or the filesystem doesn't support ACLs.
+            return;
+        }
+    }
+
+    let destination = COPIES_DIR.join("acls");
+    remove(&destination);
+    let result = fcp_run(&[source.as_os_str(), destination.as_os_str()]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    for name in ["", "file"] {
+        let acls = fs::acls(destination.join(name)).unwrap();
+        assert!(!acls.is_empty());
+        assert_eq!(acls, fs::acls(source.join(name)).unwrap());
+    }
+
+    let destination = COPIES_DIR.join("acls_omitted");
+    remove(&destination);
+    let result = fcp_run(&[
+        OsStr::new("--no-acls"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert!(fs::acls(destination.join("file")).unwrap().is_empty());
+}
+
+#[test]
+fn owner() {
+    initialize();
+    let is_root = Command::new("id").arg("-u").output().unwrap().stdout == b"0\n";
+    if !is_root {
+        // Only root can give files to other users.
+        return;
+    }
+    let source = HYDRATED_DIR.join("owner");
+    let destination = COPIES_DIR.join("owner");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    fs::create(source.join("file"), FILE_MODE).unwrap();
+    fs::symlink("file", source.join("link")).unwrap();
+    // The symlink is given a different owner than the file it points to, which must not change.
+    for (owner, path) in [("1:2", "file"), ("3:4", "link")] {
+        let status = Command::new("chown")
+            .args(&["-h", owner])
+            .arg(source.join(path))
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+    let result = fcp_run(&[
+        OsStr::new("--owner"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    for (path, owner) in [("file", (1, 2)), ("link", (3, 4))] {
+        let metadata = std::fs::symlink_metadata(destination.join(path)).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), owner);
+    }
+}
+
+#[test]
+fn preserve_timestamps_of_special_files() {
+    initialize();
+    let source = HYDRATED_DIR.join("preserve_timestamps_of_special_files");
+    let destination = COPIES_DIR.join("preserve_timestamps_of_special_files");
+    remove(&source);
+    remove(&destination);
+    fs::create_dir(&source, DIR_MODE).unwrap();
+    fs::mkfifo(
+        source.join("fifo"),
+        std::fs::Permissions::from_mode(FILE_MODE),
+    )
+    .unwrap();
+    fs::symlink("fifo", source.join("link")).unwrap();
+    for (path, time) in [
+        ("fifo", "2001-02-03 04:05:06.123456789"),
+        ("link", "2002-03-04 05:06:07.987654321"),
+    ] {
+        // `-h` sets the times of a symlink itself, and those of a FIFO without opening it.
+        let status = Command::new("touch")
+            .args(&["-h", "-d", time])
+            .arg(source.join(path))
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+    let result = fcp_run(&[
+        OsStr::new("--preserve=timestamps"),
+        source.as_os_str(),
+        destination.as_os_str(),
+    ]);
+    assert!(result.success);
+    assert_eq!(result.stderr, "");
+    for path in ["fifo", "link"] {
+        let (expected, actual) = (
+            std::fs::symlink_metadata(source.join(path)).unwrap(),
+            std::fs::symlink_metadata(destination.join(path)).unwrap(),
+        );
+        assert_eq!(
+            (actual.mtime(), actual.mtime_nsec()),
+            (expected.mtime(), expected.mtime_nsec())
+        );
+        assert_eq!(
+            (actual.atime(), actual.atime_nsec()),
+            (expected.atime(), expected.atime_nsec())
+        );
+    }
+}